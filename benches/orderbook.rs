@@ -0,0 +1,181 @@
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use loshan_keyrock::exchanges::{ExchangeId, ParsedLevel, ParsedUpdate, UpdateKind};
+use loshan_keyrock::orderbook::OrderBook;
+
+// books similar to what the 1000 levels Binance snapshot and the Bitstamp one return
+const SNAPSHOT_LEVELS: usize = 1000;
+const MID: f64 = 30_000.0;
+const TICK: f64 = 0.01;
+
+fn level(price: f64, amount: f64) -> ParsedLevel {
+    ParsedLevel { price, amount }
+}
+
+fn snapshot(exchange: ExchangeId, last_update_id: u64) -> ParsedUpdate {
+    ParsedUpdate {
+        exchange,
+        kind: UpdateKind::Snapshot,
+        bids: (1..=SNAPSHOT_LEVELS)
+            .map(|i| level(MID - i as f64 * TICK, 1.0 + i as f64))
+            .collect(),
+        asks: (1..=SNAPSHOT_LEVELS)
+            .map(|i| level(MID + i as f64 * TICK, 1.0 + i as f64))
+            .collect(),
+        last_update_id,
+        first_update_id: None,
+    }
+}
+
+fn full_book(reporting_levels: u32) -> OrderBook {
+    let mut order_book =
+        OrderBook::new(reporting_levels, snapshot(ExchangeId::Binance, 10)).unwrap();
+    order_book
+        .merge_parse_update(snapshot(ExchangeId::Bitstamp, 10))
+        .unwrap();
+    order_book
+}
+
+// a diff touching a few levels close to the top of the book, like most real diffs
+fn diff(exchange: ExchangeId, last_update_id: u64, step: usize) -> ParsedUpdate {
+    let offset = (step % 20) as f64 * TICK;
+    ParsedUpdate {
+        exchange,
+        kind: UpdateKind::Diff,
+        bids: vec![
+            level(MID - TICK - offset, 2.5),
+            level(MID - 5.0 * TICK - offset, 0.0),
+        ],
+        asks: vec![
+            level(MID + TICK + offset, 2.5),
+            level(MID + 5.0 * TICK + offset, 0.0),
+        ],
+        last_update_id,
+        first_update_id: None,
+    }
+}
+
+// removes the best bid (and the first ask) of both venues and puts them back,
+// the worst case for best price maintenance
+fn best_levels_churn(best_bid: f64, last_update_id: u64, amount: f64) -> [ParsedUpdate; 2] {
+    ExchangeId::ALL.map(|exchange| ParsedUpdate {
+        exchange,
+        kind: UpdateKind::Diff,
+        bids: vec![level(best_bid, amount)],
+        asks: vec![level(MID + TICK, amount)],
+        last_update_id,
+        first_update_id: None,
+    })
+}
+
+fn merge_benchmarks(c: &mut Criterion) {
+    let mut order_book = full_book(10);
+    let mut last_update_id = 10;
+    c.bench_function("merge_parse_update small diff", |b| {
+        b.iter(|| {
+            last_update_id += 1;
+            let exchange = if last_update_id % 2 == 0 {
+                ExchangeId::Binance
+            } else {
+                ExchangeId::Bitstamp
+            };
+            order_book
+                .merge_parse_update(diff(exchange, last_update_id, last_update_id as usize))
+                .unwrap();
+        })
+    });
+
+    for drift in [0, 500] {
+        let mut order_book = full_book(10);
+        let mut last_update_id = 10;
+        // the market moving down by `drift` ticks, deleting all those bids
+        for i in 1..=drift {
+            for exchange in ExchangeId::ALL {
+                last_update_id += 1;
+                let update = ParsedUpdate {
+                    exchange,
+                    kind: UpdateKind::Diff,
+                    bids: vec![level(MID - i as f64 * TICK, 0.0)],
+                    asks: vec![],
+                    last_update_id,
+                    first_update_id: None,
+                };
+                order_book.merge_parse_update(update).unwrap();
+            }
+        }
+        let best_bid = MID - (drift + 1) as f64 * TICK;
+        c.bench_function(
+            &format!(
+                "merge_parse_update best levels churn after {} ticks drift",
+                drift
+            ),
+            |b| {
+                b.iter(|| {
+                    last_update_id += 1;
+                    for update in best_levels_churn(best_bid, last_update_id, 0.0) {
+                        order_book.merge_parse_update(update).unwrap();
+                    }
+                    last_update_id += 1;
+                    for update in best_levels_churn(best_bid, last_update_id, 1.0) {
+                        order_book.merge_parse_update(update).unwrap();
+                    }
+                })
+            },
+        );
+    }
+}
+
+// takes out the top 100 bid prices one update at a time and puts them back,
+// every delete hits the current best bid as when the market moves down
+fn walk_book_benchmark(c: &mut Criterion) {
+    let mut order_book = full_book(10);
+    let mut last_update_id = 10;
+    c.bench_function("merge_parse_update walk down 100 levels", |b| {
+        b.iter(|| {
+            for amount in [0.0, 1.0] {
+                for i in 1..=100 {
+                    for exchange in ExchangeId::ALL {
+                        last_update_id += 1;
+                        let update = ParsedUpdate {
+                            exchange,
+                            kind: UpdateKind::Diff,
+                            bids: vec![level(MID - i as f64 * TICK, amount)],
+                            asks: vec![],
+                            last_update_id,
+                            first_update_id: None,
+                        };
+                        order_book.merge_parse_update(update).unwrap();
+                    }
+                }
+            }
+        })
+    });
+}
+
+fn summary_benchmarks(c: &mut Criterion) {
+    for reporting_levels in [10, 100] {
+        let order_book = full_book(reporting_levels);
+        c.bench_function(&format!("get_summary {} levels", reporting_levels), |b| {
+            b.iter(|| black_box(order_book.get_summary().unwrap()))
+        });
+        // top levels straight from the book, without building the proto Levels
+        c.bench_function(&format!("bids_iter top {} levels", reporting_levels), |b| {
+            b.iter(|| {
+                black_box(
+                    order_book
+                        .bids_iter()
+                        .take(reporting_levels as usize)
+                        .map(|level| level.amount)
+                        .sum::<f64>(),
+                )
+            })
+        });
+    }
+}
+
+criterion_group!(
+    benches,
+    merge_benchmarks,
+    walk_book_benchmark,
+    summary_benchmarks
+);
+criterion_main!(benches);
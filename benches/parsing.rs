@@ -0,0 +1,68 @@
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use loshan_keyrock::exchanges::{depth_message_to_update, ExchangeId};
+use loshan_keyrock::levels::{parse_levels, BINANCE_SNAPSHOT};
+use tokio_tungstenite::tungstenite::Message;
+
+// btcusdt / btcusd messages one per line, websocket diffs (and the bitstamp subscription
+// event) and a 1000 levels snapshot. Written by `cargo run --bin orderbook-capture`, which
+// dumps the frames and the snapshot body as received; the files checked in follow the
+// exchange formats but were generated, rerun the capture for real market data
+const BINANCE_DEPTH: &str = include_str!("messages/binance_depth.jsonl");
+const BITSTAMP_DIFF: &str = include_str!("messages/bitstamp_diff.jsonl");
+const BINANCE_SNAPSHOT_JSON: &str = include_str!("messages/binance_snapshot.json");
+
+fn messages(lines: &str) -> Vec<Message> {
+    lines
+        .lines()
+        .map(|line| Message::Text(line.to_string()))
+        .collect()
+}
+
+fn bench_depth_messages(c: &mut Criterion) {
+    for (exchange, lines) in [
+        (ExchangeId::Binance, BINANCE_DEPTH),
+        (ExchangeId::Bitstamp, BITSTAMP_DIFF),
+    ] {
+        let messages = messages(lines);
+        c.bench_function(
+            &format!("depth_message_to_update {} x{}", exchange, messages.len()),
+            |b| {
+                b.iter(|| {
+                    for message in &messages {
+                        black_box(depth_message_to_update(exchange, black_box(message)).unwrap());
+                    }
+                })
+            },
+        );
+    }
+
+    // what every frame cost before the level parser, a Value without reading the levels
+    let messages = messages(BINANCE_DEPTH);
+    c.bench_function(
+        &format!("serde_json Value BINANCE x{}", messages.len()),
+        |b| {
+            b.iter(|| {
+                for message in &messages {
+                    let Message::Text(text) = black_box(message) else {
+                        continue;
+                    };
+                    black_box(serde_json::from_str::<serde_json::Value>(text).unwrap());
+                }
+            })
+        },
+    );
+}
+
+fn bench_snapshot(c: &mut Criterion) {
+    c.bench_function("parse_levels BINANCE snapshot 1000 levels", |b| {
+        b.iter(|| {
+            black_box(parse_levels(
+                black_box(BINANCE_SNAPSHOT_JSON),
+                &BINANCE_SNAPSHOT,
+            ))
+        })
+    });
+}
+
+criterion_group!(benches, bench_depth_messages, bench_snapshot);
+criterion_main!(benches);
@@ -1,4 +1,16 @@
+use std::path::PathBuf;
+
 fn main() -> Result<(), Box<dyn std::error::Error>> {
-    tonic_build::compile_protos("protos/orderbookaggregator.proto")?;
+    // the descriptor set is served by the gRPC reflection service
+    let out_dir = PathBuf::from(std::env::var("OUT_DIR")?);
+    tonic_build::configure()
+        // written by hand in src/summary.rs, they borrow the exchange names
+        .extern_path(".orderbookaggregator.Level", "crate::summary::Level")
+        .extern_path(
+            ".orderbookaggregator.VenueAmount",
+            "crate::summary::VenueAmount",
+        )
+        .file_descriptor_set_path(out_dir.join("orderbookaggregator_descriptor.bin"))
+        .compile(&["protos/orderbookaggregator.proto"], &["protos"])?;
     Ok(())
 }
@@ -0,0 +1,283 @@
+use anyhow::{bail, Context, Result};
+use serde::Deserialize;
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use tonic::{Request, Status};
+
+// one client of the server as listed in the keys file, limits left out are not enforced
+#[derive(Debug, Deserialize)]
+pub struct ApiKey {
+    pub name: String,
+    pub token: String,
+    // open streams at the same time, all rpcs together
+    pub max_streams: Option<usize>,
+    // distinct symbols streamed at the same time
+    pub max_symbols: Option<usize>,
+    // largest `levels` of a SummaryRequest
+    pub max_levels: Option<u32>,
+}
+
+#[derive(Debug, Default)]
+struct Usage {
+    streams: usize,
+    // open streams per symbol
+    symbols: HashMap<String, usize>,
+}
+
+// the keys accepted by the server and what each of them is using right now
+#[derive(Debug, Clone)]
+pub struct ApiKeys {
+    keys: Arc<HashMap<String, Arc<ApiKey>>>,
+    usage: Arc<Mutex<HashMap<String, Usage>>>,
+}
+
+impl ApiKeys {
+    // usage is counted per name and keys are found by token, so both have to be unique
+    pub fn new(keys: Vec<ApiKey>) -> Result<Self> {
+        let mut names = HashSet::new();
+        let mut tokens = HashSet::new();
+        for key in &keys {
+            if !names.insert(key.name.as_str()) {
+                bail!("api key name {} is used more than once", key.name);
+            }
+            if !tokens.insert(key.token.as_str()) {
+                bail!("api key {} has the token of another key", key.name);
+            }
+        }
+        Ok(ApiKeys {
+            keys: Arc::new(
+                keys.into_iter()
+                    .map(|key| (key.token.clone(), Arc::new(key)))
+                    .collect(),
+            ),
+            usage: Arc::new(Mutex::new(HashMap::new())),
+        })
+    }
+
+    // a json array of keys: [{"name": "desk", "token": "...", "max_streams": 4, ...}]
+    pub fn from_file(path: &Path) -> Result<Self> {
+        let keys = std::fs::read(path)
+            .with_context(|| format!("failed to read keys file {}", path.display()))?;
+        let keys: Vec<ApiKey> = serde_json::from_slice(&keys)
+            .with_context(|| format!("invalid keys file {}", path.display()))?;
+        ApiKeys::new(keys).with_context(|| format!("invalid keys file {}", path.display()))
+    }
+
+    // the key of an "authorization: Bearer <token>" or "x-api-key: <token>" header
+    pub fn authenticate<T>(&self, request: &Request<T>) -> Result<Arc<ApiKey>, Status> {
+        let metadata = request.metadata();
+        let token = match metadata.get("authorization") {
+            Some(authorization) => authorization
+                .to_str()
+                .ok()
+                .and_then(|authorization| authorization.strip_prefix("Bearer ")),
+            None => metadata
+                .get("x-api-key")
+                .and_then(|api_key| api_key.to_str().ok()),
+        }
+        .ok_or_else(|| Status::unauthenticated("missing api key"))?;
+        self.keys
+            .get(token.trim())
+            .cloned()
+            .ok_or_else(|| Status::unauthenticated("invalid api key"))
+    }
+
+    // tonic interceptor rejecting requests without a valid key, the key is passed
+    // to the service in the request extensions
+    pub fn intercept(&self, mut request: Request<()>) -> Result<Request<()>, Status> {
+        let api_key = self.authenticate(&request)?;
+        request.extensions_mut().insert(api_key);
+        Ok(request)
+    }
+
+    // the key the interceptor put in the request extensions
+    pub fn acquire_request<T>(
+        &self,
+        request: &Request<T>,
+        symbol: &str,
+        levels: Option<u32>,
+    ) -> Result<QuotaGuard, Status> {
+        let api_key = request
+            .extensions()
+            .get::<Arc<ApiKey>>()
+            .ok_or_else(|| Status::unauthenticated("missing api key"))?;
+        self.acquire(api_key, symbol, levels)
+    }
+
+    // reserves one stream of `symbol` for the key, released when the guard is dropped.
+    // `levels` is only checked for book summaries
+    pub fn acquire(
+        &self,
+        api_key: &Arc<ApiKey>,
+        symbol: &str,
+        levels: Option<u32>,
+    ) -> Result<QuotaGuard, Status> {
+        if let (Some(levels), Some(max_levels)) = (levels, api_key.max_levels) {
+            if levels > max_levels {
+                return Err(Status::permission_denied(format!(
+                    "{} levels requested, the limit of {} is {}",
+                    levels, api_key.name, max_levels
+                )));
+            }
+        }
+
+        let mut usage = self.usage.lock().expect("api keys lock poisoned");
+        let usage = usage.entry(api_key.name.clone()).or_default();
+        if let Some(max_streams) = api_key.max_streams {
+            if usage.streams >= max_streams {
+                return Err(Status::resource_exhausted(format!(
+                    "{} already has {} streams open",
+                    api_key.name, usage.streams
+                )));
+            }
+        }
+        if let Some(max_symbols) = api_key.max_symbols {
+            if !usage.symbols.contains_key(symbol) && usage.symbols.len() >= max_symbols {
+                return Err(Status::resource_exhausted(format!(
+                    "{} already streams {} symbols",
+                    api_key.name,
+                    usage.symbols.len()
+                )));
+            }
+        }
+        usage.streams += 1;
+        *usage.symbols.entry(symbol.to_string()).or_insert(0) += 1;
+
+        Ok(QuotaGuard {
+            api_keys: self.clone(),
+            name: api_key.name.clone(),
+            symbol: symbol.to_string(),
+        })
+    }
+
+    fn release(&self, name: &str, symbol: &str) {
+        let mut usage = self.usage.lock().expect("api keys lock poisoned");
+        let Some(usage) = usage.get_mut(name) else {
+            return;
+        };
+        usage.streams = usage.streams.saturating_sub(1);
+        if let Some(streams) = usage.symbols.get_mut(symbol) {
+            *streams -= 1;
+            if *streams == 0 {
+                usage.symbols.remove(symbol);
+            }
+        }
+    }
+}
+
+// interceptor of the OrderbookAggregator service, letting everything through
+// when the server runs without keys
+pub fn interceptor(
+    api_keys: Option<ApiKeys>,
+) -> impl FnMut(Request<()>) -> Result<Request<()>, Status> + Clone {
+    move |request| match &api_keys {
+        Some(api_keys) => api_keys.intercept(request),
+        None => Ok(request),
+    }
+}
+
+// one open stream counted against a key
+pub struct QuotaGuard {
+    api_keys: ApiKeys,
+    name: String,
+    symbol: String,
+}
+
+impl Drop for QuotaGuard {
+    fn drop(&mut self) {
+        self.api_keys.release(&self.name, &self.symbol);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn api_keys() -> ApiKeys {
+        let keys: Vec<ApiKey> = serde_json::from_str(
+            r#"[
+                {"name": "desk", "token": "desk-token", "max_streams": 2, "max_symbols": 1, "max_levels": 20},
+                {"name": "research", "token": "research-token"}
+            ]"#,
+        )
+        .unwrap();
+        ApiKeys::new(keys).unwrap()
+    }
+
+    fn request_with(header: &'static str, value: &'static str) -> Request<()> {
+        let mut request = Request::new(());
+        request
+            .metadata_mut()
+            .insert(header, value.parse().unwrap());
+        request
+    }
+
+    #[test]
+    fn authenticates_bearer_and_api_key_headers() {
+        let api_keys = api_keys();
+        let request = request_with("authorization", "Bearer desk-token");
+        assert_eq!(api_keys.authenticate(&request).unwrap().name, "desk");
+        let request = request_with("x-api-key", "research-token");
+        assert_eq!(api_keys.authenticate(&request).unwrap().name, "research");
+
+        for request in [
+            Request::new(()),
+            request_with("authorization", "Bearer nope"),
+            request_with("authorization", "desk-token"),
+        ] {
+            let status = api_keys.authenticate(&request).unwrap_err();
+            assert_eq!(status.code(), tonic::Code::Unauthenticated);
+        }
+
+        let request = api_keys
+            .intercept(request_with("authorization", "Bearer desk-token"))
+            .unwrap();
+        assert!(request.extensions().get::<Arc<ApiKey>>().is_some());
+    }
+
+    #[test]
+    fn enforces_and_releases_quotas() {
+        let api_keys = api_keys();
+        let desk = api_keys
+            .authenticate(&request_with("x-api-key", "desk-token"))
+            .unwrap();
+
+        let status = api_keys.acquire(&desk, "btcusdt", Some(21)).err().unwrap();
+        assert_eq!(status.code(), tonic::Code::PermissionDenied);
+
+        let first = api_keys.acquire(&desk, "btcusdt", Some(20)).unwrap();
+        let status = api_keys.acquire(&desk, "ethbtc", None).err().unwrap();
+        assert_eq!(status.code(), tonic::Code::ResourceExhausted);
+        let second = api_keys.acquire(&desk, "btcusdt", None).unwrap();
+        let status = api_keys.acquire(&desk, "btcusdt", None).err().unwrap();
+        assert_eq!(status.code(), tonic::Code::ResourceExhausted);
+
+        drop(first);
+        drop(second);
+        let _other_symbol = api_keys.acquire(&desk, "ethbtc", Some(5)).unwrap();
+
+        // no limits for research
+        let research = api_keys
+            .authenticate(&request_with("x-api-key", "research-token"))
+            .unwrap();
+        let _guards: Vec<QuotaGuard> = (0..10)
+            .map(|i| {
+                api_keys
+                    .acquire(&research, &format!("symbol{}", i), Some(u32::MAX))
+                    .unwrap()
+            })
+            .collect();
+    }
+
+    #[test]
+    fn rejects_duplicate_names_and_tokens() {
+        for keys in [
+            r#"[{"name": "desk", "token": "a"}, {"name": "desk", "token": "b"}]"#,
+            r#"[{"name": "desk", "token": "a"}, {"name": "research", "token": "a"}]"#,
+        ] {
+            let keys: Vec<ApiKey> = serde_json::from_str(keys).unwrap();
+            assert!(ApiKeys::new(keys).is_err());
+        }
+    }
+}
@@ -0,0 +1,263 @@
+use crate::exchanges::ParsedTrade;
+use crate::orderbookaggregator::{Candle, CandleSource};
+use std::collections::VecDeque;
+
+// intervals built when the server is not told otherwise: 1s, 1m, 5m
+pub const DEFAULT_INTERVALS: [u32; 3] = [1, 60, 300];
+
+// rolling candles of one source and interval. The candle in progress is only
+// returned once closed, the closed ones are kept in a ring buffer of `capacity`
+// candles used to backfill new subscribers
+#[derive(Debug)]
+pub struct CandleSeries {
+    source: CandleSource,
+    interval_secs: u32,
+    capacity: usize,
+    closed: VecDeque<Candle>,
+    current: Option<Candle>,
+}
+
+impl CandleSeries {
+    pub fn new(source: CandleSource, interval_secs: u32, capacity: usize) -> Self {
+        assert!(interval_secs > 0, "candle interval of 0s");
+        CandleSeries {
+            source,
+            interval_secs,
+            capacity,
+            closed: VecDeque::with_capacity(capacity),
+            current: None,
+        }
+    }
+
+    pub fn source(&self) -> CandleSource {
+        self.source
+    }
+
+    pub fn interval_secs(&self) -> u32 {
+        self.interval_secs
+    }
+
+    fn interval_micros(&self) -> u64 {
+        self.interval_secs as u64 * 1_000_000
+    }
+
+    fn new_candle(&self, open_time: u64, price: f64) -> Candle {
+        Candle {
+            source: self.source as i32,
+            interval_secs: self.interval_secs,
+            open_time,
+            open: price,
+            high: price,
+            low: price,
+            close: price,
+            volume: 0.0,
+            trades: 0,
+        }
+    }
+
+    fn push_closed(&mut self, candle: Candle) {
+        if self.closed.len() == self.capacity {
+            self.closed.pop_front();
+        }
+        if self.capacity > 0 {
+            self.closed.push_back(candle);
+        }
+    }
+
+    // adds one price at `timestamp` (microseconds), with the traded amount for trades.
+    // Returns the candles closed by it, oldest first: the one in progress and a flat
+    // candle (close carried over, no volume) for every empty interval in between.
+    // A timestamp older than the candle in progress (clock going backwards) is
+    // folded into it rather than reopening a closed candle
+    pub fn update(&mut self, timestamp: u64, price: f64, amount: Option<f64>) -> Vec<Candle> {
+        let interval_micros = self.interval_micros();
+        let open_time = timestamp - timestamp % interval_micros;
+        let mut closed = Vec::new();
+
+        match self.current.take() {
+            Some(current) if open_time > current.open_time => {
+                let missing = (open_time - current.open_time) / interval_micros - 1;
+                // no point filling more than the ring buffer can hold
+                let missing = missing.min(self.capacity as u64);
+                let first_missing = open_time - missing * interval_micros;
+                let last_close = current.close;
+                closed.push(current);
+                for i in 0..missing {
+                    closed.push(self.new_candle(first_missing + i * interval_micros, last_close));
+                }
+                self.current = Some(self.new_candle(open_time, price));
+            }
+            Some(mut current) => {
+                current.high = current.high.max(price);
+                current.low = current.low.min(price);
+                current.close = price;
+                self.current = Some(current);
+            }
+            None => self.current = Some(self.new_candle(open_time, price)),
+        }
+
+        if let (Some(amount), Some(current)) = (amount, self.current.as_mut()) {
+            current.volume += amount;
+            current.trades += 1;
+        }
+
+        for candle in closed.iter() {
+            self.push_closed(candle.clone());
+        }
+        closed
+    }
+
+    pub fn current(&self) -> Option<&Candle> {
+        self.current.as_ref()
+    }
+
+    // the last `count` closed candles, oldest first
+    pub fn history(&self, count: usize) -> Vec<Candle> {
+        let skip = self.closed.len().saturating_sub(count);
+        self.closed.iter().skip(skip).cloned().collect()
+    }
+}
+
+// all the candle series of one symbol, a mid and a trade series per interval
+#[derive(Debug)]
+pub struct CandleBuilder {
+    series: Vec<CandleSeries>,
+}
+
+impl CandleBuilder {
+    pub fn new(intervals: &[u32], capacity: usize) -> Self {
+        let series = [CandleSource::Mid, CandleSource::Trades]
+            .iter()
+            .flat_map(|source| {
+                intervals
+                    .iter()
+                    .map(move |interval_secs| CandleSeries::new(*source, *interval_secs, capacity))
+            })
+            .collect();
+        CandleBuilder { series }
+    }
+
+    pub fn series(&self, source: CandleSource, interval_secs: u32) -> Option<&CandleSeries> {
+        self.series
+            .iter()
+            .find(|series| series.source == source && series.interval_secs == interval_secs)
+    }
+
+    fn update(
+        &mut self,
+        source: CandleSource,
+        timestamp: u64,
+        price: f64,
+        amount: Option<f64>,
+    ) -> Vec<Candle> {
+        self.series
+            .iter_mut()
+            .filter(|series| series.source == source)
+            .flat_map(|series| series.update(timestamp, price, amount))
+            .collect()
+    }
+
+    // mid of the merged book, usually after every applied diff
+    pub fn on_mid(&mut self, timestamp: u64, mid_price: f64) -> Vec<Candle> {
+        self.update(CandleSource::Mid, timestamp, mid_price, None)
+    }
+
+    pub fn on_trade(&mut self, timestamp: u64, trade: &ParsedTrade) -> Vec<Candle> {
+        self.update(
+            CandleSource::Trades,
+            timestamp,
+            trade.price,
+            Some(trade.amount),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::exchanges::{ExchangeId, TradeSide};
+
+    const SECOND: u64 = 1_000_000;
+
+    #[test]
+    fn builds_ohlc_and_closes_on_next_interval() {
+        let mut series = CandleSeries::new(CandleSource::Mid, 1, 10);
+        assert!(series.update(10 * SECOND, 100.0, None).is_empty());
+        assert!(series.update(10 * SECOND + 200_000, 103.0, None).is_empty());
+        assert!(series.update(10 * SECOND + 400_000, 98.0, None).is_empty());
+        assert!(series.update(10 * SECOND + 900_000, 101.0, None).is_empty());
+
+        let closed = series.update(11 * SECOND, 102.0, None);
+        assert_eq!(closed.len(), 1);
+        assert_eq!(closed[0].open_time, 10 * SECOND);
+        assert_eq!(
+            (
+                closed[0].open,
+                closed[0].high,
+                closed[0].low,
+                closed[0].close
+            ),
+            (100.0, 103.0, 98.0, 101.0)
+        );
+        assert_eq!(series.current().unwrap().open, 102.0);
+        assert_eq!(series.history(5), closed);
+    }
+
+    #[test]
+    fn fills_empty_intervals_with_flat_candles() {
+        let mut series = CandleSeries::new(CandleSource::Trades, 60, 10);
+        series.update(60 * SECOND, 100.0, Some(1.0));
+        series.update(61 * SECOND, 101.0, Some(2.0));
+
+        let closed = series.update(4 * 60 * SECOND + 5, 99.0, Some(0.5));
+        let open_times: Vec<u64> = closed.iter().map(|candle| candle.open_time).collect();
+        assert_eq!(open_times, vec![60 * SECOND, 120 * SECOND, 180 * SECOND]);
+        assert_eq!((closed[0].volume, closed[0].trades), (3.0, 2));
+        for flat in &closed[1..] {
+            assert_eq!(
+                (flat.open, flat.close, flat.volume, flat.trades),
+                (101.0, 101.0, 0.0, 0)
+            );
+        }
+    }
+
+    #[test]
+    fn history_is_a_ring_buffer() {
+        let mut series = CandleSeries::new(CandleSource::Mid, 1, 3);
+        for second in 0..10 {
+            series.update(second * SECOND, second as f64, None);
+        }
+        let history: Vec<f64> = series
+            .history(100)
+            .iter()
+            .map(|candle| candle.open)
+            .collect();
+        assert_eq!(history, vec![6.0, 7.0, 8.0]);
+        let history: Vec<f64> = series.history(2).iter().map(|candle| candle.open).collect();
+        assert_eq!(history, vec![7.0, 8.0]);
+    }
+
+    #[test]
+    fn builder_keeps_mid_and_trades_apart() {
+        let mut builder = CandleBuilder::new(&[1, 60], 10);
+        builder.on_mid(0, 100.0);
+        let trade = ParsedTrade {
+            exchange: ExchangeId::Binance,
+            trade_id: 1,
+            price: 100.5,
+            amount: 2.0,
+            side: TradeSide::Buy,
+            timestamp: 0,
+        };
+        builder.on_trade(0, &trade);
+
+        let closed = builder.on_mid(SECOND, 100.2);
+        assert_eq!(closed.len(), 1);
+        assert_eq!(closed[0].source(), CandleSource::Mid);
+        assert_eq!(closed[0].volume, 0.0);
+
+        let trades_1m = builder.series(CandleSource::Trades, 60).unwrap();
+        assert_eq!(trades_1m.current().unwrap().volume, 2.0);
+        assert!(builder.series(CandleSource::Trades, 5).is_none());
+    }
+}
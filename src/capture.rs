@@ -0,0 +1,86 @@
+use anyhow::{anyhow, Context, Result};
+use clap::Parser;
+use futures::{SinkExt, StreamExt};
+use loshan_keyrock::exchanges::{
+    get_binance_stream, get_bitstamp_stream, BinanceDepth, ExchangeStream,
+};
+use loshan_keyrock::rest::BINANCE_REST;
+use std::fs;
+use std::path::{Path, PathBuf};
+use tokio_tungstenite::tungstenite::Message;
+
+/// writes the websocket frames and the snapshot body of a symbol exactly as the exchanges
+/// send them, the inputs of benches/parsing.rs
+#[derive(Parser)]
+struct Cli {
+    /// binance symbol, bitstamp uses the same name with usd for usdt
+    #[arg(default_value = "btcusdt")]
+    symbol: String,
+    /// text frames kept per exchange
+    #[arg(long, default_value_t = 100)]
+    messages: usize,
+    #[arg(long, default_value = "benches/messages")]
+    dir: PathBuf,
+}
+
+// one frame per line, the frames are single line json
+async fn capture_frames(mut stream: ExchangeStream, count: usize, path: &Path) -> Result<()> {
+    let mut lines = String::new();
+    let mut captured = 0;
+    while captured < count {
+        let message = stream
+            .next()
+            .await
+            .ok_or_else(|| anyhow!("socket closed after {} frames", captured))??;
+        match message {
+            Message::Text(text) => {
+                lines.push_str(&text);
+                lines.push('\n');
+                captured += 1;
+            }
+            Message::Ping(payload) => {
+                stream.send(Message::Pong(payload)).await?;
+            }
+            _ => {}
+        }
+    }
+    stream.close(None).await.ok();
+    fs::write(path, lines).with_context(|| format!("failed to write {}", path.display()))
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let args = Cli::parse();
+    let binance_symbol = args.symbol.to_lowercase();
+    let bitstamp_symbol = binance_symbol.replace("usdt", "usd");
+    fs::create_dir_all(&args.dir)?;
+
+    let depth = BinanceDepth::default();
+    let (binance, bitstamp) = tokio::try_join!(
+        get_binance_stream(&binance_symbol, depth.update_speed_ms),
+        get_bitstamp_stream(&bitstamp_symbol),
+    )?;
+    let (binance_path, bitstamp_path) = (
+        args.dir.join("binance_depth.jsonl"),
+        args.dir.join("bitstamp_diff.jsonl"),
+    );
+    tokio::try_join!(
+        capture_frames(binance, args.messages, &binance_path),
+        capture_frames(bitstamp, args.messages, &bitstamp_path),
+    )?;
+
+    let url = format!(
+        "https://api.binance.com/api/v3/depth?symbol={}&limit={}",
+        binance_symbol.to_uppercase(),
+        depth.snapshot_limit
+    );
+    let body = BINANCE_REST.get_text(&url, depth.snapshot_weight()).await?;
+    fs::write(args.dir.join("binance_snapshot.json"), body)?;
+    println!(
+        "captured {} frames per exchange and a {} levels snapshot in {}",
+        args.messages,
+        depth.snapshot_limit,
+        args.dir.display()
+    );
+    Ok(())
+}
@@ -1,25 +1,67 @@
-use anyhow::Result;
+use anyhow::{Context, Result};
 use clap::Parser;
-use clearscreen;
+use std::path::PathBuf;
 use tokio_stream::StreamExt;
+use tonic::metadata::{Ascii, MetadataValue};
+use tonic::service::{interceptor::InterceptedService, Interceptor};
+use tonic::transport::{Certificate, Channel, ClientTlsConfig, Identity};
+use tonic::{Request, Status};
 
 use loshan_keyrock::orderbookaggregator::{
-    orderbook_aggregator_client::OrderbookAggregatorClient, SummaryRequest,
+    orderbook_aggregator_client::OrderbookAggregatorClient, CandleSource, CandlesRequest,
+    SummaryRequest, TradesRequest,
 };
 
 #[derive(Parser)]
 struct Cli {
     symbol: String,
     levels: u32,
+    /// server url, https:// for TLS
+    #[arg(long, default_value = "http://127.0.0.1:5001")]
+    server: String,
+    /// PEM CA certificate the server certificate is checked against,
+    /// the system roots are used when not given
+    #[arg(long)]
+    tls_ca: Option<PathBuf>,
+    /// name expected in the server certificate, the server url host by default
+    #[arg(long)]
+    tls_domain: Option<String>,
+    /// PEM certificate presented to servers requiring client authentication
+    #[arg(long, requires = "tls_key")]
+    tls_cert: Option<PathBuf>,
+    /// PEM private key of --tls-cert
+    #[arg(long, requires = "tls_cert")]
+    tls_key: Option<PathBuf>,
+    /// api key sent as a bearer token, for servers started with --api-keys
+    #[arg(long)]
+    token: Option<String>,
+    /// report each price once, summing the amounts across exchanges
+    #[arg(long)]
+    consolidated: bool,
+    /// also print mid, microprice, imbalance and depth around mid
+    #[arg(long)]
+    analytics: bool,
+    /// basis points around mid used for the analytics depth
+    #[arg(long, default_value_t = 0.0)]
+    depth_bps: f64,
+    /// print the trades of both exchanges instead of the book summaries
+    #[arg(long)]
+    trades: bool,
+    /// with --trades, the Binance aggTrade stream (one trade per taker order and price)
+    #[arg(long)]
+    binance_aggregated: bool,
+    /// print the candles of this interval (in seconds) instead of the book summaries
+    #[arg(long)]
+    candles: Option<u32>,
+    /// candles built from the trades (with volume) instead of the mid
+    #[arg(long)]
+    trade_candles: bool,
+    /// closed candles printed before the live ones
+    #[arg(long, default_value_t = 20)]
+    history: u32,
 }
 
-async fn book_summary_stream(
-    mut client: OrderbookAggregatorClient<tonic::transport::Channel>,
-    symbol: String,
-    levels: u32,
-) -> Result<()> {
-    let summary_request = SummaryRequest { levels, symbol };
-
+async fn book_summary_stream(mut client: Client, summary_request: SummaryRequest) -> Result<()> {
     let mut stream = client.book_summary(summary_request).await?.into_inner();
     while let Some(summary) = stream.next().await {
         clearscreen::clear().expect("failed to clear screen");
@@ -33,12 +75,125 @@ async fn book_summary_stream(
     Ok(())
 }
 
+async fn trades_stream(mut client: Client, trades_request: TradesRequest) -> Result<()> {
+    let mut stream = client.trades(trades_request).await?.into_inner();
+    while let Some(trade) = stream.next().await {
+        let trade = trade?;
+        println!(
+            "{} {}: {:?} {} @ {}",
+            trade.timestamp,
+            trade.exchange,
+            trade.side(),
+            trade.amount,
+            trade.price
+        );
+    }
+    Ok(())
+}
+
+async fn candles_stream(mut client: Client, candles_request: CandlesRequest) -> Result<()> {
+    let mut stream = client.candles(candles_request).await?.into_inner();
+    while let Some(candle) = stream.next().await {
+        let candle = candle?;
+        println!(
+            "{} {}s o: {} h: {} l: {} c: {} v: {} ({} trades)",
+            candle.open_time,
+            candle.interval_secs,
+            candle.open,
+            candle.high,
+            candle.low,
+            candle.close,
+            candle.volume,
+            candle.trades
+        );
+    }
+    Ok(())
+}
+
+// adds the bearer token, if any, to every request
+#[derive(Clone)]
+struct TokenInterceptor {
+    authorization: Option<MetadataValue<Ascii>>,
+}
+
+impl Interceptor for TokenInterceptor {
+    fn call(&mut self, mut request: Request<()>) -> Result<Request<()>, Status> {
+        if let Some(authorization) = &self.authorization {
+            request
+                .metadata_mut()
+                .insert("authorization", authorization.clone());
+        }
+        Ok(request)
+    }
+}
+
+type Client = OrderbookAggregatorClient<InterceptedService<Channel, TokenInterceptor>>;
+
+async fn connect(args: &Cli) -> Result<Channel> {
+    let mut endpoint = Channel::from_shared(args.server.clone())?;
+    let tls_requested =
+        args.server.starts_with("https://") || args.tls_ca.is_some() || args.tls_cert.is_some();
+    if tls_requested {
+        let read = |path: &PathBuf| {
+            std::fs::read(path).with_context(|| format!("failed to read {}", path.display()))
+        };
+        let mut tls_config = ClientTlsConfig::new();
+        if let Some(ca_path) = &args.tls_ca {
+            tls_config = tls_config.ca_certificate(Certificate::from_pem(read(ca_path)?));
+        }
+        if let Some(domain) = &args.tls_domain {
+            tls_config = tls_config.domain_name(domain);
+        }
+        if let (Some(cert_path), Some(key_path)) = (&args.tls_cert, &args.tls_key) {
+            tls_config = tls_config.identity(Identity::from_pem(read(cert_path)?, read(key_path)?));
+        }
+        endpoint = endpoint.tls_config(tls_config)?;
+    }
+    Ok(endpoint.connect().await?)
+}
+
 #[tokio::main]
 async fn main() -> Result<()> {
-    let client = OrderbookAggregatorClient::connect("http://127.0.0.1:5001").await?;
-
     let args = Cli::parse();
-    _ = book_summary_stream(client, args.symbol, args.levels).await?;
+    let authorization = args
+        .token
+        .as_ref()
+        .map(|token| format!("Bearer {}", token).parse())
+        .transpose()
+        .context("token is not a valid header value")?;
+    let client = OrderbookAggregatorClient::with_interceptor(
+        connect(&args).await?,
+        TokenInterceptor { authorization },
+    );
+    if args.trades {
+        let trades_request = TradesRequest {
+            symbol: args.symbol,
+            binance_aggregated: args.binance_aggregated,
+        };
+        return trades_stream(client, trades_request).await;
+    }
+    if let Some(interval_secs) = args.candles {
+        let source = if args.trade_candles {
+            CandleSource::Trades
+        } else {
+            CandleSource::Mid
+        };
+        let candles_request = CandlesRequest {
+            symbol: args.symbol,
+            interval_secs,
+            source: source as i32,
+            history: args.history,
+        };
+        return candles_stream(client, candles_request).await;
+    }
+    let summary_request = SummaryRequest {
+        levels: args.levels,
+        symbol: args.symbol,
+        consolidated: args.consolidated,
+        analytics: args.analytics,
+        depth_bps: args.depth_bps,
+    };
+    book_summary_stream(client, summary_request).await?;
 
     Ok(())
 }
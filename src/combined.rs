@@ -0,0 +1,954 @@
+use crate::exchanges::{ExchangeStream, BINANCE_CONNECTION_LIFETIME};
+use anyhow::{anyhow, Context, Result};
+use futures::{SinkExt, Stream, StreamExt};
+use serde::Deserialize;
+use serde_json::value::RawValue;
+use std::collections::{HashMap, VecDeque};
+use std::pin::Pin;
+use std::task::{ready, Poll};
+use std::time::Duration;
+use tokio::sync::{mpsc, oneshot};
+use tokio::task::JoinHandle;
+use tokio::time::{self, Instant, MissedTickBehavior};
+use tokio_tungstenite::connect_async;
+use tokio_tungstenite::tungstenite::{self, Message};
+
+// the combined endpoint without any stream, they are added and removed with SUBSCRIBE
+// and UNSUBSCRIBE on the live socket
+pub const BINANCE_COMBINED_URL: &str = "wss://stream.binance.com:9443/stream";
+// binance refuses more streams on a single socket
+const MAX_STREAMS_PER_SOCKET: usize = 1024;
+// binance accepts 5 messages per second from a client (pings and pongs included)
+// and disconnects above that, SUBSCRIBE and UNSUBSCRIBE are spaced by this much
+const CONTROL_MESSAGE_INTERVAL: Duration = Duration::from_millis(300);
+// messages waiting for a subscriber, one too slow to keep up is dropped and resyncs
+const SUBSCRIBER_CAPACITY: usize = 1024;
+
+enum Command {
+    Subscribe {
+        stream_name: String,
+        reply: oneshot::Sender<Result<(u64, mpsc::Receiver<Message>)>>,
+    },
+    Unsubscribe {
+        stream_name: String,
+        id: u64,
+    },
+}
+
+// every message of the combined endpoint is wrapped with the name of its stream,
+// {"stream":"btcusdt@depth@100ms","data":{...}}
+#[derive(Deserialize)]
+struct StreamMessage<'a> {
+    stream: &'a str,
+    #[serde(borrow)]
+    data: &'a RawValue,
+}
+
+// the position of a message in its stream: the last update id of a depth diff, the
+// trade id of a trade or of an aggregated trade. None for the other streams
+#[derive(Deserialize)]
+struct Sequence {
+    u: Option<u64>,
+    t: Option<u64>,
+    a: Option<u64>,
+}
+
+fn sequence(data: &RawValue) -> Option<u64> {
+    let sequence: Sequence = serde_json::from_str(data.get()).ok()?;
+    sequence.u.or(sequence.t).or(sequence.a)
+}
+
+// the answer to SUBSCRIBE and UNSUBSCRIBE, {"result":null,"id":1} or
+// {"error":{"code":2,"msg":"Invalid request"},"id":1}
+#[derive(Deserialize)]
+struct Response {
+    id: u64,
+    #[serde(default)]
+    error: Option<serde_json::Value>,
+}
+
+// one binance websocket shared by the feeds of every symbol, each of them subscribes to
+// its stream names and only receives their messages (the "data" part, as a socket of its
+// own would have sent it). It keeps the server under the binance connection limits.
+// Before the 24 hours limit a new socket subscribed to every stream is opened in the
+// background and swapped in once live. Connections and requests are made without holding
+// up the messages of the current socket
+#[derive(Clone)]
+pub struct BinanceCombined {
+    commands: mpsc::UnboundedSender<Command>,
+}
+
+impl BinanceCombined {
+    // the socket is opened on the first subscription and closed after the last one
+    pub fn start(url: &str, inactivity_timeout: Duration) -> Self {
+        let (commands, receiver) = mpsc::unbounded_channel();
+        tokio::spawn(Hub::new(url, inactivity_timeout).run(receiver));
+        BinanceCombined { commands }
+    }
+
+    // returns once the stream is live, see Joining
+    pub async fn subscribe(&self, stream_name: &str) -> Result<CombinedStream> {
+        let (reply, replied) = oneshot::channel();
+        self.commands
+            .send(Command::Subscribe {
+                stream_name: stream_name.to_string(),
+                reply,
+            })
+            .map_err(|_| anyhow!("binance combined socket stopped"))?;
+        let (id, receiver) = replied.await.context("binance combined socket stopped")??;
+        Ok(CombinedStream {
+            stream_name: stream_name.to_string(),
+            id,
+            receiver,
+            commands: self.commands.clone(),
+            closed: false,
+        })
+    }
+}
+
+// the messages of one stream name, unsubscribed when dropped. When the shared socket
+// goes away it ends with a close frame, like a socket of its own would
+pub struct CombinedStream {
+    stream_name: String,
+    id: u64,
+    receiver: mpsc::Receiver<Message>,
+    commands: mpsc::UnboundedSender<Command>,
+    closed: bool,
+}
+
+impl Stream for CombinedStream {
+    type Item = tungstenite::Result<Message>;
+
+    fn poll_next(
+        mut self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> Poll<Option<Self::Item>> {
+        if self.closed {
+            return Poll::Ready(None);
+        }
+        match ready!(self.receiver.poll_recv(cx)) {
+            Some(message) => Poll::Ready(Some(Ok(message))),
+            None => {
+                self.closed = true;
+                Poll::Ready(Some(Ok(Message::Close(None))))
+            }
+        }
+    }
+}
+
+impl Drop for CombinedStream {
+    fn drop(&mut self) {
+        // an error only means the hub is already gone
+        _ = self.commands.send(Command::Unsubscribe {
+            stream_name: std::mem::take(&mut self.stream_name),
+            id: self.id,
+        });
+    }
+}
+
+struct Hub {
+    url: String,
+    socket: Option<ExchangeStream>,
+    // the first socket being opened, the subscriptions wait for it in `joining`
+    connecting: Option<JoinHandle<Result<ExchangeStream>>>,
+    subscribers: HashMap<String, Vec<(u64, mpsc::Sender<Message>)>>,
+    // the streams subscribed to but not live yet
+    joining: HashMap<String, Joining>,
+    // SUBSCRIBE, UNSUBSCRIBE and the heartbeat pings waiting for their turn, sent
+    // CONTROL_MESSAGE_INTERVAL apart
+    requests: VecDeque<Message>,
+    // for both the subscribers and the SUBSCRIBE / UNSUBSCRIBE requests
+    next_id: u64,
+    connected_at: Instant,
+    last_received: Instant,
+    last_sent: Instant,
+    inactivity_timeout: Duration,
+    // the socket replacing the current one and the streams it is subscribed to
+    rotating: Option<JoinHandle<Result<Subscribed>>>,
+    // the position (see Sequence) of the last message handed out per stream while the
+    // new socket joins, until the new socket streams past it (see Hub::covered)
+    last_forwarded: HashMap<String, u64>,
+}
+
+// a stream is live once binance answered its SUBSCRIBE and sent its first message, only
+// then are its subscriptions handed out, so that a snapshot taken afterwards is not older
+// than the first message of the stream. A stream with nothing to send is live after
+// `inactivity_timeout` if the SUBSCRIBE was answered, its subscriptions fail otherwise
+struct Joining {
+    // the id binance answers the SUBSCRIBE with, {"result":null,"id":...}
+    request_id: u64,
+    acked: bool,
+    deadline: Instant,
+    waiting: Vec<Waiting>,
+}
+
+struct Waiting {
+    id: u64,
+    sender: mpsc::Sender<Message>,
+    receiver: mpsc::Receiver<Message>,
+    reply: oneshot::Sender<Result<(u64, mpsc::Receiver<Message>)>>,
+}
+
+// a new socket subscribed to the streams, with the stream messages it sent while joining
+struct Subscribed {
+    socket: ExchangeStream,
+    stream_names: Vec<String>,
+    received: Vec<String>,
+}
+
+// only lives until it is matched, the size of the socket does not matter
+#[allow(clippy::large_enum_variant)]
+enum Wake {
+    Command(Command),
+    Received(Option<tungstenite::Result<Message>>),
+    Connected(Result<ExchangeStream>),
+    Request,
+    JoinDeadline,
+    Heartbeat,
+    Silent,
+    Rotate,
+    Rotated(Result<Subscribed>),
+}
+
+fn request_message(method: &str, stream_names: &[&str], id: u64) -> Message {
+    let request = serde_json::json!({
+        "method": method,
+        "params": stream_names,
+        "id": id,
+    });
+    tracing::info!("binance combined socket request: {}", request);
+    Message::Text(request.to_string())
+}
+
+// the answer to a request, None for any other message
+fn parse_response(text: &str) -> Option<(u64, Result<()>)> {
+    let response: Response = serde_json::from_str(text).ok()?;
+    let result = match response.error {
+        Some(error) => Err(anyhow!(
+            "binance refused request {}: {}",
+            response.id,
+            error
+        )),
+        None => Ok(()),
+    };
+    Some((response.id, result))
+}
+
+async fn connect(url: &str) -> Result<ExchangeStream> {
+    let (socket, _) = connect_async(url)
+        .await
+        .context("Failed to connect to binance combined endpoint")?;
+    Ok(socket)
+}
+
+// a new socket subscribed to the streams, returned once live (see Joining) with the
+// stream messages read meanwhile
+async fn connect_subscribed(
+    url: &str,
+    stream_names: Vec<String>,
+    id: u64,
+    wait: Duration,
+) -> Result<Subscribed> {
+    let mut socket = connect(url).await?;
+    let names: Vec<&str> = stream_names.iter().map(String::as_str).collect();
+    // one message for all of them, binance counts messages and not streams
+    socket
+        .send(request_message("SUBSCRIBE", &names, id))
+        .await
+        .context("failed to subscribe the new socket")?;
+    let deadline = Instant::now() + wait;
+    let mut acked = false;
+    let mut received = Vec::new();
+    while !acked || received.is_empty() {
+        let message = match time::timeout_at(deadline, socket.next()).await {
+            Ok(Some(message)) => message.context("new socket failed")?,
+            Ok(None) => return Err(anyhow!("new socket ended")),
+            Err(_) if acked => break,
+            Err(_) => return Err(anyhow!("no answer to the SUBSCRIBE of the new socket")),
+        };
+        match message {
+            Message::Text(text) if serde_json::from_str::<StreamMessage>(&text).is_ok() => {
+                received.push(text)
+            }
+            Message::Text(text) => {
+                if let Some((response_id, result)) = parse_response(&text) {
+                    if response_id == id {
+                        result?;
+                        acked = true;
+                    }
+                }
+            }
+            // the pong tungstenite queued is sent with the flush
+            Message::Ping(_) => socket.flush().await.context("failed to answer ping")?,
+            Message::Close(frame) => return Err(anyhow!("new socket closed: {:?}", frame)),
+            _ => {}
+        }
+    }
+    Ok(Subscribed {
+        socket,
+        stream_names,
+        received,
+    })
+}
+
+// sleeps until the deadline, forever without one
+async fn sleep_until(deadline: Option<Instant>) {
+    match deadline {
+        Some(deadline) => time::sleep_until(deadline).await,
+        None => std::future::pending().await,
+    }
+}
+
+impl Hub {
+    fn new(url: &str, inactivity_timeout: Duration) -> Self {
+        Hub {
+            url: url.to_string(),
+            socket: None,
+            connecting: None,
+            subscribers: HashMap::new(),
+            joining: HashMap::new(),
+            requests: VecDeque::new(),
+            next_id: 0,
+            connected_at: Instant::now(),
+            last_received: Instant::now(),
+            last_sent: Instant::now(),
+            inactivity_timeout,
+            rotating: None,
+            last_forwarded: HashMap::new(),
+        }
+    }
+
+    async fn run(mut self, mut commands: mpsc::UnboundedReceiver<Command>) {
+        let heartbeat_period = self.inactivity_timeout / 3;
+        let mut heartbeat = time::interval(heartbeat_period);
+        heartbeat.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        loop {
+            let silent_at = self.last_received + self.inactivity_timeout;
+            let rotate_at = self.connected_at + BINANCE_CONNECTION_LIFETIME;
+            let next_request_at = self.last_sent + CONTROL_MESSAGE_INTERVAL;
+            let join_deadline = self.joining.values().map(|joining| joining.deadline).min();
+            let connected = self.socket.is_some();
+            let rotating = self.rotating.is_some();
+            let requesting = connected && !self.requests.is_empty();
+            let wake = tokio::select! {
+                command = commands.recv() => match command {
+                    Some(command) => Wake::Command(command),
+                    None => break,
+                },
+                received = async {
+                    match self.socket.as_mut() {
+                        Some(socket) => socket.next().await,
+                        None => std::future::pending().await,
+                    }
+                } => Wake::Received(received),
+                connected = async {
+                    match self.connecting.as_mut() {
+                        Some(connecting) => connecting.await,
+                        None => std::future::pending().await,
+                    }
+                } => Wake::Connected(
+                    connected.unwrap_or_else(|err| Err(err).context("connect task failed")),
+                ),
+                _ = time::sleep_until(next_request_at), if requesting => Wake::Request,
+                _ = sleep_until(join_deadline) => Wake::JoinDeadline,
+                _ = heartbeat.tick(), if connected => Wake::Heartbeat,
+                _ = time::sleep_until(silent_at), if connected => Wake::Silent,
+                _ = time::sleep_until(rotate_at), if connected && !rotating => Wake::Rotate,
+                connected = async {
+                    match self.rotating.as_mut() {
+                        Some(connecting) => connecting.await,
+                        None => std::future::pending().await,
+                    }
+                } => Wake::Rotated(
+                    connected.unwrap_or_else(|err| Err(err).context("connect task failed")),
+                ),
+            };
+
+            match wake {
+                Wake::Command(Command::Subscribe { stream_name, reply }) => {
+                    self.subscribe(stream_name, reply);
+                }
+                Wake::Command(Command::Unsubscribe { stream_name, id }) => {
+                    self.unsubscribe(&stream_name, id).await;
+                }
+                Wake::Received(Some(Ok(Message::Text(text)))) => {
+                    self.last_received = Instant::now();
+                    self.dispatch(&text).await;
+                }
+                // the pong tungstenite queued is sent with the flush
+                Wake::Received(Some(Ok(Message::Ping(_)))) => {
+                    self.last_received = Instant::now();
+                    if let Err(err) = self.send(None).await {
+                        self.disconnect(&format!("failed to answer ping: {:#}", err));
+                    }
+                }
+                Wake::Received(Some(Ok(Message::Close(frame)))) => {
+                    self.disconnect(&format!("closed by binance: {:?}", frame));
+                }
+                Wake::Received(Some(Ok(_))) => self.last_received = Instant::now(),
+                Wake::Received(Some(Err(err))) => self.disconnect(&err.to_string()),
+                Wake::Received(None) => self.disconnect("socket ended"),
+                Wake::Connected(connected) => {
+                    self.connecting = None;
+                    match connected {
+                        Ok(socket) => {
+                            tracing::info!("binance combined socket connected");
+                            self.socket = Some(socket);
+                            self.connected_at = Instant::now();
+                            self.last_received = Instant::now();
+                        }
+                        Err(err) => {
+                            tracing::warn!("binance combined socket failed to connect: {:#}", err);
+                            for (_, joining) in self.joining.drain() {
+                                for waiting in joining.waiting {
+                                    _ = waiting.reply.send(Err(anyhow!("{:#}", err)));
+                                }
+                            }
+                            self.requests.clear();
+                        }
+                    }
+                }
+                Wake::Request => {
+                    if let Some(request) = self.requests.pop_front() {
+                        if let Err(err) = self.send(Some(request)).await {
+                            self.disconnect(&format!("failed to send request: {:#}", err));
+                        }
+                    }
+                }
+                Wake::JoinDeadline => self.join_expired().await,
+                // paced with the requests, binance counts the pings as well
+                Wake::Heartbeat => {
+                    let ping_queued = self
+                        .requests
+                        .iter()
+                        .any(|request| matches!(request, Message::Ping(_)));
+                    if !ping_queued {
+                        self.requests.push_back(Message::Ping(Vec::new()));
+                    }
+                }
+                Wake::Silent => self.disconnect("nothing received"),
+                Wake::Rotate => {
+                    tracing::info!("opening a new binance combined socket");
+                    let url = self.url.clone();
+                    let stream_names = self.subscribers.keys().cloned().collect();
+                    let id = self.next_id();
+                    let wait = self.inactivity_timeout;
+                    self.rotating = Some(tokio::spawn(async move {
+                        connect_subscribed(&url, stream_names, id, wait).await
+                    }));
+                }
+                Wake::Rotated(connected) => {
+                    self.rotating = None;
+                    match connected {
+                        Ok(subscribed) => self.swap(subscribed).await,
+                        Err(err) => {
+                            tracing::warn!("planned binance combined reconnect failed: {:#}", err);
+                            self.last_forwarded.clear();
+                            // tried again a minute later
+                            self.connected_at += Duration::from_secs(60);
+                        }
+                    }
+                }
+            }
+        }
+        self.close().await;
+    }
+
+    // the old socket streams until the new one is live. The streams added or removed
+    // meanwhile are subscribed or unsubscribed on the new socket, the ones still joining
+    // are subscribed again, then the old socket is closed and the messages of the new
+    // one read so far handed out, without the ones the old socket already handed out
+    async fn swap(&mut self, subscribed: Subscribed) {
+        let Subscribed {
+            socket,
+            stream_names,
+            received,
+        } = subscribed;
+        let Some(mut old_socket) = self.socket.replace(socket) else {
+            return;
+        };
+        self.connected_at = Instant::now();
+        self.last_received = Instant::now();
+        let added: Vec<String> = self
+            .subscribers
+            .keys()
+            .filter(|stream_name| !stream_names.contains(stream_name))
+            .cloned()
+            .collect();
+        let removed: Vec<String> = stream_names
+            .into_iter()
+            .filter(|stream_name| !self.subscribers.contains_key(stream_name))
+            .collect();
+        for (method, stream_names) in [("SUBSCRIBE", added), ("UNSUBSCRIBE", removed)] {
+            if stream_names.is_empty() {
+                continue;
+            }
+            let names: Vec<&str> = stream_names.iter().map(String::as_str).collect();
+            self.queue_request(method, &names);
+        }
+        let joining: Vec<String> = self.joining.keys().cloned().collect();
+        for stream_name in joining {
+            let request_id = self.queue_request("SUBSCRIBE", &[&stream_name]);
+            if let Some(joining) = self.joining.get_mut(&stream_name) {
+                joining.request_id = request_id;
+                joining.acked = false;
+            }
+        }
+        if let Err(err) = old_socket.close(None).await {
+            tracing::warn!("failed to close the old binance combined socket: {}", err);
+        }
+        tracing::info!("binance combined socket replaced");
+        for text in received {
+            self.dispatch(&text).await;
+        }
+    }
+
+    // answered right away for a live stream, once it is live otherwise
+    fn subscribe(
+        &mut self,
+        stream_name: String,
+        reply: oneshot::Sender<Result<(u64, mpsc::Receiver<Message>)>>,
+    ) {
+        let (sender, receiver) = mpsc::channel(SUBSCRIBER_CAPACITY);
+        let id = self.next_id();
+        if let Some(subscribers) = self.subscribers.get_mut(&stream_name) {
+            subscribers.push((id, sender));
+            // an error only means the feed stopped waiting
+            _ = reply.send(Ok((id, receiver)));
+            return;
+        }
+        let waiting = Waiting {
+            id,
+            sender,
+            receiver,
+            reply,
+        };
+        if let Some(joining) = self.joining.get_mut(&stream_name) {
+            joining.waiting.push(waiting);
+            return;
+        }
+        if self.subscribers.len() + self.joining.len() >= MAX_STREAMS_PER_SOCKET {
+            _ = waiting.reply.send(Err(anyhow!(
+                "binance combined socket already has {} streams",
+                MAX_STREAMS_PER_SOCKET
+            )));
+            return;
+        }
+        if self.socket.is_none() && self.connecting.is_none() {
+            let url = self.url.clone();
+            self.connecting = Some(tokio::spawn(async move { connect(&url).await }));
+        }
+        let request_id = self.queue_request("SUBSCRIBE", &[&stream_name]);
+        self.joining.insert(
+            stream_name,
+            Joining {
+                request_id,
+                acked: false,
+                deadline: Instant::now() + self.inactivity_timeout,
+                waiting: vec![waiting],
+            },
+        );
+    }
+
+    // hands out the subscriptions of a stream now live
+    fn go_live(&mut self, stream_name: String, joining: Joining) {
+        let subscribers = self.subscribers.entry(stream_name).or_default();
+        for waiting in joining.waiting {
+            // a feed that stopped waiting gets dropped on the next message
+            if waiting
+                .reply
+                .send(Ok((waiting.id, waiting.receiver)))
+                .is_ok()
+            {
+                subscribers.push((waiting.id, waiting.sender));
+            }
+        }
+    }
+
+    // quiet streams answered by binance are live, the others failed
+    async fn join_expired(&mut self) {
+        let now = Instant::now();
+        let expired: Vec<String> = self
+            .joining
+            .iter()
+            .filter(|(_, joining)| joining.deadline <= now)
+            .map(|(stream_name, _)| stream_name.clone())
+            .collect();
+        for stream_name in expired {
+            let joining = self.joining.remove(&stream_name).expect("listed above");
+            if joining.acked {
+                self.go_live(stream_name, joining);
+                continue;
+            }
+            tracing::warn!("no answer to the {} subscription", stream_name);
+            for waiting in joining.waiting {
+                _ = waiting.reply.send(Err(anyhow!(
+                    "binance did not answer the {} subscription",
+                    stream_name
+                )));
+            }
+            self.remove_stream(&stream_name).await;
+        }
+    }
+
+    async fn unsubscribe(&mut self, stream_name: &str, id: u64) {
+        let Some(subscribers) = self.subscribers.get_mut(stream_name) else {
+            return;
+        };
+        subscribers.retain(|(subscriber_id, _)| *subscriber_id != id);
+        if subscribers.is_empty() {
+            self.remove_stream(stream_name).await;
+        }
+    }
+
+    // the last subscriber of the stream is gone
+    async fn remove_stream(&mut self, stream_name: &str) {
+        self.subscribers.remove(stream_name);
+        self.last_forwarded.remove(stream_name);
+        if self.subscribers.is_empty() && self.joining.is_empty() {
+            self.close().await;
+        } else {
+            self.queue_request("UNSUBSCRIBE", &[stream_name]);
+        }
+    }
+
+    // hands the data of a stream message to the subscribers of its stream, the other
+    // messages are the answers to SUBSCRIBE and UNSUBSCRIBE
+    async fn dispatch(&mut self, text: &str) {
+        let Ok(message) = serde_json::from_str::<StreamMessage>(text) else {
+            tracing::info!("binance combined socket: {}", text);
+            self.answered(text).await;
+            return;
+        };
+        // messages sent before the SUBSCRIBE was answered are not handed out
+        if self
+            .joining
+            .get(message.stream)
+            .is_some_and(|joining| joining.acked)
+        {
+            let (stream_name, joining) = self
+                .joining
+                .remove_entry(message.stream)
+                .expect("checked above");
+            self.go_live(stream_name, joining);
+        }
+        if !self.subscribers.contains_key(message.stream) || self.covered(&message) {
+            return;
+        }
+        let Some(subscribers) = self.subscribers.get_mut(message.stream) else {
+            return;
+        };
+        let data = Message::Text(message.data.get().to_string());
+        subscribers.retain(|(_, sender)| match sender.try_send(data.clone()) {
+            Ok(()) => true,
+            Err(mpsc::error::TrySendError::Full(_)) => {
+                tracing::warn!("{} subscriber too slow, dropped", message.stream);
+                false
+            }
+            Err(mpsc::error::TrySendError::Closed(_)) => false,
+        });
+        if subscribers.is_empty() {
+            let stream_name = message.stream.to_string();
+            self.remove_stream(&stream_name).await;
+        }
+    }
+
+    // both sockets stream the same messages around a swap: while the new one joins, the
+    // position of what the old one hands out is kept, then the messages of the new one
+    // up to it are dropped. Trades would be handed out twice and out of order otherwise
+    fn covered(&mut self, message: &StreamMessage) -> bool {
+        let rotating = self.rotating.is_some();
+        if !rotating && self.last_forwarded.is_empty() {
+            return false;
+        }
+        let Some(position) = sequence(message.data) else {
+            return false;
+        };
+        if rotating {
+            match self.last_forwarded.get_mut(message.stream) {
+                Some(last_forwarded) => *last_forwarded = position,
+                None => {
+                    self.last_forwarded
+                        .insert(message.stream.to_string(), position);
+                }
+            }
+            return false;
+        }
+        match self.last_forwarded.get(message.stream) {
+            Some(last_forwarded) if position <= *last_forwarded => true,
+            Some(_) => {
+                self.last_forwarded.remove(message.stream);
+                false
+            }
+            None => false,
+        }
+    }
+
+    // the answer to the SUBSCRIBE of a joining stream, its subscriptions fail on an error
+    async fn answered(&mut self, text: &str) {
+        let Some((id, result)) = parse_response(text) else {
+            return;
+        };
+        let Some(stream_name) = self
+            .joining
+            .iter()
+            .find(|(_, joining)| joining.request_id == id)
+            .map(|(stream_name, _)| stream_name.clone())
+        else {
+            return;
+        };
+        match result {
+            Ok(()) => {
+                if let Some(joining) = self.joining.get_mut(&stream_name) {
+                    joining.acked = true;
+                }
+            }
+            Err(err) => {
+                let joining = self.joining.remove(&stream_name).expect("found above");
+                for waiting in joining.waiting {
+                    _ = waiting.reply.send(Err(anyhow!("{:#}", err)));
+                }
+                // nothing to unsubscribe, the socket is closed if nothing else uses it
+                if self.subscribers.is_empty() && self.joining.is_empty() {
+                    self.close().await;
+                }
+            }
+        }
+    }
+
+    // sent in order by the hub loop, spaced by CONTROL_MESSAGE_INTERVAL
+    fn queue_request(&mut self, method: &str, stream_names: &[&str]) -> u64 {
+        let id = self.next_id();
+        self.requests
+            .push_back(request_message(method, stream_names, id));
+        id
+    }
+
+    // sends the message if any and flushes whatever tungstenite has queued
+    async fn send(&mut self, message: Option<Message>) -> Result<()> {
+        let Some(socket) = self.socket.as_mut() else {
+            return Ok(());
+        };
+        self.last_sent = Instant::now();
+        match message {
+            Some(message) => socket.send(message).await,
+            None => socket.flush().await,
+        }
+        .context("websocket send failed")
+    }
+
+    // dropping the senders ends every subscriber with a close frame, their feeds
+    // reconnect and subscribe again on a new socket. The subscriptions still joining fail
+    fn disconnect(&mut self, reason: &str) {
+        self.stop_rotating();
+        if self.socket.take().is_some() {
+            tracing::warn!("binance combined socket dropped: {}", reason);
+        }
+        self.subscribers.clear();
+        self.joining.clear();
+        self.requests.clear();
+        self.last_forwarded.clear();
+    }
+
+    async fn close(&mut self) {
+        self.stop_rotating();
+        if let Some(connecting) = self.connecting.take() {
+            connecting.abort();
+        }
+        self.requests.clear();
+        if let Some(mut socket) = self.socket.take() {
+            if let Err(err) = socket.close(None).await {
+                tracing::warn!("failed to close binance combined socket: {}", err);
+            }
+        }
+    }
+
+    fn stop_rotating(&mut self) {
+        if let Some(connecting) = self.rotating.take() {
+            connecting.abort();
+        }
+    }
+
+    fn next_id(&mut self) -> u64 {
+        self.next_id += 1;
+        self.next_id
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+    use tokio_tungstenite::accept_async;
+
+    async fn next_text(stream: &mut CombinedStream) -> String {
+        match stream.next().await.unwrap().unwrap() {
+            Message::Text(text) => text,
+            message => panic!("unexpected {:?}", message),
+        }
+    }
+
+    fn request(message: Message) -> Option<(String, u64)> {
+        let Message::Text(text) = message else {
+            return None;
+        };
+        let request: serde_json::Value = serde_json::from_str(&text).unwrap();
+        Some((
+            format!("{} {}", request["method"], request["params"][0]),
+            request["id"].as_u64().unwrap(),
+        ))
+    }
+
+    #[tokio::test]
+    async fn demultiplexes_streams_of_one_socket() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let exchange = tokio::spawn(async move {
+            let (socket, _) = listener.accept().await.unwrap();
+            let mut socket = accept_async(socket).await.unwrap();
+            let mut requests = Vec::new();
+            // each SUBSCRIBE is answered where "answer" is, the data sent before the
+            // answer is not handed out
+            for messages in [
+                [
+                    "answer",
+                    r#"{"stream":"ethbtc@depth@100ms","data":{"u":1}}"#,
+                ]
+                .as_slice(),
+                &[
+                    r#"{"stream":"btcusdt@depth@100ms","data":{"u":2}}"#,
+                    "answer",
+                    r#"{"stream":"btcusdt@depth@100ms","data":{"u":3}}"#,
+                    r#"{"stream":"ltcbtc@depth@100ms","data":{"u":4}}"#,
+                    r#"{"stream":"ethbtc@depth@100ms","data":{"u":5}}"#,
+                ],
+            ] {
+                let (subscribe, id) = loop {
+                    if let Some(request) = request(socket.next().await.unwrap().unwrap()) {
+                        break request;
+                    }
+                };
+                requests.push(subscribe);
+                for message in messages {
+                    let message = match *message {
+                        "answer" => format!(r#"{{"result":null,"id":{}}}"#, id),
+                        message => message.to_string(),
+                    };
+                    socket.send(Message::Text(message)).await.unwrap();
+                }
+            }
+            // the last subscriber leaving closes the socket
+            while let Some(Ok(message)) = socket.next().await {
+                if let Some((request, _)) = request(message) {
+                    requests.push(request);
+                }
+            }
+            requests
+        });
+
+        let combined =
+            BinanceCombined::start(&format!("ws://{}/stream", address), Duration::from_secs(30));
+        let mut ethbtc = combined.subscribe("ethbtc@depth@100ms").await.unwrap();
+        let mut btcusdt = combined.subscribe("btcusdt@depth@100ms").await.unwrap();
+        assert_eq!(next_text(&mut ethbtc).await, r#"{"u":1}"#);
+        assert_eq!(next_text(&mut btcusdt).await, r#"{"u":3}"#);
+        assert_eq!(next_text(&mut ethbtc).await, r#"{"u":5}"#);
+        drop(ethbtc);
+        // the UNSUBSCRIBE waits for its turn, a socket closed before has nothing to unsubscribe
+        time::sleep(CONTROL_MESSAGE_INTERVAL * 2).await;
+        drop(btcusdt);
+
+        assert_eq!(
+            exchange.await.unwrap(),
+            vec![
+                r#""SUBSCRIBE" "ethbtc@depth@100ms""#,
+                r#""SUBSCRIBE" "btcusdt@depth@100ms""#,
+                r#""UNSUBSCRIBE" "ethbtc@depth@100ms""#,
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn swap_drops_the_trades_the_old_socket_handed_out() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("ws://{}/stream", listener.local_addr().unwrap());
+        // the old and the new socket, kept open until closed by the hub
+        tokio::spawn(async move {
+            loop {
+                let (socket, _) = listener.accept().await.unwrap();
+                tokio::spawn(async move {
+                    let mut socket = accept_async(socket).await.unwrap();
+                    while let Some(Ok(_)) = socket.next().await {}
+                });
+            }
+        });
+        let trade = |trade_id: u64| {
+            format!(
+                r#"{{"stream":"btcusdt@trade","data":{{"e":"trade","t":{}}}}}"#,
+                trade_id
+            )
+        };
+
+        let mut hub = Hub::new(&url, Duration::from_secs(30));
+        hub.socket = Some(connect(&url).await.unwrap());
+        let (sender, mut receiver) = mpsc::channel(16);
+        hub.subscribers
+            .insert("btcusdt@trade".to_string(), vec![(1, sender)]);
+        hub.rotating = Some(tokio::spawn(std::future::pending()));
+        for trade_id in 1..=4 {
+            hub.dispatch(&trade(trade_id)).await;
+        }
+        hub.stop_rotating();
+        // the new socket joined at trade 2, it streams 4 again before going further
+        hub.swap(Subscribed {
+            socket: connect(&url).await.unwrap(),
+            stream_names: vec!["btcusdt@trade".to_string()],
+            received: vec![trade(2), trade(3)],
+        })
+        .await;
+        for trade_id in 4..=6 {
+            hub.dispatch(&trade(trade_id)).await;
+        }
+
+        let mut trade_ids = Vec::new();
+        while let Ok(Message::Text(text)) = receiver.try_recv() {
+            let data: serde_json::Value = serde_json::from_str(&text).unwrap();
+            trade_ids.push(data["t"].as_u64().unwrap());
+        }
+        assert_eq!(trade_ids, vec![1, 2, 3, 4, 5, 6]);
+        assert!(hub.last_forwarded.is_empty());
+        assert!(hub.requests.is_empty());
+    }
+
+    #[tokio::test]
+    async fn new_socket_is_live_after_its_answer_and_first_message() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (socket, _) = listener.accept().await.unwrap();
+            let mut socket = accept_async(socket).await.unwrap();
+            let (_, id) = request(socket.next().await.unwrap().unwrap()).unwrap();
+            for message in [
+                r#"{"stream":"ethbtc@depth@100ms","data":{"u":1}}"#.to_string(),
+                format!(r#"{{"result":null,"id":{}}}"#, id + 1),
+                format!(r#"{{"result":null,"id":{}}}"#, id),
+                r#"{"stream":"ethbtc@depth@100ms","data":{"u":2}}"#.to_string(),
+            ] {
+                socket.send(Message::Text(message)).await.unwrap();
+            }
+            while socket.next().await.is_some() {}
+        });
+
+        let subscribed = connect_subscribed(
+            &format!("ws://{}/stream", address),
+            vec!["ethbtc@depth@100ms".to_string()],
+            7,
+            Duration::from_secs(5),
+        )
+        .await
+        .unwrap();
+        // returned with its answer, what it streamed until then is kept for the swap
+        assert_eq!(subscribed.received.len(), 1);
+        assert!(subscribed.received[0].contains(r#"{"u":1}"#));
+    }
+}
@@ -1,85 +1,296 @@
-use crate::orderbookaggregator::Level;
-use anyhow::{Context, Result};
-use futures::stream::SplitStream;
-use futures::{SinkExt, StreamExt};
-use reqwest;
+use crate::levels::{
+    parse_bitstamp_message, parse_levels, BINANCE_DIFF, BINANCE_SNAPSHOT, BITSTAMP_SNAPSHOT,
+};
+use crate::orderbookaggregator::{Side, Trade};
+use crate::rest::{BINANCE_REST, BITSTAMP_REST};
+use anyhow::{anyhow, Context, Result};
+use futures::SinkExt;
 use serde_json::Value;
+use std::fmt;
+use std::str::FromStr;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use tokio::net::TcpStream;
-use tokio_stream::StreamMap;
 use tokio_tungstenite::{connect_async, tungstenite::Message, MaybeTlsStream, WebSocketStream};
 
-// "BINANCE" "BITSTAMP" are kept hard coded across the codebase
-// maybe a future improvement would be to somehow handle this better
+// compact identifier used inside the order book instead of the exchange name,
+// "BINANCE" and "BITSTAMP" strings are only produced for the gRPC Level messages.
 // every exchange currently would need specific functions anyway.
-#[derive(Debug, Default)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum ExchangeId {
+    Binance,
+    Bitstamp,
+}
+
+impl ExchangeId {
+    pub const ALL: [ExchangeId; 2] = [ExchangeId::Binance, ExchangeId::Bitstamp];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ExchangeId::Binance => "BINANCE",
+            ExchangeId::Bitstamp => "BITSTAMP",
+        }
+    }
+}
+
+impl fmt::Display for ExchangeId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for ExchangeId {
+    type Err = anyhow::Error;
+
+    fn from_str(exchange: &str) -> Result<Self> {
+        match exchange.to_uppercase().as_str() {
+            "BINANCE" => Ok(ExchangeId::Binance),
+            "BITSTAMP" => Ok(ExchangeId::Bitstamp),
+            _ => Err(anyhow!("unknown exchange {}", exchange)),
+        }
+    }
+}
+
+// price and amount as received, the exchange is the same for the whole update
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ParsedLevel {
+    pub price: f64,
+    pub amount: f64,
+}
+
+// snapshots come from the REST endpoints and describe the whole book of one exchange,
+// diffs come from the websockets and only carry the levels that changed (possibly none)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UpdateKind {
+    Snapshot,
+    Diff,
+}
+
+#[derive(Debug, Clone)]
 pub struct ParsedUpdate {
-    pub bids: Vec<Level>,
-    pub asks: Vec<Level>,
+    pub exchange: ExchangeId,
+    pub kind: UpdateKind,
+    pub bids: Vec<ParsedLevel>,
+    pub asks: Vec<ParsedLevel>,
     pub last_update_id: u64,
+    // the first update id a binance diff covers ("U"), it follows the previous diff when it
+    // is at most one past its last_update_id. None for the updates without such a sequence
+    pub first_update_id: Option<u64>,
+}
+
+// side of the taker, the one that crossed the spread
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TradeSide {
+    Buy,
+    Sell,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ParsedTrade {
+    pub exchange: ExchangeId,
+    pub trade_id: u64,
+    pub price: f64,
+    pub amount: f64,
+    pub side: TradeSide,
+    pub timestamp: u64, // microseconds since epoch for both exchanges
 }
 
-pub async fn get_bitstamp_snapshot(symbol: &String) -> Result<ParsedUpdate> {
+impl From<&ParsedTrade> for Trade {
+    fn from(trade: &ParsedTrade) -> Self {
+        let side = match trade.side {
+            TradeSide::Buy => Side::Buy,
+            TradeSide::Sell => Side::Sell,
+        };
+        Trade {
+            exchange: trade.exchange.to_string(),
+            trade_id: trade.trade_id,
+            price: trade.price,
+            amount: trade.amount,
+            side: side as i32,
+            timestamp: trade.timestamp,
+        }
+    }
+}
+
+pub async fn get_bitstamp_snapshot(symbol: &str) -> Result<ParsedUpdate> {
     let url = format!(
         "https://www.bitstamp.net/api/v2/order_book/{}/",
         symbol.to_lowercase()
     );
     tracing::info!("bitsamp initial snapshot url: {}", url);
-    let request_result = reqwest::get(url).await?;
-    let message_value = request_result.json::<serde_json::Value>().await?;
-    let parsed_update = bitstamp_json_snapshot_to_levels(&message_value);
-    return parsed_update;
+    let body = BITSTAMP_REST.get_text(&url, 1).await?;
+    parse_levels(&body, &BITSTAMP_SNAPSHOT)
+}
+
+// update speed of the binance diff stream and depth of the REST snapshot of one symbol
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BinanceDepth {
+    // 100 or 1000
+    pub update_speed_ms: u32,
+    // 1 to 5000 prices per side
+    pub snapshot_limit: u32,
 }
 
-pub async fn get_binance_snapshot(symbol: &String) -> Result<ParsedUpdate> {
+impl Default for BinanceDepth {
+    fn default() -> Self {
+        BinanceDepth {
+            update_speed_ms: 100,
+            snapshot_limit: 1000,
+        }
+    }
+}
+
+impl BinanceDepth {
+    pub fn new(update_speed_ms: u32, snapshot_limit: u32) -> Result<Self> {
+        if update_speed_ms != 100 && update_speed_ms != 1000 {
+            return Err(anyhow!(
+                "binance update speed is 100 or 1000 ms, not {}",
+                update_speed_ms
+            ));
+        }
+        if !(1..=5000).contains(&snapshot_limit) {
+            return Err(anyhow!(
+                "binance snapshot limit is between 1 and 5000, not {}",
+                snapshot_limit
+            ));
+        }
+        Ok(BinanceDepth {
+            update_speed_ms,
+            snapshot_limit,
+        })
+    }
+
+    // REST request weight of the snapshot, binance charges more for deeper books
+    pub fn snapshot_weight(&self) -> u32 {
+        match self.snapshot_limit {
+            0..=100 => 5,
+            101..=500 => 25,
+            501..=1000 => 50,
+            _ => 250,
+        }
+    }
+}
+
+// "{update speed ms}:{snapshot limit}", e.g. "1000:5000"
+impl FromStr for BinanceDepth {
+    type Err = anyhow::Error;
+
+    fn from_str(depth: &str) -> Result<Self> {
+        let (update_speed_ms, snapshot_limit) = depth
+            .split_once(':')
+            .with_context(|| format!("expected update_speed_ms:snapshot_limit, got {}", depth))?;
+        BinanceDepth::new(
+            update_speed_ms
+                .trim_end_matches("ms")
+                .parse()
+                .context("invalid binance update speed")?,
+            snapshot_limit
+                .parse()
+                .context("invalid binance snapshot limit")?,
+        )
+    }
+}
+
+// a bitstamp snapshot taken before the first diff of the stream would miss the diffs in
+// between, it is taken again until it is at least as new
+const BITSTAMP_SNAPSHOT_ATTEMPTS: u32 = 5;
+const BITSTAMP_SNAPSHOT_RETRY: Duration = Duration::from_millis(500);
+
+// the documented way to start a bitstamp book: subscribe to diff_order_book, buffer the
+// diffs, take a snapshot no older than the first of them and only apply the diffs newer
+// than the snapshot (the book drops the others)
+pub async fn get_aligned_bitstamp_snapshot(
+    symbol: &str,
+    first_diff_id: u64,
+) -> Result<ParsedUpdate> {
+    for attempt in 1..=BITSTAMP_SNAPSHOT_ATTEMPTS {
+        let snapshot = get_bitstamp_snapshot(symbol).await?;
+        if snapshot.last_update_id >= first_diff_id {
+            return Ok(snapshot);
+        }
+        tracing::info!(
+            "bitstamp {} snapshot at {} older than the first diff at {} (attempt {})",
+            symbol,
+            snapshot.last_update_id,
+            first_diff_id,
+            attempt
+        );
+        tokio::time::sleep(BITSTAMP_SNAPSHOT_RETRY).await;
+    }
+    Err(anyhow!(
+        "no bitstamp {} snapshot as new as the diff stream after {} attempts",
+        symbol,
+        BITSTAMP_SNAPSHOT_ATTEMPTS
+    ))
+}
+
+// waits for its request weight to fit in the binance budget (see rest.rs)
+pub async fn get_binance_snapshot(symbol: &str, depth: BinanceDepth) -> Result<ParsedUpdate> {
     let url = format!(
-        "https://api.binance.com/api/v3/depth?symbol={}&limit=1000",
+        "https://api.binance.com/api/v3/depth?symbol={}&limit={}",
         // "https://www.binance.us/api/v3/depth?symbol={}&limit=1000",
         // wrong endpoint, api.binance.com is the correct one
-        symbol.to_uppercase()
+        symbol.to_uppercase(),
+        depth.snapshot_limit
     );
     tracing::info!("binance initial snapshot url: {}", url);
 
-    let request_result = reqwest::get(url).await?;
-    let message_value = request_result.json::<serde_json::Value>().await?;
-    let parsed_update = binance_json_to_levels(message_value);
-    return parsed_update;
+    let body = BINANCE_REST.get_text(&url, depth.snapshot_weight()).await?;
+    parse_levels(&body, &BINANCE_SNAPSHOT)
 }
 
-pub async fn get_binance_stream(
-    symbol: &String,
-) -> Result<SplitStream<WebSocketStream<MaybeTlsStream<TcpStream>>>> {
-    // no depth level (5, 10 or 20) provided below or will return a full depth stream instead of diff stream
+// binance closes its websockets after 24 hours, they are replaced a bit before that
+pub const BINANCE_CONNECTION_LIFETIME: Duration = Duration::from_secs(23 * 3600 + 30 * 60);
+
+// the whole websocket and not only its read half, so that it can be closed properly
+pub type ExchangeStream = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+async fn get_binance_raw_stream(stream_name: &str) -> Result<ExchangeStream> {
     // "wss://stream.binance.us:9443" was wrong and wss://stream.binance.com:9443 was the correct one
     let ws_url_binance = url::Url::parse("wss://stream.binance.com:9443")
         .context("wrong binance url")?
-        .join(&format!("/ws/{}@depth@100ms", symbol))?;
+        .join(&format!("/ws/{}", stream_name))?;
 
     let (ws_stream_binance, _) = connect_async(&ws_url_binance)
         .await
         .context("Failed to connect to binance wss endpoint")?;
 
-    let (_, read_stream) = ws_stream_binance.split();
+    Ok(ws_stream_binance)
+}
+
+// no depth level (5, 10 or 20) in the name or it is a full depth stream instead of a diff
+// stream, without a speed the stream is updated every second
+pub fn binance_depth_stream_name(symbol: &str, update_speed_ms: u32) -> String {
+    match update_speed_ms {
+        1000 => format!("{}@depth", symbol),
+        _ => format!("{}@depth@100ms", symbol),
+    }
+}
+
+// aggTrade groups the fills of one taker order at the same price, trade sends every fill
+pub fn binance_trade_stream_name(symbol: &str, aggregated: bool) -> String {
+    let stream_type = if aggregated { "aggTrade" } else { "trade" };
+    format!("{}@{}", symbol, stream_type)
+}
+
+pub async fn get_binance_stream(symbol: &str, update_speed_ms: u32) -> Result<ExchangeStream> {
+    get_binance_raw_stream(&binance_depth_stream_name(symbol, update_speed_ms)).await
+}
 
-    Ok(read_stream)
+pub async fn get_binance_trade_stream(symbol: &str, aggregated: bool) -> Result<ExchangeStream> {
+    get_binance_raw_stream(&binance_trade_stream_name(symbol, aggregated)).await
 }
 
-pub async fn get_bitstamp_stream(
-    symbol: &String,
-) -> Result<SplitStream<WebSocketStream<MaybeTlsStream<TcpStream>>>> {
+async fn get_bitstamp_channel_stream(channel: &str) -> Result<ExchangeStream> {
     let ws_url_bitstamp = url::Url::parse("wss://ws.bitstamp.net").context("wrong bitstamp url")?;
 
     let (mut ws_stream_bitstamp, _) = connect_async(&ws_url_bitstamp)
         .await
         .context("Failed to connect to bitstamp wss endpoint")?;
 
-    // from binance https://github.com/binance/binance-spot-api-docs/blob/master/web-socket-streams.md
-    // it seems that taking a snapshot and applying the diff feed is the only way.
-    // maybe worth keeping it consistent across the 2 exchanges and do it in a similar way
-
     let subscribe_msg = serde_json::json!({
         "event": "bts:subscribe",
         "data": {
-            "channel": format!("diff_order_book_{}", symbol)
+            "channel": channel
         }
     });
     tracing::info!("sending bitstamp subscription message: {}", subscribe_msg);
@@ -89,270 +300,363 @@ pub async fn get_bitstamp_stream(
         .await
         .context("failed to subscribe to bitstap")?;
 
-    let (_, read_stream) = ws_stream_bitstamp.split();
-    Ok(read_stream)
+    Ok(ws_stream_bitstamp)
 }
 
-pub async fn get_all_streams(
-    symbol: &String,
-) -> Result<StreamMap<&'static str, SplitStream<WebSocketStream<MaybeTlsStream<TcpStream>>>>> {
-    let mut streams_map = StreamMap::new();
-
-    let binance_stream_read = get_binance_stream(symbol).await.unwrap();
-    streams_map.insert("BINANCE", binance_stream_read);
+pub async fn get_bitstamp_stream(symbol: &str) -> Result<ExchangeStream> {
+    // from binance https://github.com/binance/binance-spot-api-docs/blob/master/web-socket-streams.md
+    // it seems that taking a snapshot and applying the diff feed is the only way.
+    // maybe worth keeping it consistent across the 2 exchanges and do it in a similar way
+    get_bitstamp_channel_stream(&format!("diff_order_book_{}", symbol)).await
+}
 
-    let bitstamp_stream_read = get_bitstamp_stream(symbol).await.unwrap();
-    streams_map.insert("BITSTAMP", bitstamp_stream_read);
+// top 100 prices of each side, every message is a whole book and no snapshot is needed
+pub async fn get_bitstamp_order_book_stream(symbol: &str) -> Result<ExchangeStream> {
+    get_bitstamp_channel_stream(&format!("order_book_{}", symbol)).await
+}
 
-    tracing::info!("returning both streams for BINANCE and BITSTAMP");
+pub async fn get_bitstamp_trade_stream(symbol: &str) -> Result<ExchangeStream> {
+    get_bitstamp_channel_stream(&format!("live_trades_{}", symbol)).await
+}
 
-    Ok(streams_map)
+// works for both the @trade and the @aggTrade streams, only the id field differs
+pub fn binance_json_to_trade(value: &Value) -> Result<ParsedTrade> {
+    let trade_id = value["t"]
+        .as_u64()
+        .or_else(|| value["a"].as_u64())
+        .context("no trade id in binance trade")?;
+    let buyer_is_maker = value["m"]
+        .as_bool()
+        .context("binance trade maker flag failed as bool")?;
+    Ok(ParsedTrade {
+        exchange: ExchangeId::Binance,
+        trade_id,
+        price: value["p"]
+            .as_str()
+            .context("binance trade price failed as string")?
+            .parse::<f64>()
+            .context("binance trade price failed as float")?,
+        amount: value["q"]
+            .as_str()
+            .context("binance trade amount failed as string")?
+            .parse::<f64>()
+            .context("binance trade amount failed as float")?,
+        // the taker sold into a resting buy order when the buyer is the maker
+        side: if buyer_is_maker {
+            TradeSide::Sell
+        } else {
+            TradeSide::Buy
+        },
+        timestamp: value["T"]
+            .as_u64()
+            .context("binance trade time failed as u64")?
+            * 1000,
+    })
 }
 
-pub fn bitstamp_json_snapshot_to_levels(value: &Value) -> Result<ParsedUpdate> {
-    let mut vector_of_bids: Vec<Level> = Vec::with_capacity(
-        value["bids"]
-            .as_array()
-            .expect("failed to get bids capacity")
-            .len(),
-    );
-    let mut vector_of_asks: Vec<Level> = Vec::with_capacity(
-        value["asks"]
-            .as_array()
-            .expect("failed to get asks capacity")
-            .len(),
-    );
-    let last_update_id = value["microtimestamp"]
+// None for the events that are not trades (subscription confirmations, heartbeats...)
+pub fn bitstamp_message_to_trade(value: &Value) -> Result<Option<ParsedTrade>> {
+    let bitstamp_event = value["event"]
         .as_str()
-        .context("failed to parse microtimestamp as string")?
-        .parse::<u64>()
-        .context(" failed to parse from string to u64")?;
-
-    for bid in value["bids"]
-        .as_array()
-        .context("no array for bids in bitstamp message")?
+        .context("error in parsing bitstamp event to string")?;
+    if bitstamp_event != "trade" {
+        tracing::info!(
+            "received bitstamp {} event with no trade data, continue",
+            bitstamp_event
+        );
+        return Ok(None);
+    }
+    let data = &value["data"];
+    // type is 0 for buys and 1 for sells
+    let side = match data["type"]
+        .as_u64()
+        .context("bitstamp trade type failed as u64")?
     {
-        let level = Level {
-            price: bid[0]
-                .as_str()
-                .context("bitstamp bid price failed as string")?
-                .parse::<f64>()
-                .context("bitstamp bid price failed as float")?,
-            amount: bid[1]
-                .as_str()
-                .context("bitstamp bid amount failed as string")?
-                .parse::<f64>()
-                .context("bitstamp bid amount failed as float")?,
-            exchange: "BITSTAMP".to_string(),
-        };
-        vector_of_bids.insert(0, level);
+        0 => TradeSide::Buy,
+        1 => TradeSide::Sell,
+        trade_type => return Err(anyhow!("unknown bitstamp trade type {}", trade_type)),
+    };
+    Ok(Some(ParsedTrade {
+        exchange: ExchangeId::Bitstamp,
+        trade_id: data["id"]
+            .as_u64()
+            .context("bitstamp trade id failed as u64")?,
+        price: data["price_str"]
+            .as_str()
+            .context("bitstamp trade price failed as string")?
+            .parse::<f64>()
+            .context("bitstamp trade price failed as float")?,
+        amount: data["amount_str"]
+            .as_str()
+            .context("bitstamp trade amount failed as string")?
+            .parse::<f64>()
+            .context("bitstamp trade amount failed as float")?,
+        side,
+        timestamp: data["microtimestamp"]
+            .as_str()
+            .context("bitstamp trade microtimestamp failed as string")?
+            .parse::<u64>()
+            .context("bitstamp trade microtimestamp failed as u64")?,
+    }))
+}
+
+// one websocket frame of the depth streams, None for the frames without book data
+// (pings, pongs and the bitstamp events other than data)
+pub fn depth_message_to_update(
+    exchange: ExchangeId,
+    message: &Message,
+) -> Result<Option<ParsedUpdate>> {
+    let text = match message {
+        Message::Text(text) => text,
+        Message::Ping(_) | Message::Pong(_) => return Ok(None),
+        _ => {
+            return Err(anyhow!(
+                "unexpected {} depth message: {}",
+                exchange,
+                message
+            ))
+        }
+    };
+    match exchange {
+        ExchangeId::Binance => parse_levels(text, &BINANCE_DIFF).map(Some),
+        ExchangeId::Bitstamp => parse_bitstamp_message(text),
     }
+}
 
-    for ask in value["asks"]
-        .as_array()
-        .context("no array for asks in bitsamp message")?
-    {
-        let level = Level {
-            price: ask[0]
-                .as_str()
-                .context("bitstamp ask price failed as string")?
-                .parse::<f64>()
-                .context("bitstamp ask price failed as float")?,
-            amount: ask[1]
-                .as_str()
-                .context("bitstampask amount failed as string")?
-                .parse::<f64>()
-                .context("bitstamp ask amount failed as float")?,
-            exchange: "BITSTAMP".to_string(),
-        };
-        vector_of_asks.insert(0, level);
+// same as above for the trade streams
+pub fn trade_message_to_trade(
+    exchange: ExchangeId,
+    message: &Message,
+) -> Result<Option<ParsedTrade>> {
+    let text = match message {
+        Message::Text(text) => text,
+        Message::Ping(_) | Message::Pong(_) => return Ok(None),
+        _ => {
+            return Err(anyhow!(
+                "unexpected {} trade message: {}",
+                exchange,
+                message
+            ))
+        }
+    };
+    let value: Value = serde_json::from_str(text).context("trade message is not json")?;
+    match exchange {
+        ExchangeId::Binance => binance_json_to_trade(&value).map(Some),
+        ExchangeId::Bitstamp => bitstamp_message_to_trade(&value),
     }
+}
 
-    Ok(ParsedUpdate {
-        bids: vector_of_bids,
-        asks: vector_of_asks,
-        last_update_id,
-    })
+// bitstamp sends bts:request_reconnect before a maintenance, the socket has to be
+// replaced by a new one soon
+pub fn is_reconnect_request(exchange: ExchangeId, message: &Message) -> bool {
+    let Message::Text(text) = message else {
+        return false;
+    };
+    // most messages are book data, only those mentioning the event are parsed
+    exchange == ExchangeId::Bitstamp
+        && text.contains("bts:request_reconnect")
+        && serde_json::from_str::<Value>(text)
+            .is_ok_and(|value| value["event"] == "bts:request_reconnect")
 }
 
-pub fn bitstamp_json_to_levels(value: &Value) -> Result<ParsedUpdate> {
-    let mut vector_of_bids: Vec<Level> = Vec::with_capacity(
-        value["data"]["bids"]
-            .as_array()
-            .expect("failed to get bids capacity")
-            .len(),
-    );
-    let mut vector_of_asks: Vec<Level> = Vec::with_capacity(
-        value["data"]["asks"]
-            .as_array()
-            .expect("failed to get asks capacity")
-            .len(),
-    );
-    let last_update_id = value["data"]["microtimestamp"]
-        .as_str()
-        .context("failed to parse microtimestamp as string")?
-        .parse::<u64>()
-        .context(" failed to parse from string to u64")?;
+// local receive time, used where the exchanges do not give a comparable timestamp
+pub fn now_micros() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_micros() as u64)
+        .unwrap_or(0)
+}
 
-    for bid in value["data"]["bids"]
-        .as_array()
-        .context("no array for bids in bitstamp message")?
-    {
-        let level = Level {
-            price: bid[0]
-                .as_str()
-                .context("bitstamp bid price failed as string")?
-                .parse::<f64>()
-                .context("bitstamp bid price failed as float")?,
-            amount: bid[1]
-                .as_str()
-                .context("bitstamp bid amount failed as string")?
-                .parse::<f64>()
-                .context("bitstamp bid amount failed as float")?,
-            exchange: "BITSTAMP".to_string(),
-        };
-        vector_of_bids.insert(0, level);
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_binance_diff_without_changes() {
+        let message =
+            r#"{"e":"depthUpdate","E":1687000000123,"s":"ETHBTC","U":6813,"u":6813,"b":[],"a":[]}"#;
+        let parsed_update = parse_levels(message, &BINANCE_DIFF).unwrap();
+        assert_eq!(parsed_update.exchange, ExchangeId::Binance);
+        assert_eq!(parsed_update.kind, UpdateKind::Diff);
+        assert_eq!(parsed_update.last_update_id, 6813);
+        assert!(parsed_update.bids.is_empty());
+        assert!(parsed_update.asks.is_empty());
     }
 
-    for ask in value["data"]["asks"]
-        .as_array()
-        .context("no array for asks in bitsamp message")?
-    {
-        let level = Level {
-            price: ask[0]
-                .as_str()
-                .context("bitstamp ask price failed as string")?
-                .parse::<f64>()
-                .context("bitstamp ask price failed as float")?,
-            amount: ask[1]
-                .as_str()
-                .context("bitstampask amount failed as string")?
-                .parse::<f64>()
-                .context("bitstamp ask amount failed as float")?,
-            exchange: "BITSTAMP".to_string(),
-        };
-        vector_of_asks.insert(0, level);
+    #[test]
+    fn parses_binance_diff_with_one_side_only() {
+        let message = r#"{"e":"depthUpdate","E":1687000000123,"s":"ETHBTC","U":6813,"u":6814,"b":[],"a":[["0.06400000","0.00000000"]]}"#;
+        let parsed_update = parse_levels(message, &BINANCE_DIFF).unwrap();
+        assert!(parsed_update.bids.is_empty());
+        assert_eq!(
+            parsed_update.asks,
+            vec![ParsedLevel {
+                price: 0.064,
+                amount: 0.0
+            }]
+        );
     }
 
-    Ok(ParsedUpdate {
-        bids: vector_of_bids,
-        asks: vector_of_asks,
-        last_update_id,
-    })
-}
+    #[test]
+    fn parses_binance_snapshot_as_snapshot() {
+        let message = r#"{"lastUpdateId":6812,"bids":[["0.06390000","1.5"]],"asks":[]}"#;
+        let parsed_update = parse_levels(message, &BINANCE_SNAPSHOT).unwrap();
+        assert_eq!(parsed_update.kind, UpdateKind::Snapshot);
+        assert_eq!(parsed_update.last_update_id, 6812);
+        assert_eq!(parsed_update.bids.len(), 1);
+        assert!(parsed_update.asks.is_empty());
+    }
 
-pub fn binance_json_to_levels(value: Value) -> Result<ParsedUpdate> {
-    let mut vector_of_bids: Vec<Level> =
-        Vec::with_capacity(value["bids"].as_array().unwrap().len());
-    let mut vector_of_asks: Vec<Level> =
-        Vec::with_capacity(value["asks"].as_array().unwrap().len());
-    let last_update_id = value["lastUpdateId"].as_u64().unwrap();
+    #[test]
+    fn parses_bitstamp_data_without_changes() {
+        let message = r#"{"data":{"timestamp":"1687000000","microtimestamp":"1687000000123456","bids":[],"asks":[]},"channel":"diff_order_book_ethbtc","event":"data"}"#;
+        let parsed_update = parse_bitstamp_message(message).unwrap().unwrap();
+        assert_eq!(parsed_update.exchange, ExchangeId::Bitstamp);
+        assert_eq!(parsed_update.kind, UpdateKind::Diff);
+        assert_eq!(parsed_update.last_update_id, 1687000000123456);
+        assert!(parsed_update.bids.is_empty());
+        assert!(parsed_update.asks.is_empty());
+    }
 
-    for bid in value["bids"]
-        .as_array()
-        .context("no array for bids in binance message")?
-    {
-        let level = Level {
-            price: bid[0]
-                .as_str()
-                .context("binance bid price failed as string")?
-                .parse::<f64>()
-                .context("binance bid price failed as float")?,
-            amount: bid[1]
-                .as_str()
-                .context("binance bid amount failed as string")?
-                .parse::<f64>()
-                .context("binance bid amount failed as float")?,
-            exchange: "BINANCE".to_string(),
-        };
-        vector_of_bids.insert(0, level);
+    #[test]
+    fn parses_bitstamp_order_book_channel_as_snapshot() {
+        let message = r#"{"data":{"timestamp":"1687000000","microtimestamp":"1687000000123456","bids":[["0.06390000","1.5"]],"asks":[["0.06400000","2"]]},"channel":"order_book_ethbtc","event":"data"}"#;
+        let parsed_update = parse_bitstamp_message(message).unwrap().unwrap();
+        assert_eq!(parsed_update.kind, UpdateKind::Snapshot);
+        assert_eq!(parsed_update.last_update_id, 1687000000123456);
+        assert_eq!(parsed_update.bids.len(), 1);
+        assert_eq!(parsed_update.asks.len(), 1);
     }
 
-    for ask in value["asks"]
-        .as_array()
-        .context("no array for asks in binance message")?
-    {
-        let level = Level {
-            price: ask[0]
-                .as_str()
-                .context("binance ask price failed as string")?
-                .parse::<f64>()
-                .context("binance ask price failed as float")?,
-            amount: ask[1]
-                .as_str()
-                .context("binance ask amount failed as string")?
-                .parse::<f64>()
-                .context("binance ask amount failed as float")?,
-            exchange: "BINANCE".to_string(),
-        };
-        vector_of_asks.insert(0, level);
+    #[test]
+    fn skips_bitstamp_events_without_book_data() {
+        for message in [
+            r#"{"event":"bts:subscription_succeeded","channel":"diff_order_book_ethbtc","data":{}}"#,
+            r#"{"event":"bts:heartbeat","channel":"","data":{"status":"success"}}"#,
+            r#"{"event":"bts:request_reconnect","channel":"","data":""}"#,
+        ] {
+            assert!(parse_bitstamp_message(message).unwrap().is_none());
+        }
     }
 
-    Ok(ParsedUpdate {
-        bids: vector_of_bids,
-        asks: vector_of_asks,
-        last_update_id,
-    })
-}
+    #[test]
+    fn malformed_messages_are_errors() {
+        let message = r#"{"e":"depthUpdate","E":1687000000123,"b":[],"a":[]}"#;
+        assert!(parse_levels(message, &BINANCE_DIFF).is_err());
+        assert!(parse_bitstamp_message(r#"{"event":"data","data":{}}"#).is_err());
+        assert!(parse_bitstamp_message(r#"{"data":{}}"#).is_err());
+    }
 
-pub fn binance_diff_json_to_levels(value: Value) -> Result<ParsedUpdate> {
-    let mut vector_of_bids: Vec<Level> = Vec::with_capacity(
-        value["b"]
-            .as_array()
-            .expect("no bids in binance update")
-            .len(),
-    );
-    let mut vector_of_asks: Vec<Level> = Vec::with_capacity(
-        value["a"]
-            .as_array()
-            .expect("no asks in binance update")
-            .len(),
-    );
-    let last_update_id = value["E"].as_u64().unwrap();
+    #[test]
+    fn parses_binance_trades() {
+        let message: Value = serde_json::from_str(
+            r#"{"e":"trade","E":1687000000124,"s":"ETHBTC","t":12345,"p":"0.06400000","q":"1.25000000","b":88,"a":50,"T":1687000000123,"m":true,"M":true}"#,
+        )
+        .unwrap();
+        let trade = binance_json_to_trade(&message).unwrap();
+        assert_eq!(
+            trade,
+            ParsedTrade {
+                exchange: ExchangeId::Binance,
+                trade_id: 12345,
+                price: 0.064,
+                amount: 1.25,
+                side: TradeSide::Sell,
+                timestamp: 1687000000123000,
+            }
+        );
+
+        let message: Value = serde_json::from_str(
+            r#"{"e":"aggTrade","E":1687000000124,"s":"ETHBTC","a":777,"p":"0.06400000","q":"2.00000000","f":100,"l":105,"T":1687000000123,"m":false,"M":true}"#,
+        )
+        .unwrap();
+        let trade = binance_json_to_trade(&message).unwrap();
+        assert_eq!(trade.trade_id, 777);
+        assert_eq!(trade.side, TradeSide::Buy);
+    }
 
-    for bid in value["b"]
-        .as_array()
-        .context("no array for bids in binance message")?
-    {
-        let level = Level {
-            price: bid[0]
-                .as_str()
-                .context("binance bid price failed as string")?
-                .parse::<f64>()
-                .context("binance bid price failed as float")?,
-            amount: bid[1]
-                .as_str()
-                .context("binance bid amount failed as string")?
-                .parse::<f64>()
-                .context("binance bid amount failed as float")?,
-            exchange: "BINANCE".to_string(),
-        };
-        vector_of_bids.insert(0, level);
+    #[test]
+    fn parses_bitstamp_trades() {
+        let message: Value = serde_json::from_str(
+            r#"{"data":{"id":289364410,"timestamp":"1687000000","amount":0.5,"amount_str":"0.50000000","price":26500,"price_str":"26500","type":1,"microtimestamp":"1687000000123456","buy_order_id":1,"sell_order_id":2},"channel":"live_trades_btcusd","event":"trade"}"#,
+        )
+        .unwrap();
+        let trade = bitstamp_message_to_trade(&message).unwrap().unwrap();
+        assert_eq!(
+            trade,
+            ParsedTrade {
+                exchange: ExchangeId::Bitstamp,
+                trade_id: 289364410,
+                price: 26500.0,
+                amount: 0.5,
+                side: TradeSide::Sell,
+                timestamp: 1687000000123456,
+            }
+        );
+
+        let message: Value = serde_json::from_str(
+            r#"{"event":"bts:subscription_succeeded","channel":"live_trades_btcusd","data":{}}"#,
+        )
+        .unwrap();
+        assert!(bitstamp_message_to_trade(&message).unwrap().is_none());
     }
 
-    for ask in value["a"]
-        .as_array()
-        .context("no array for asks in binance message")?
-    {
-        let level = Level {
-            price: ask[0]
-                .as_str()
-                .context("binance ask price failed as string")?
-                .parse::<f64>()
-                .context("binance ask price failed as float")?,
-            amount: ask[1]
-                .as_str()
-                .context("binance ask amount failed as string")?
-                .parse::<f64>()
-                .context("binance ask amount failed as float")?,
-            exchange: "BINANCE".to_string(),
-        };
-        vector_of_asks.insert(0, level);
+    #[test]
+    fn parses_binance_depth_settings() {
+        let depth: BinanceDepth = "1000:5000".parse().unwrap();
+        assert_eq!(depth, BinanceDepth::new(1000, 5000).unwrap());
+        assert_eq!(depth.snapshot_weight(), 250);
+        assert_eq!(
+            "100ms:100"
+                .parse::<BinanceDepth>()
+                .unwrap()
+                .snapshot_weight(),
+            5
+        );
+        assert_eq!(binance_depth_stream_name("ethbtc", 1000), "ethbtc@depth");
+        assert_eq!(
+            binance_depth_stream_name("ethbtc", 100),
+            "ethbtc@depth@100ms"
+        );
+        for depth in ["250:1000", "100:0", "100:5001", "100", "fast:1000"] {
+            assert!(depth.parse::<BinanceDepth>().is_err(), "{}", depth);
+        }
     }
 
-    Ok(ParsedUpdate {
-        bids: vector_of_bids,
-        asks: vector_of_asks,
-        last_update_id,
-    })
+    #[test]
+    fn detects_bitstamp_reconnect_requests() {
+        let request = Message::Text(
+            r#"{"event":"bts:request_reconnect","channel":"","data":""}"#.to_string(),
+        );
+        assert!(is_reconnect_request(ExchangeId::Bitstamp, &request));
+        assert!(!is_reconnect_request(ExchangeId::Binance, &request));
+        let heartbeat = Message::Text(
+            r#"{"event":"bts:heartbeat","channel":"","data":{"status":"success"}}"#.to_string(),
+        );
+        assert!(!is_reconnect_request(ExchangeId::Bitstamp, &heartbeat));
+        assert!(!is_reconnect_request(
+            ExchangeId::Bitstamp,
+            &Message::Close(None)
+        ));
+    }
+
+    #[test]
+    fn skips_control_frames_and_rejects_close() {
+        let ping = Message::Ping(vec![1]);
+        assert!(depth_message_to_update(ExchangeId::Binance, &ping)
+            .unwrap()
+            .is_none());
+        assert!(trade_message_to_trade(ExchangeId::Bitstamp, &ping)
+            .unwrap()
+            .is_none());
+        assert!(depth_message_to_update(ExchangeId::Bitstamp, &Message::Close(None)).is_err());
+
+        let subscribed = Message::Text(
+            r#"{"event":"bts:subscription_succeeded","channel":"live_trades_btcusd","data":{}}"#
+                .to_string(),
+        );
+        assert!(trade_message_to_trade(ExchangeId::Bitstamp, &subscribed)
+            .unwrap()
+            .is_none());
+    }
 }
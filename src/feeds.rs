@@ -0,0 +1,886 @@
+use crate::combined::{BinanceCombined, CombinedStream};
+use crate::exchanges::{
+    binance_depth_stream_name, binance_trade_stream_name, depth_message_to_update,
+    get_aligned_bitstamp_snapshot, get_binance_snapshot, get_binance_stream,
+    get_binance_trade_stream, get_bitstamp_order_book_stream, get_bitstamp_stream,
+    get_bitstamp_trade_stream, is_reconnect_request, BinanceDepth, ExchangeId, ExchangeStream,
+    ParsedUpdate, BINANCE_CONNECTION_LIFETIME,
+};
+use crate::metrics::METRICS;
+use anyhow::{anyhow, Context, Result};
+use futures::{SinkExt, Stream, StreamExt};
+use std::collections::{HashMap, VecDeque};
+use std::pin::Pin;
+use std::task::Poll;
+use std::time::Duration;
+use tokio::task::JoinHandle;
+use tokio::time::{self, Instant, Interval, MissedTickBehavior};
+use tokio_stream::StreamMap;
+use tokio_tungstenite::tungstenite::{self, Message};
+
+// wait before trying again when the new socket of a planned reconnect fails to open
+const ROTATION_RETRY: Duration = Duration::from_secs(60);
+
+// what the sockets of a feed carry, needed to open them again
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FeedKind {
+    Depth,
+    Trades { binance_aggregated: bool },
+}
+
+// how the feeds of the server connect, shared by all of them
+#[derive(Clone)]
+pub struct FeedConfig {
+    // nothing received for that long (heartbeat replies included) and a socket is opened again
+    pub inactivity_timeout: Duration,
+    // the binance streams of every symbol on one shared socket, a socket per stream without it
+    pub binance_combined: Option<BinanceCombined>,
+    // for the symbols without their own settings in binance_depth_symbols
+    pub binance_depth: BinanceDepth,
+    pub binance_depth_symbols: HashMap<String, BinanceDepth>,
+    // bitstamp books from the order_book channel (top 100, a whole book per message)
+    // instead of diff_order_book and REST snapshots
+    pub bitstamp_order_book: bool,
+}
+
+impl FeedConfig {
+    pub fn binance_depth(&self, symbol: &str) -> BinanceDepth {
+        self.binance_depth_symbols
+            .get(symbol)
+            .copied()
+            .unwrap_or(self.binance_depth)
+    }
+}
+
+// a websocket of the feed's own, or its subscription on the shared binance socket
+pub enum FeedSocket {
+    Own(Box<ExchangeStream>),
+    Combined(CombinedStream),
+}
+
+impl Stream for FeedSocket {
+    type Item = tungstenite::Result<Message>;
+
+    fn poll_next(
+        self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> Poll<Option<Self::Item>> {
+        match self.get_mut() {
+            FeedSocket::Own(stream) => stream.poll_next_unpin(cx),
+            FeedSocket::Combined(stream) => stream.poll_next_unpin(cx),
+        }
+    }
+}
+
+impl FeedSocket {
+    // sends the message if any and flushes whatever tungstenite has queued. The shared
+    // socket answers its pings and sends its heartbeats itself
+    async fn send(&mut self, message: Option<Message>) -> Result<()> {
+        let FeedSocket::Own(stream) = self else {
+            return Ok(());
+        };
+        match message {
+            Some(message) => stream.send(message).await,
+            None => stream.flush().await,
+        }
+        .context("websocket send failed")
+    }
+
+    // a subscription is only dropped, the shared socket stays open for the others
+    async fn close(&mut self, exchange: ExchangeId) {
+        if let FeedSocket::Own(stream) = self {
+            if let Err(err) = stream.as_mut().close(None).await {
+                tracing::warn!("failed to close {} websocket: {}", exchange, err);
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum FeedEvent {
+    // a text or binary frame of one exchange, control frames are handled here
+    Message(ExchangeId, Message),
+    // the socket of the exchange went quiet, failed or was closed and had to be opened
+    // again, depth feeds take a new snapshot since the diffs in between are lost
+    Reconnected(ExchangeId),
+}
+
+// the websockets of one symbol, answering pings and sending heartbeats on all of them.
+// A socket with nothing received for `inactivity_timeout` (heartbeat replies included)
+// is considered dead and opened again.
+// Planned reconnects (the binance 24 hours limit, bitstamp bts:request_reconnect) are made
+// before breaking: the new socket is opened while the old one keeps streaming, see Handover
+pub struct ExchangeFeeds {
+    symbol: String,
+    kind: FeedKind,
+    config: FeedConfig,
+    streams: StreamMap<ExchangeId, FeedSocket>,
+    last_received: HashMap<ExchangeId, Instant>,
+    heartbeat: Interval,
+    // when each socket has to be replaced by a planned reconnect
+    rotate_at: HashMap<ExchangeId, Instant>,
+    // the new socket being opened, one planned reconnect at a time
+    rotating: Option<(ExchangeId, JoinHandle<Result<FeedSocket>>)>,
+    // the new socket once opened, until it takes over
+    handover: Option<Handover>,
+    // events read ahead by first_update_id, handed out first by next()
+    pending: VecDeque<FeedEvent>,
+}
+
+// the new depth socket of a planned reconnect, read alongside the old one. Its messages
+// are held back until its first diff shows that nothing is lost between the two sockets:
+// the old one handed out an update at least as new as the one before that diff. The old
+// socket is then closed and the held back messages handed out, the updates received twice
+// are not newer than the last one applied and dropped by the book. When the overlap can't
+// be shown within `inactivity_timeout` the new socket takes over with a Reconnected event
+// and the book is taken again from a snapshot
+struct Handover {
+    exchange: ExchangeId,
+    socket: FeedSocket,
+    held_back: VecDeque<Message>,
+    // first update id the new socket streamed
+    first_update_id: Option<u64>,
+    // last update id handed out from the old socket since the new one opened
+    last_update_id: Option<u64>,
+    deadline: Instant,
+}
+
+impl Handover {
+    fn is_continuous(&self) -> bool {
+        let (Some(first_update_id), Some(last_update_id)) =
+            (self.first_update_id, self.last_update_id)
+        else {
+            return false;
+        };
+        match self.exchange {
+            // diffs are numbered, the next one starts right after the last
+            ExchangeId::Binance => first_update_id <= last_update_id + 1,
+            // microtimestamps, the same diff has to come through both sockets
+            ExchangeId::Bitstamp => first_update_id <= last_update_id,
+        }
+    }
+}
+
+enum Wake {
+    Received(Option<(ExchangeId, tungstenite::Result<Message>)>),
+    Heartbeat,
+    Silent(ExchangeId),
+    Rotate(ExchangeId),
+    Rotated(ExchangeId, Result<FeedSocket>),
+    HandedOver(Option<tungstenite::Result<Message>>),
+    HandoverTimeout,
+}
+
+async fn connect_stream(
+    kind: FeedKind,
+    symbol: &str,
+    exchange: ExchangeId,
+    config: &FeedConfig,
+) -> Result<FeedSocket> {
+    if let (ExchangeId::Binance, Some(binance_combined)) = (exchange, &config.binance_combined) {
+        let stream_name = match kind {
+            FeedKind::Depth => {
+                binance_depth_stream_name(symbol, config.binance_depth(symbol).update_speed_ms)
+            }
+            FeedKind::Trades { binance_aggregated } => {
+                binance_trade_stream_name(symbol, binance_aggregated)
+            }
+        };
+        return Ok(FeedSocket::Combined(
+            binance_combined.subscribe(&stream_name).await?,
+        ));
+    }
+    let stream = match (kind, exchange) {
+        (FeedKind::Depth, ExchangeId::Binance) => {
+            get_binance_stream(symbol, config.binance_depth(symbol).update_speed_ms).await
+        }
+        (FeedKind::Depth, ExchangeId::Bitstamp) if config.bitstamp_order_book => {
+            get_bitstamp_order_book_stream(symbol).await
+        }
+        (FeedKind::Depth, ExchangeId::Bitstamp) => get_bitstamp_stream(symbol).await,
+        (FeedKind::Trades { binance_aggregated }, ExchangeId::Binance) => {
+            get_binance_trade_stream(symbol, binance_aggregated).await
+        }
+        (FeedKind::Trades { .. }, ExchangeId::Bitstamp) => get_bitstamp_trade_stream(symbol).await,
+    }?;
+    Ok(FeedSocket::Own(Box::new(stream)))
+}
+
+// planned reconnect of a socket opened at `connected`, if the exchange needs one.
+// The shared binance socket is replaced on its own
+fn rotation_deadline(
+    exchange: ExchangeId,
+    socket: &FeedSocket,
+    connected: Instant,
+) -> Option<Instant> {
+    match (exchange, socket) {
+        (ExchangeId::Binance, FeedSocket::Own(_)) => Some(connected + BINANCE_CONNECTION_LIFETIME),
+        _ => None,
+    }
+}
+
+// sleeps until the earliest of the deadlines, forever without any
+async fn earliest(deadlines: &HashMap<ExchangeId, Instant>) -> ExchangeId {
+    match deadlines.iter().min_by_key(|(_, deadline)| **deadline) {
+        Some((exchange, deadline)) => {
+            time::sleep_until(*deadline).await;
+            *exchange
+        }
+        None => std::future::pending().await,
+    }
+}
+
+// binance only needs its pings answered, a ping of ours is answered with a pong all the same.
+// Bitstamp replies to bts:heartbeat with a bts:heartbeat event
+fn heartbeat_message(exchange: ExchangeId) -> Message {
+    match exchange {
+        ExchangeId::Binance => Message::Ping(Vec::new()),
+        ExchangeId::Bitstamp => {
+            Message::Text(serde_json::json!({"event": "bts:heartbeat"}).to_string())
+        }
+    }
+}
+
+impl ExchangeFeeds {
+    pub fn new(
+        symbol: &str,
+        kind: FeedKind,
+        streams: StreamMap<ExchangeId, FeedSocket>,
+        config: FeedConfig,
+    ) -> Self {
+        let now = Instant::now();
+        // a few heartbeats fit in the timeout, so one lost reply is not enough to reconnect
+        let heartbeat_period = config.inactivity_timeout / 3;
+        let mut heartbeat = time::interval_at(now + heartbeat_period, heartbeat_period);
+        heartbeat.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        ExchangeFeeds {
+            symbol: symbol.to_string(),
+            kind,
+            last_received: streams.keys().map(|exchange| (*exchange, now)).collect(),
+            rotate_at: streams
+                .iter()
+                .filter_map(|(exchange, socket)| {
+                    Some((*exchange, rotation_deadline(*exchange, socket, now)?))
+                })
+                .collect(),
+            streams,
+            config,
+            heartbeat,
+            rotating: None,
+            handover: None,
+            pending: VecDeque::new(),
+        }
+    }
+
+    // both exchanges, the depth sockets have to be opened before taking the snapshots
+    pub async fn connect(symbol: &str, kind: FeedKind, config: &FeedConfig) -> Result<Self> {
+        let mut streams = StreamMap::new();
+        for exchange in ExchangeId::ALL {
+            streams.insert(
+                exchange,
+                connect_stream(kind, symbol, exchange, config).await?,
+            );
+        }
+        tracing::info!("returning both streams for BINANCE and BITSTAMP");
+        Ok(ExchangeFeeds::new(symbol, kind, streams, config.clone()))
+    }
+
+    // None once every socket has ended, an error when a socket fails or can't be opened again
+    pub async fn next(&mut self) -> Option<Result<FeedEvent>> {
+        if let Some(event) = self.pending.pop_front() {
+            return Some(Ok(event));
+        }
+        loop {
+            let silent_at = self
+                .last_received
+                .iter()
+                .map(|(exchange, last_received)| {
+                    (*exchange, *last_received + self.config.inactivity_timeout)
+                })
+                .collect();
+            let ExchangeFeeds {
+                streams,
+                heartbeat,
+                rotate_at,
+                rotating,
+                handover,
+                ..
+            } = &mut *self;
+            let handover_deadline = handover.as_ref().map(|handover| handover.deadline);
+            let wake = tokio::select! {
+                received = streams.next() => Wake::Received(received),
+                _ = heartbeat.tick() => Wake::Heartbeat,
+                exchange = earliest(&silent_at) => Wake::Silent(exchange),
+                exchange = earliest(rotate_at), if rotating.is_none() && handover.is_none() => {
+                    Wake::Rotate(exchange)
+                }
+                received = async {
+                    match handover {
+                        Some(handover) => handover.socket.next().await,
+                        None => std::future::pending().await,
+                    }
+                } => Wake::HandedOver(received),
+                _ = async {
+                    match handover_deadline {
+                        Some(deadline) => time::sleep_until(deadline).await,
+                        None => std::future::pending().await,
+                    }
+                } => Wake::HandoverTimeout,
+                (exchange, connected) = async {
+                    match rotating {
+                        Some((exchange, connecting)) => (*exchange, connecting.await),
+                        None => std::future::pending().await,
+                    }
+                } => Wake::Rotated(
+                    exchange,
+                    connected.unwrap_or_else(|err| Err(err).context("connect task failed")),
+                ),
+            };
+
+            match wake {
+                Wake::Received(None) => return None,
+                Wake::Received(Some((exchange, received))) => {
+                    let message = match received {
+                        Ok(message) => message,
+                        Err(err) => {
+                            tracing::warn!("{} {} websocket error: {}", exchange, self.symbol, err);
+                            return Some(self.reconnect(exchange).await);
+                        }
+                    };
+                    self.last_received.insert(exchange, Instant::now());
+                    if is_reconnect_request(exchange, &message) {
+                        tracing::info!("{} {} asked for a reconnection", exchange, self.symbol);
+                        self.rotate_at.insert(exchange, Instant::now());
+                        continue;
+                    }
+                    match message {
+                        // tungstenite queues the pong with the same payload when it reads the
+                        // ping but only sends it on the next read or write, flushed right away here
+                        Message::Ping(_) => {
+                            if let Err(err) = self.send(exchange, None).await {
+                                tracing::warn!("failed to answer {} ping: {:#}", exchange, err);
+                                return Some(self.reconnect(exchange).await);
+                            }
+                        }
+                        Message::Pong(_) | Message::Frame(_) => {}
+                        Message::Close(frame) => {
+                            tracing::warn!(
+                                "{} {} websocket closed: {:?}",
+                                exchange,
+                                self.symbol,
+                                frame
+                            );
+                            return Some(self.reconnect(exchange).await);
+                        }
+                        message => {
+                            self.follow_handover(exchange, &message).await;
+                            return Some(Ok(FeedEvent::Message(exchange, message)));
+                        }
+                    }
+                }
+                Wake::Heartbeat => {
+                    for exchange in ExchangeId::ALL {
+                        if !self.streams.contains_key(&exchange) {
+                            continue;
+                        }
+                        if let Err(err) =
+                            self.send(exchange, Some(heartbeat_message(exchange))).await
+                        {
+                            tracing::warn!("failed to send {} heartbeat: {:#}", exchange, err);
+                            return Some(self.reconnect(exchange).await);
+                        }
+                    }
+                }
+                Wake::Silent(exchange) => {
+                    tracing::warn!(
+                        "nothing received from {} {} for {:?}",
+                        exchange,
+                        self.symbol,
+                        self.config.inactivity_timeout
+                    );
+                    return Some(self.reconnect(exchange).await);
+                }
+                Wake::Rotate(exchange) => {
+                    tracing::info!("opening a new {} {} websocket", exchange, self.symbol);
+                    self.rotate_at.remove(&exchange);
+                    let (kind, symbol, config) =
+                        (self.kind, self.symbol.clone(), self.config.clone());
+                    let connecting = tokio::spawn(async move {
+                        connect_stream(kind, &symbol, exchange, &config).await
+                    });
+                    self.rotating = Some((exchange, connecting));
+                }
+                Wake::Rotated(exchange, connected) => {
+                    self.rotating = None;
+                    match connected {
+                        Ok(stream) => self.start_handover(exchange, stream).await,
+                        Err(err) => {
+                            tracing::warn!(
+                                "planned {} {} reconnect failed, retrying in {:?}: {:#}",
+                                exchange,
+                                self.symbol,
+                                ROTATION_RETRY,
+                                err
+                            );
+                            self.rotate_at
+                                .insert(exchange, Instant::now() + ROTATION_RETRY);
+                        }
+                    }
+                }
+                Wake::HandedOver(received) => {
+                    if let Some(event) = self.hold_back(received).await {
+                        return Some(Ok(event));
+                    }
+                }
+                Wake::HandoverTimeout => {
+                    let Some(handover) = self.handover.take() else {
+                        continue;
+                    };
+                    tracing::warn!(
+                        "no overlap between the old and new {} {} websockets, resyncing",
+                        handover.exchange,
+                        self.symbol
+                    );
+                    let exchange = handover.exchange;
+                    self.take_over(handover).await;
+                    return Some(Ok(FeedEvent::Reconnected(exchange)));
+                }
+            }
+        }
+    }
+
+    // depth diffs need the overlap of a Handover, trades and whole books (the bitstamp
+    // order_book channel) can just be swapped
+    async fn start_handover(&mut self, exchange: ExchangeId, socket: FeedSocket) {
+        let whole_books = exchange == ExchangeId::Bitstamp && self.config.bitstamp_order_book;
+        if self.kind != FeedKind::Depth || whole_books {
+            self.swap(exchange, socket).await;
+            return;
+        }
+        self.handover = Some(Handover {
+            exchange,
+            socket,
+            held_back: VecDeque::new(),
+            first_update_id: None,
+            last_update_id: None,
+            deadline: Instant::now() + self.config.inactivity_timeout,
+        });
+    }
+
+    // a message of the new socket, the first diff tells from where it streams. The events
+    // to hand out when it takes over
+    async fn hold_back(
+        &mut self,
+        received: Option<tungstenite::Result<Message>>,
+    ) -> Option<FeedEvent> {
+        let handover = self.handover.as_mut()?;
+        let exchange = handover.exchange;
+        let message = match received {
+            Some(Ok(Message::Ping(_))) => {
+                if let Err(err) = handover.socket.send(None).await {
+                    tracing::warn!("failed to answer {} ping: {:#}", exchange, err);
+                }
+                return None;
+            }
+            Some(Ok(Message::Pong(_) | Message::Frame(_))) => return None,
+            Some(Ok(Message::Close(_))) | Some(Err(_)) | None => {
+                tracing::warn!(
+                    "new {} {} websocket failed, retrying in {:?}",
+                    exchange,
+                    self.symbol,
+                    ROTATION_RETRY
+                );
+                self.handover = None;
+                self.rotate_at
+                    .insert(exchange, Instant::now() + ROTATION_RETRY);
+                return None;
+            }
+            Some(Ok(message)) => message,
+        };
+        if handover.first_update_id.is_none() {
+            // parse errors are reported when the message is handed out
+            if let Ok(Some(update)) = depth_message_to_update(exchange, &message) {
+                handover.first_update_id =
+                    Some(update.first_update_id.unwrap_or(update.last_update_id));
+            }
+        }
+        handover.held_back.push_back(message);
+        if !handover.is_continuous() {
+            return None;
+        }
+        let handover = self.handover.take()?;
+        self.take_over(handover).await;
+        self.pending.pop_front()
+    }
+
+    // a message handed out from the old socket of a handover
+    async fn follow_handover(&mut self, exchange: ExchangeId, message: &Message) {
+        let Some(handover) = self.handover.as_mut() else {
+            return;
+        };
+        if handover.exchange != exchange {
+            return;
+        }
+        if let Ok(Some(update)) = depth_message_to_update(exchange, message) {
+            handover.last_update_id = Some(update.last_update_id);
+        }
+        if handover.is_continuous() {
+            let handover = self.handover.take().expect("checked above");
+            self.take_over(handover).await;
+        }
+    }
+
+    // the new socket replaces the old one, its held back messages are handed out next
+    async fn take_over(&mut self, handover: Handover) {
+        let Handover {
+            exchange,
+            socket,
+            held_back,
+            ..
+        } = handover;
+        self.swap(exchange, socket).await;
+        self.pending.extend(
+            held_back
+                .into_iter()
+                .map(|message| FeedEvent::Message(exchange, message)),
+        );
+    }
+
+    // reads ahead until the exchange streams a book update and returns its id, to take a
+    // snapshot at least as new. Everything read meanwhile is buffered and handed out again
+    // by next(), the diffs of the exchange older than the snapshot are then dropped by the
+    // book. A reconnection of the exchange in between is not, the snapshot follows anyway.
+    // None when the exchange sends no update for `wait` (a quiet book)
+    pub async fn first_update_id(
+        &mut self,
+        exchange: ExchangeId,
+        wait: Duration,
+    ) -> Result<Option<u64>> {
+        let deadline = Instant::now() + wait;
+        let mut buffered = VecDeque::new();
+        let first_update_id = loop {
+            let next = tokio::select! {
+                next = self.next() => Some(next),
+                _ = time::sleep_until(deadline) => None,
+            };
+            let event = match next {
+                Some(Some(event)) => event?,
+                Some(None) => return Err(anyhow!("{} {} feed ended", exchange, self.symbol)),
+                None => break None,
+            };
+            match &event {
+                FeedEvent::Reconnected(reconnected) if *reconnected == exchange => continue,
+                FeedEvent::Message(received, message) if *received == exchange => {
+                    // parse errors are reported when the message is handed out again
+                    if let Ok(Some(update)) = depth_message_to_update(exchange, message) {
+                        buffered.push_back(event);
+                        break Some(update.last_update_id);
+                    }
+                }
+                _ => {}
+            }
+            buffered.push_back(event);
+        };
+        // ahead of whatever was still pending from before
+        buffered.append(&mut self.pending);
+        self.pending = buffered;
+        Ok(first_update_id)
+    }
+
+    // the REST snapshot to start or resync the depth book of the exchange with, once its
+    // socket is open. None for the bitstamp order_book channel, its messages are whole books
+    pub async fn snapshot(&mut self, exchange: ExchangeId) -> Result<Option<ParsedUpdate>> {
+        match exchange {
+            ExchangeId::Binance => {
+                get_binance_snapshot(&self.symbol, self.config.binance_depth(&self.symbol))
+                    .await
+                    .map(Some)
+            }
+            ExchangeId::Bitstamp if self.config.bitstamp_order_book => Ok(None),
+            ExchangeId::Bitstamp => {
+                // nothing changed while waiting, any snapshot is new enough
+                let first_diff_id = self
+                    .first_update_id(exchange, self.config.inactivity_timeout)
+                    .await?
+                    .unwrap_or(0);
+                get_aligned_bitstamp_snapshot(&self.symbol, first_diff_id)
+                    .await
+                    .map(Some)
+            }
+        }
+    }
+
+    // puts a new socket of the exchange in place of the old one, closed afterwards
+    async fn swap(&mut self, exchange: ExchangeId, stream: FeedSocket) {
+        METRICS.count_reconnect(exchange);
+        let now = Instant::now();
+        self.last_received.insert(exchange, now);
+        if let Some(rotate_at) = rotation_deadline(exchange, &stream, now) {
+            self.rotate_at.insert(exchange, rotate_at);
+        }
+        if let Some(mut old_stream) = self.streams.insert(exchange, stream) {
+            old_stream.close(exchange).await;
+        }
+        tracing::info!("{} {} websocket replaced", exchange, self.symbol);
+    }
+
+    async fn send(&mut self, exchange: ExchangeId, message: Option<Message>) -> Result<()> {
+        match self
+            .streams
+            .iter_mut()
+            .find(|(stream_exchange, _)| *stream_exchange == exchange)
+        {
+            Some((_, stream)) => stream.send(message).await,
+            None => Ok(()),
+        }
+    }
+
+    // break before make, the old socket is dropped without a close handshake since it
+    // is not answering or already closed. The new socket of a handover is used if any
+    async fn reconnect(&mut self, exchange: ExchangeId) -> Result<FeedEvent> {
+        tracing::info!("reconnecting {} {} feed", exchange, self.symbol);
+        self.streams.remove(&exchange);
+        self.last_received.remove(&exchange);
+        self.rotate_at.remove(&exchange);
+        if let Some((rotating, connecting)) = &self.rotating {
+            if *rotating == exchange {
+                connecting.abort();
+                self.rotating = None;
+            }
+        }
+        if self
+            .handover
+            .as_ref()
+            .is_some_and(|handover| handover.exchange == exchange)
+        {
+            let handover = self.handover.take().expect("checked above");
+            self.take_over(handover).await;
+            return Ok(FeedEvent::Reconnected(exchange));
+        }
+        let stream = connect_stream(self.kind, &self.symbol, exchange, &self.config)
+            .await
+            .with_context(|| format!("failed to reconnect {} {}", exchange, self.symbol))?;
+        self.swap(exchange, stream).await;
+        Ok(FeedEvent::Reconnected(exchange))
+    }
+
+    pub async fn close(&mut self) {
+        if let Some((_, connecting)) = self.rotating.take() {
+            connecting.abort();
+        }
+        if let Some(mut handover) = self.handover.take() {
+            handover.socket.close(handover.exchange).await;
+        }
+        for (exchange, stream) in self.streams.iter_mut() {
+            stream.close(*exchange).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::levels::{parse_levels, BINANCE_DIFF};
+    use tokio::net::TcpListener;
+    use tokio_tungstenite::{accept_async, connect_async};
+
+    fn binance_diff(first_update_id: u64, last_update_id: u64) -> String {
+        format!(
+            r#"{{"e":"depthUpdate","E":1687000000123,"s":"ETHBTC","U":{},"u":{},"b":[],"a":[]}}"#,
+            first_update_id, last_update_id
+        )
+    }
+
+    // a binance socket sending the messages after their delays, the task ends with
+    // whether the feed closed it
+    async fn serve(messages: Vec<(u64, String)>) -> (FeedSocket, JoinHandle<bool>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let exchange = tokio::spawn(async move {
+            let (socket, _) = listener.accept().await.unwrap();
+            let mut socket = accept_async(socket).await.unwrap();
+            for (delay_ms, message) in messages {
+                time::sleep(Duration::from_millis(delay_ms)).await;
+                socket.send(Message::Text(message)).await.unwrap();
+            }
+            while let Some(Ok(message)) = socket.next().await {
+                if let Message::Close(_) = message {
+                    return true;
+                }
+            }
+            false
+        });
+        let (stream, _) = connect_async(format!("ws://{}", address)).await.unwrap();
+        (FeedSocket::Own(Box::new(stream)), exchange)
+    }
+
+    async fn next_event(feeds: &mut ExchangeFeeds) -> String {
+        match feeds.next().await.unwrap().unwrap() {
+            FeedEvent::Message(_, Message::Text(text)) => {
+                let update = parse_levels(&text, &BINANCE_DIFF).unwrap();
+                format!(
+                    "{}-{}",
+                    update.first_update_id.unwrap(),
+                    update.last_update_id
+                )
+            }
+            event => format!("{:?}", event),
+        }
+    }
+
+    async fn rotating_feeds(
+        old_messages: Vec<(u64, String)>,
+        new_messages: Vec<(u64, String)>,
+    ) -> (ExchangeFeeds, JoinHandle<bool>) {
+        let (old_socket, old_exchange) = serve(old_messages).await;
+        let mut streams = StreamMap::new();
+        streams.insert(ExchangeId::Binance, old_socket);
+        let config = FeedConfig {
+            inactivity_timeout: Duration::from_millis(500),
+            binance_combined: None,
+            binance_depth: BinanceDepth::default(),
+            binance_depth_symbols: HashMap::new(),
+            bitstamp_order_book: false,
+        };
+        let mut feeds = ExchangeFeeds::new("ethbtc", FeedKind::Depth, streams, config);
+        assert_eq!(next_event(&mut feeds).await, "1-10");
+        let (new_socket, _) = serve(new_messages).await;
+        feeds.start_handover(ExchangeId::Binance, new_socket).await;
+        (feeds, old_exchange)
+    }
+
+    #[tokio::test]
+    async fn hands_over_once_the_old_socket_caught_up() {
+        let (mut feeds, old_exchange) = rotating_feeds(
+            vec![(0, binance_diff(1, 10)), (100, binance_diff(11, 20))],
+            vec![(0, binance_diff(15, 25)), (0, binance_diff(26, 30))],
+        )
+        .await;
+        // the new socket is held back until the old one reaches its first diff
+        for expected in ["11-20", "15-25", "26-30"] {
+            assert_eq!(next_event(&mut feeds).await, expected);
+        }
+        assert!(old_exchange.await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn resyncs_when_the_sockets_do_not_overlap() {
+        let (mut feeds, old_exchange) = rotating_feeds(
+            vec![(0, binance_diff(1, 10))],
+            vec![(0, binance_diff(31, 40))],
+        )
+        .await;
+        // 11 to 30 came through neither socket
+        for expected in ["Reconnected(Binance)", "31-40"] {
+            assert_eq!(next_event(&mut feeds).await, expected);
+        }
+        assert!(old_exchange.await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn answers_pings_and_sends_heartbeats() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let exchange = tokio::spawn(async move {
+            let (socket, _) = listener.accept().await.unwrap();
+            let mut socket = accept_async(socket).await.unwrap();
+            socket.send(Message::Ping(vec![7])).await.unwrap();
+            socket
+                .send(Message::Text("{\"e\":\"depthUpdate\"}".to_string()))
+                .await
+                .unwrap();
+            let mut received = Vec::new();
+            while received.len() < 2 {
+                match socket.next().await.unwrap().unwrap() {
+                    Message::Pong(payload) => received.push(format!("pong {:?}", payload)),
+                    Message::Ping(_) => received.push("ping".to_string()),
+                    _ => {}
+                }
+            }
+            received
+        });
+
+        let (stream, _) = connect_async(format!("ws://{}", address)).await.unwrap();
+        let mut streams = StreamMap::new();
+        streams.insert(ExchangeId::Binance, FeedSocket::Own(Box::new(stream)));
+        let config = FeedConfig {
+            inactivity_timeout: Duration::from_millis(300),
+            binance_combined: None,
+            binance_depth: BinanceDepth::default(),
+            binance_depth_symbols: HashMap::new(),
+            bitstamp_order_book: false,
+        };
+        let mut feeds = ExchangeFeeds::new("ethbtc", FeedKind::Depth, streams, config);
+
+        // the ping is answered and not handed over
+        match feeds.next().await.unwrap().unwrap() {
+            FeedEvent::Message(ExchangeId::Binance, Message::Text(text)) => {
+                assert!(text.contains("depthUpdate"))
+            }
+            event => panic!("unexpected {:?}", event),
+        }
+        // keeps reading (and sending heartbeats) until the exchange side is done
+        let _ = time::timeout(Duration::from_millis(250), feeds.next()).await;
+        assert_eq!(exchange.await.unwrap(), vec!["pong [7]", "ping"]);
+    }
+
+    #[tokio::test]
+    async fn buffers_the_messages_read_before_the_first_update() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (socket, _) = listener.accept().await.unwrap();
+            let mut socket = accept_async(socket).await.unwrap();
+            for message in [
+                r#"{"event":"bts:subscription_succeeded","channel":"diff_order_book_ethbtc","data":{}}"#,
+                r#"{"data":{"microtimestamp":"1687000000000001","bids":[],"asks":[]},"channel":"diff_order_book_ethbtc","event":"data"}"#,
+                r#"{"data":{"microtimestamp":"1687000000000002","bids":[],"asks":[]},"channel":"diff_order_book_ethbtc","event":"data"}"#,
+            ] {
+                socket
+                    .send(Message::Text(message.to_string()))
+                    .await
+                    .unwrap();
+            }
+            // keeps the socket open
+            while socket.next().await.is_some() {}
+        });
+
+        let (stream, _) = connect_async(format!("ws://{}", address)).await.unwrap();
+        let mut streams = StreamMap::new();
+        streams.insert(ExchangeId::Bitstamp, FeedSocket::Own(Box::new(stream)));
+        let config = FeedConfig {
+            inactivity_timeout: Duration::from_secs(5),
+            binance_combined: None,
+            binance_depth: BinanceDepth::default(),
+            binance_depth_symbols: HashMap::new(),
+            bitstamp_order_book: false,
+        };
+        let mut feeds = ExchangeFeeds::new("ethbtc", FeedKind::Depth, streams, config);
+
+        let first_update_id = feeds
+            .first_update_id(ExchangeId::Bitstamp, Duration::from_secs(1))
+            .await
+            .unwrap();
+        assert_eq!(first_update_id, Some(1687000000000001));
+        // nothing is lost, the subscription event and the first diff come out first
+        let mut received = Vec::new();
+        for _ in 0..3 {
+            match feeds.next().await.unwrap().unwrap() {
+                FeedEvent::Message(ExchangeId::Bitstamp, Message::Text(text)) => {
+                    received.push(text)
+                }
+                event => panic!("unexpected {:?}", event),
+            }
+        }
+        assert!(received[0].contains("subscription_succeeded"));
+        assert!(received[1].contains("1687000000000001"));
+        assert!(received[2].contains("1687000000000002"));
+
+        // a quiet book gives up after the wait
+        let first_update_id = feeds
+            .first_update_id(ExchangeId::Bitstamp, Duration::from_millis(50))
+            .await
+            .unwrap();
+        assert_eq!(first_update_id, None);
+    }
+}
@@ -0,0 +1,188 @@
+use crate::exchanges::ExchangeId;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use tonic_health::server::HealthReporter;
+use tonic_health::ServingStatus;
+
+// grpc.health.v1 service name of one feed, e.g. "orderbookaggregator.OrderbookAggregator/btcusdt/BINANCE"
+pub fn feed_service_name(symbol: &str, exchange: ExchangeId) -> String {
+    format!(
+        "orderbookaggregator.OrderbookAggregator/{}/{}",
+        symbol, exchange
+    )
+}
+
+// the books of one symbol and how many of them are in sync with each exchange
+#[derive(Default)]
+struct SymbolFeeds {
+    books: usize,
+    in_sync: HashMap<ExchangeId, usize>,
+}
+
+// health of every (symbol, exchange) feed: SERVING while at least one book of the
+// symbol is in sync with that exchange, NOT_SERVING once none is. The statuses of a
+// symbol are removed with its last book
+#[derive(Clone)]
+pub struct FeedHealth {
+    reporter: HealthReporter,
+    symbols: Arc<Mutex<HashMap<String, SymbolFeeds>>>,
+    // status updates are published one at a time, each with the count of its
+    // moment, so the last published status is always the current one
+    publishing: Arc<tokio::sync::Mutex<()>>,
+}
+
+impl FeedHealth {
+    pub fn new(reporter: HealthReporter) -> Self {
+        FeedHealth {
+            reporter,
+            symbols: Arc::new(Mutex::new(HashMap::new())),
+            publishing: Arc::new(tokio::sync::Mutex::new(())),
+        }
+    }
+
+    // None once no book of the symbol runs anymore
+    fn status(&self, symbol: &str, exchange: ExchangeId) -> Option<bool> {
+        self.symbols
+            .lock()
+            .expect("feed health lock poisoned")
+            .get(symbol)
+            .map(|feeds| feeds.in_sync.get(&exchange).is_some_and(|books| *books > 0))
+    }
+
+    pub fn is_in_sync(&self, symbol: &str, exchange: ExchangeId) -> bool {
+        self.status(symbol, exchange).unwrap_or(false)
+    }
+
+    fn count(&self, symbol: &str, exchange: ExchangeId, in_sync: bool) {
+        let mut symbols = self.symbols.lock().expect("feed health lock poisoned");
+        let Some(feeds) = symbols.get_mut(symbol) else {
+            return;
+        };
+        let books = feeds.in_sync.entry(exchange).or_insert(0);
+        if in_sync {
+            *books += 1;
+        } else {
+            *books = books.saturating_sub(1);
+        }
+    }
+
+    async fn publish(&self, symbol: &str, exchange: ExchangeId) {
+        let _publishing = self.publishing.lock().await;
+        let mut reporter = self.reporter.clone();
+        let service_name = feed_service_name(symbol, exchange);
+        match self.status(symbol, exchange) {
+            Some(true) => {
+                reporter
+                    .set_service_status(service_name, ServingStatus::Serving)
+                    .await
+            }
+            Some(false) => {
+                reporter
+                    .set_service_status(service_name, ServingStatus::NotServing)
+                    .await
+            }
+            None => reporter.clear_service_status(&service_name).await,
+        }
+    }
+
+    // to be held by each book for as long as it runs
+    pub fn guard(&self, symbol: &str) -> FeedHealthGuard {
+        self.symbols
+            .lock()
+            .expect("feed health lock poisoned")
+            .entry(symbol.to_string())
+            .or_default()
+            .books += 1;
+        FeedHealthGuard {
+            health: self.clone(),
+            symbol: symbol.to_string(),
+            in_sync: Vec::new(),
+        }
+    }
+}
+
+// the feeds of one book, the ones still in sync are reported out of sync on drop
+pub struct FeedHealthGuard {
+    health: FeedHealth,
+    symbol: String,
+    in_sync: Vec<ExchangeId>,
+}
+
+impl FeedHealthGuard {
+    pub async fn set_in_sync(&mut self, exchange: ExchangeId, in_sync: bool) {
+        if self.in_sync.contains(&exchange) == in_sync {
+            return;
+        }
+        if in_sync {
+            self.in_sync.push(exchange);
+        } else {
+            self.in_sync.retain(|current| *current != exchange);
+        }
+        self.health.count(&self.symbol, exchange, in_sync);
+        self.health.publish(&self.symbol, exchange).await;
+    }
+}
+
+impl Drop for FeedHealthGuard {
+    fn drop(&mut self) {
+        let mut changed = std::mem::take(&mut self.in_sync);
+        for exchange in &changed {
+            self.health.count(&self.symbol, *exchange, false);
+        }
+        {
+            let mut symbols = self
+                .health
+                .symbols
+                .lock()
+                .expect("feed health lock poisoned");
+            if let Some(feeds) = symbols.get_mut(&self.symbol) {
+                feeds.books -= 1;
+                if feeds.books == 0 {
+                    symbols.remove(&self.symbol);
+                    changed = ExchangeId::ALL.to_vec();
+                }
+            }
+        }
+        // the status itself can only be published from the runtime
+        if let Ok(runtime) = tokio::runtime::Handle::try_current() {
+            let health = self.health.clone();
+            let symbol = self.symbol.clone();
+            runtime.spawn(async move {
+                for exchange in changed {
+                    health.publish(&symbol, exchange).await;
+                }
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn feed_stays_in_sync_while_one_book_is() {
+        let (reporter, _) = tonic_health::server::health_reporter();
+        let health = FeedHealth::new(reporter);
+
+        let mut first = health.guard("btcusdt");
+        let mut second = health.guard("btcusdt");
+        first.set_in_sync(ExchangeId::Binance, true).await;
+        second.set_in_sync(ExchangeId::Binance, true).await;
+        // setting it twice does not count the book twice
+        second.set_in_sync(ExchangeId::Binance, true).await;
+        assert!(health.is_in_sync("btcusdt", ExchangeId::Binance));
+        assert!(!health.is_in_sync("btcusdt", ExchangeId::Bitstamp));
+        assert!(!health.is_in_sync("ethbtc", ExchangeId::Binance));
+
+        drop(first);
+        assert!(health.is_in_sync("btcusdt", ExchangeId::Binance));
+        second.set_in_sync(ExchangeId::Binance, false).await;
+        assert!(!health.is_in_sync("btcusdt", ExchangeId::Binance));
+        assert!(health.symbols.lock().unwrap().contains_key("btcusdt"));
+        drop(second);
+        assert!(!health.is_in_sync("btcusdt", ExchangeId::Binance));
+        // nothing is kept for a symbol without books
+        assert!(!health.symbols.lock().unwrap().contains_key("btcusdt"));
+    }
+}
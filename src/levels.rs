@@ -0,0 +1,464 @@
+use crate::exchanges::{ExchangeId, ParsedLevel, ParsedUpdate, UpdateKind};
+use anyhow::{anyhow, Context, Result};
+use serde::de::{self, DeserializeSeed, Deserializer, IgnoredAny, MapAccess, SeqAccess, Visitor};
+use serde::Deserialize;
+use std::fmt;
+use std::marker::PhantomData;
+
+// where one message format keeps its levels and update id, every book message of the
+// exchanges is parsed by parse_levels with one of these
+pub struct LevelFormat {
+    pub exchange: ExchangeId,
+    pub kind: UpdateKind,
+    pub bids: &'static str,
+    pub asks: &'static str,
+    pub update_id: &'static str,
+    // first update id of a diff, for the exchanges numbering their diffs
+    pub first_update_id: Option<&'static str>,
+}
+
+pub const BINANCE_SNAPSHOT: LevelFormat = LevelFormat {
+    exchange: ExchangeId::Binance,
+    kind: UpdateKind::Snapshot,
+    bids: "bids",
+    asks: "asks",
+    update_id: "lastUpdateId",
+    first_update_id: None,
+};
+
+// "u" is the final update id of the event, the same sequence as the snapshot
+// lastUpdateId, so diffs already included in a snapshot get dropped by the book
+// (the event time "E" was used before but it is not comparable with it).
+// "U" is the first one, a diff not starting right after the previous one means lost diffs
+pub const BINANCE_DIFF: LevelFormat = LevelFormat {
+    exchange: ExchangeId::Binance,
+    kind: UpdateKind::Diff,
+    bids: "b",
+    asks: "a",
+    update_id: "u",
+    first_update_id: Some("U"),
+};
+
+pub const BITSTAMP_SNAPSHOT: LevelFormat = LevelFormat {
+    exchange: ExchangeId::Bitstamp,
+    kind: UpdateKind::Snapshot,
+    bids: "bids",
+    asks: "asks",
+    update_id: "microtimestamp",
+    first_update_id: None,
+};
+
+// the data of the websocket messages, see parse_bitstamp_message
+pub const BITSTAMP_DIFF: LevelFormat = LevelFormat {
+    kind: UpdateKind::Diff,
+    ..BITSTAMP_SNAPSHOT
+};
+
+// the order_book channel sends the whole top of the book each time
+pub const BITSTAMP_ORDER_BOOK: LevelFormat = BITSTAMP_SNAPSHOT;
+
+pub fn parse_levels(json: &str, format: &LevelFormat) -> Result<ParsedUpdate> {
+    deserialize(json, format)
+        .with_context(|| format!("invalid {} {:?} message", format.exchange, format.kind))
+}
+
+// the websocket also sends events without book data (subscription confirmations,
+// heartbeats, reconnect requests), for those there is nothing to merge
+pub fn parse_bitstamp_message(json: &str) -> Result<Option<ParsedUpdate>> {
+    let message: BitstampMessage =
+        deserialize(json, PhantomData).context("invalid bitstamp message")?;
+    match message.event {
+        Some(BitstampEvent::Data) => {}
+        Some(BitstampEvent::Other(event)) => {
+            tracing::info!(
+                "received bitstamp {} event with no book data, continue",
+                event
+            );
+            return Ok(None);
+        }
+        None => return Err(anyhow!("bitstamp message without event")),
+    }
+    let format = match message.order_book {
+        true => &BITSTAMP_ORDER_BOOK,
+        false => &BITSTAMP_DIFF,
+    };
+    message
+        .data
+        .complete(format)
+        .map(Some)
+        .map_err(|field| anyhow!("bitstamp data without {}", field))
+}
+
+// the whole message through the seed, nothing of it is borrowed past the call
+fn deserialize<T>(json: &str, seed: impl for<'de> DeserializeSeed<'de, Value = T>) -> Result<T> {
+    let mut deserializer = serde_json::Deserializer::from_str(json);
+    let value = seed.deserialize(&mut deserializer)?;
+    deserializer.end()?;
+    Ok(value)
+}
+
+// the fields of a format found in a message so far
+#[derive(Default)]
+struct FoundLevels {
+    bids: Option<Vec<ParsedLevel>>,
+    asks: Option<Vec<ParsedLevel>>,
+    update_id: Option<u64>,
+    first_update_id: Option<u64>,
+}
+
+impl FoundLevels {
+    fn visit<'de, A: MapAccess<'de>>(format: &LevelFormat, mut map: A) -> Result<Self, A::Error> {
+        let mut found = FoundLevels::default();
+        while let Some(key) = map.next_key::<&str>()? {
+            if key == format.bids {
+                found.bids = Some(map.next_value::<Levels>()?.0);
+            } else if key == format.asks {
+                found.asks = Some(map.next_value::<Levels>()?.0);
+            } else if key == format.update_id {
+                found.update_id = Some(map.next_value::<UpdateId>()?.0);
+            } else if Some(key) == format.first_update_id {
+                found.first_update_id = Some(map.next_value::<UpdateId>()?.0);
+            } else {
+                map.next_value::<IgnoredAny>()?;
+            }
+        }
+        Ok(found)
+    }
+
+    // the name of the first missing field otherwise
+    fn complete(self, format: &LevelFormat) -> Result<ParsedUpdate, &'static str> {
+        Ok(ParsedUpdate {
+            exchange: format.exchange,
+            kind: format.kind,
+            bids: self.bids.ok_or(format.bids)?,
+            asks: self.asks.ok_or(format.asks)?,
+            last_update_id: self.update_id.ok_or(format.update_id)?,
+            first_update_id: match format.first_update_id {
+                Some(field) => Some(self.first_update_id.ok_or(field)?),
+                None => None,
+            },
+        })
+    }
+}
+
+// the format is the seed, the fields are looked up by the names it gives
+impl<'de> DeserializeSeed<'de> for &LevelFormat {
+    type Value = ParsedUpdate;
+
+    fn deserialize<D: Deserializer<'de>>(self, deserializer: D) -> Result<ParsedUpdate, D::Error> {
+        deserializer.deserialize_map(self)
+    }
+}
+
+impl<'de> Visitor<'de> for &LevelFormat {
+    type Value = ParsedUpdate;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "a {} {:?} object", self.exchange, self.kind)
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, map: A) -> Result<ParsedUpdate, A::Error> {
+        FoundLevels::visit(self, map)?
+            .complete(self)
+            .map_err(de::Error::missing_field)
+    }
+}
+
+enum BitstampEvent {
+    Data,
+    // only kept to be logged
+    Other(String),
+}
+
+// the fields come in any order, data usually first, so the levels are looked up before
+// knowing the event and the channel. Other events have a data without levels
+struct BitstampMessage {
+    event: Option<BitstampEvent>,
+    order_book: bool,
+    data: FoundLevels,
+}
+
+impl<'de> Deserialize<'de> for BitstampMessage {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserializer.deserialize_map(BitstampMessageVisitor)
+    }
+}
+
+struct BitstampMessageVisitor;
+
+impl<'de> Visitor<'de> for BitstampMessageVisitor {
+    type Value = BitstampMessage;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("a bitstamp websocket message")
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<BitstampMessage, A::Error> {
+        let mut message = BitstampMessage {
+            event: None,
+            order_book: false,
+            data: FoundLevels::default(),
+        };
+        while let Some(key) = map.next_key::<&str>()? {
+            match key {
+                "event" => {
+                    message.event = Some(match map.next_value::<&str>()? {
+                        "data" => BitstampEvent::Data,
+                        event => BitstampEvent::Other(event.to_string()),
+                    })
+                }
+                "channel" => {
+                    message.order_book = map.next_value::<&str>()?.starts_with("order_book_")
+                }
+                "data" => message.data = map.next_value::<BitstampData>()?.0,
+                _ => {
+                    map.next_value::<IgnoredAny>()?;
+                }
+            }
+        }
+        Ok(message)
+    }
+}
+
+// the levels when data is an object, nothing for the "" of some events
+struct BitstampData(FoundLevels);
+
+impl<'de> Deserialize<'de> for BitstampData {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserializer.deserialize_any(BitstampDataVisitor)
+    }
+}
+
+struct BitstampDataVisitor;
+
+impl<'de> Visitor<'de> for BitstampDataVisitor {
+    type Value = BitstampData;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("bitstamp data")
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, map: A) -> Result<BitstampData, A::Error> {
+        // the same names in every channel, only the kind differs
+        FoundLevels::visit(&BITSTAMP_DIFF, map).map(BitstampData)
+    }
+
+    fn visit_str<E: de::Error>(self, _: &str) -> Result<BitstampData, E> {
+        Ok(BitstampData(FoundLevels::default()))
+    }
+
+    fn visit_unit<E: de::Error>(self) -> Result<BitstampData, E> {
+        Ok(BitstampData(FoundLevels::default()))
+    }
+}
+
+// [price, amount, ...] with both as decimal strings, anything after them is ignored
+struct Levels(Vec<ParsedLevel>);
+
+impl<'de> Deserialize<'de> for Levels {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserializer.deserialize_seq(LevelsVisitor)
+    }
+}
+
+struct LevelsVisitor;
+
+impl<'de> Visitor<'de> for LevelsVisitor {
+    type Value = Levels;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("an array of [price, amount] levels")
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Levels, A::Error> {
+        // the hint is whatever the input claims, it is capped to not trust it blindly
+        let mut levels = Vec::with_capacity(seq.size_hint().unwrap_or(0).min(5000));
+        while let Some(Level(level)) = seq.next_element()? {
+            levels.push(level);
+        }
+        Ok(Levels(levels))
+    }
+}
+
+struct Level(ParsedLevel);
+
+impl<'de> Deserialize<'de> for Level {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserializer.deserialize_seq(LevelVisitor)
+    }
+}
+
+struct LevelVisitor;
+
+fn decimal<E: de::Error>(text: &str) -> Result<f64, E> {
+    match text.parse::<f64>() {
+        Ok(number) if number.is_finite() && number >= 0.0 => Ok(number),
+        _ => Err(E::invalid_value(
+            de::Unexpected::Str(text),
+            &"a non negative decimal",
+        )),
+    }
+}
+
+impl<'de> Visitor<'de> for LevelVisitor {
+    type Value = Level;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("a [price, amount] level")
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Level, A::Error> {
+        let price: &str = seq
+            .next_element()?
+            .ok_or_else(|| de::Error::invalid_length(0, &self))?;
+        let amount: &str = seq
+            .next_element()?
+            .ok_or_else(|| de::Error::invalid_length(1, &self))?;
+        while seq.next_element::<IgnoredAny>()?.is_some() {}
+        Ok(Level(ParsedLevel {
+            price: decimal(price)?,
+            amount: decimal(amount)?,
+        }))
+    }
+}
+
+// a json number for binance, a string of digits for bitstamp
+struct UpdateId(u64);
+
+impl<'de> Deserialize<'de> for UpdateId {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserializer.deserialize_any(UpdateIdVisitor)
+    }
+}
+
+struct UpdateIdVisitor;
+
+impl<'de> Visitor<'de> for UpdateIdVisitor {
+    type Value = UpdateId;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("an update id as a number or a string")
+    }
+
+    fn visit_u64<E: de::Error>(self, id: u64) -> Result<UpdateId, E> {
+        Ok(UpdateId(id))
+    }
+
+    fn visit_str<E: de::Error>(self, id: &str) -> Result<UpdateId, E> {
+        id.parse()
+            .map(UpdateId)
+            .map_err(|_| E::invalid_value(de::Unexpected::Str(id), &self))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MESSAGES: [(&str, &LevelFormat); 3] = [
+        (
+            r#"{"lastUpdateId":6812,"bids":[["0.06390000","1.5"],["0.06380000","2"]],"asks":[["0.06400000","0.3"]]}"#,
+            &BINANCE_SNAPSHOT,
+        ),
+        (
+            r#"{"e":"depthUpdate","E":1687000000123,"s":"ETHBTC","U":6813,"u":6814,"b":[["0.06390000","0.00000000"]],"a":[]}"#,
+            &BINANCE_DIFF,
+        ),
+        (
+            r#"{"timestamp":"1687000000","microtimestamp":"1687000000123456","bids":[["0.06390000","1.5"]],"asks":[["0.06400000","0.3","1234"]]}"#,
+            &BITSTAMP_SNAPSHOT,
+        ),
+    ];
+    const BITSTAMP_MESSAGE: &str = r#"{"data":{"timestamp":"1687000000","microtimestamp":"1687000000123456","bids":[["0.06390000","1.5"]],"asks":[]},"channel":"diff_order_book_ethbtc","event":"data"}"#;
+
+    #[test]
+    fn parses_every_format_in_exchange_order() {
+        let snapshot = parse_levels(MESSAGES[0].0, MESSAGES[0].1).unwrap();
+        assert_eq!(snapshot.kind, UpdateKind::Snapshot);
+        assert_eq!(snapshot.last_update_id, 6812);
+        assert_eq!(
+            snapshot.bids,
+            vec![
+                ParsedLevel {
+                    price: 0.0639,
+                    amount: 1.5
+                },
+                ParsedLevel {
+                    price: 0.0638,
+                    amount: 2.0
+                },
+            ]
+        );
+        let diff = parse_levels(MESSAGES[1].0, MESSAGES[1].1).unwrap();
+        assert_eq!((diff.kind, diff.last_update_id), (UpdateKind::Diff, 6814));
+        assert_eq!(diff.first_update_id, Some(6813));
+        // extra fields after price and amount are ignored
+        let bitstamp = parse_levels(MESSAGES[2].0, MESSAGES[2].1).unwrap();
+        assert_eq!(bitstamp.exchange, ExchangeId::Bitstamp);
+        assert_eq!(bitstamp.last_update_id, 1687000000123456);
+        assert_eq!(bitstamp.asks[0].amount, 0.3);
+    }
+
+    #[test]
+    fn rejects_levels_that_are_not_prices() {
+        for json in [
+            r#"{"lastUpdateId":1,"bids":[["-1","1"]],"asks":[]}"#,
+            r#"{"lastUpdateId":1,"bids":[["NaN","1"]],"asks":[]}"#,
+            r#"{"lastUpdateId":1,"bids":[["1"]],"asks":[]}"#,
+            r#"{"lastUpdateId":1,"bids":[[1.0,"1"]],"asks":[]}"#,
+            r#"{"lastUpdateId":"x","bids":[],"asks":[]}"#,
+            r#"{"lastUpdateId":1,"bids":[]}"#,
+            r#"{"lastUpdateId":1,"bids":[],"asks":[]} trailing"#,
+        ] {
+            assert!(parse_levels(json, &BINANCE_SNAPSHOT).is_err(), "{}", json);
+        }
+    }
+
+    // xorshift, enough to mutate messages without a dependency
+    struct Random(u64);
+
+    impl Random {
+        fn next(&mut self, bound: usize) -> usize {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            (self.0 % bound as u64) as usize
+        }
+    }
+
+    fn mutate(random: &mut Random, message: &str) -> String {
+        const PIECES: [&str; 12] = [
+            "\"", "[", "]", "{", "}", ",", ":", "-", "1e999", "null", "\\u0000", "\"\\\"\"",
+        ];
+        let mut bytes = message.as_bytes().to_vec();
+        for _ in 0..=random.next(4) {
+            let at = random.next(bytes.len() + 1);
+            match random.next(4) {
+                0 => bytes.truncate(at),
+                1 if at < bytes.len() => {
+                    bytes.remove(at);
+                }
+                2 if at < bytes.len() => bytes[at] = random.next(256) as u8,
+                _ => {
+                    let piece = PIECES[random.next(PIECES.len())].as_bytes();
+                    bytes.splice(at..at, piece.iter().copied());
+                }
+            }
+        }
+        String::from_utf8_lossy(&bytes).into_owned()
+    }
+
+    #[test]
+    fn malformed_input_never_panics() {
+        let mut random = Random(0x9e3779b97f4a7c15);
+        let mut rejected = 0;
+        for round in 0..20_000 {
+            let (message, format) = MESSAGES[round % MESSAGES.len()];
+            let mutated = mutate(&mut random, message);
+            rejected += parse_levels(&mutated, format).is_err() as usize;
+            let _ = parse_bitstamp_message(&mutate(&mut random, BITSTAMP_MESSAGE));
+        }
+        // most mutations break the message, otherwise the test mutates too little
+        assert!(rejected > 10_000, "{}", rejected);
+    }
+}
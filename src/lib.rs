@@ -1,6 +1,27 @@
+// the rpc helpers return tonic::Status, the error the rpcs end with anyway
+#![allow(clippy::result_large_err)]
+
 pub mod orderbookaggregator {
     tonic::include_proto!("orderbookaggregator");
+    pub use crate::summary::{Level, VenueAmount};
+
+    // served by the gRPC reflection service
+    pub const FILE_DESCRIPTOR_SET: &[u8] =
+        tonic::include_file_descriptor_set!("orderbookaggregator_descriptor");
 }
 
+pub mod auth;
+pub mod candles;
+pub mod combined;
 pub mod exchanges;
+pub mod feeds;
+pub mod health;
+pub mod levels;
+pub mod metrics;
 pub mod orderbook;
+pub mod rate_limit;
+pub mod recorder;
+pub mod replay;
+pub mod rest;
+pub mod summary;
+pub mod validation;
@@ -0,0 +1,359 @@
+use crate::exchanges::ExchangeId;
+use crate::orderbook::OrderBook;
+use anyhow::{Context, Result};
+use hyper::header::CONTENT_TYPE;
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Method, Request, Response, StatusCode};
+use prometheus::{
+    Encoder, GaugeVec, Histogram, HistogramOpts, IntCounterVec, IntGaugeVec, Opts, Registry,
+    TextEncoder,
+};
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{LazyLock, Mutex};
+use std::time::Instant;
+use tokio_tungstenite::tungstenite::Message;
+
+// everything exported on /metrics, shared by all the streams of the server
+pub struct Metrics {
+    registry: Registry,
+    // websocket text frames received, by exchange
+    pub messages_received: IntCounterVec,
+    pub parse_errors: IntCounterVec,
+    // updates not newer than the last one applied, dropped by merge_parse_update
+    pub dropped_updates: IntCounterVec,
+    pub reconnects: IntCounterVec,
+    // prices quoted per symbol, exchange and side, by one of the books of the symbol
+    book_depth: IntGaugeVec,
+    // refreshed from symbols on every scrape
+    seconds_since_update: GaugeVec,
+    // open streams per rpc
+    pub active_subscribers: IntGaugeVec,
+    // from a websocket message received to the Summary built with it
+    pub summary_emit_latency: Histogram,
+    // symbols with an open book, their series are removed with the last one
+    symbols: Mutex<HashMap<String, SymbolBooks>>,
+    next_book_id: AtomicU64,
+}
+
+#[derive(Default)]
+struct SymbolBooks {
+    books: usize,
+    // the book whose depth is reported, so that books of the same symbol
+    // don't overwrite each other
+    depth_reporter: Option<u64>,
+    last_updates: HashMap<ExchangeId, Instant>,
+}
+
+pub static METRICS: LazyLock<Metrics> = LazyLock::new(Metrics::new);
+
+fn counter(name: &str, help: &str, labels: &[&str]) -> IntCounterVec {
+    IntCounterVec::new(Opts::new(name, help), labels).expect("invalid counter definition")
+}
+
+impl Metrics {
+    fn new() -> Self {
+        let metrics = Metrics {
+            registry: Registry::new(),
+            messages_received: counter(
+                "orderbook_messages_received_total",
+                "websocket messages received",
+                &["exchange"],
+            ),
+            parse_errors: counter(
+                "orderbook_parse_errors_total",
+                "websocket messages that failed to parse",
+                &["exchange"],
+            ),
+            dropped_updates: counter(
+                "orderbook_dropped_updates_total",
+                "book updates dropped as older than the last one applied",
+                &["exchange"],
+            ),
+            reconnects: counter(
+                "orderbook_reconnects_total",
+                "exchange websocket reconnections",
+                &["exchange"],
+            ),
+            book_depth: IntGaugeVec::new(
+                Opts::new("orderbook_book_depth", "prices quoted in the book"),
+                &["symbol", "exchange", "side"],
+            )
+            .expect("invalid gauge definition"),
+            seconds_since_update: GaugeVec::new(
+                Opts::new(
+                    "orderbook_seconds_since_last_update",
+                    "seconds since the last update applied to the book",
+                ),
+                &["symbol", "exchange"],
+            )
+            .expect("invalid gauge definition"),
+            active_subscribers: IntGaugeVec::new(
+                Opts::new("orderbook_active_subscribers", "open gRPC streams"),
+                &["rpc"],
+            )
+            .expect("invalid gauge definition"),
+            summary_emit_latency: Histogram::with_opts(
+                HistogramOpts::new(
+                    "orderbook_summary_emit_seconds",
+                    "time from an exchange message to the Summary built with it",
+                )
+                .buckets(prometheus::exponential_buckets(0.00001, 4.0, 8).unwrap()),
+            )
+            .expect("invalid histogram definition"),
+            symbols: Mutex::new(HashMap::new()),
+            next_book_id: AtomicU64::new(0),
+        };
+
+        let registry = &metrics.registry;
+        for collector in [
+            &metrics.messages_received,
+            &metrics.parse_errors,
+            &metrics.dropped_updates,
+            &metrics.reconnects,
+        ] {
+            registry
+                .register(Box::new(collector.clone()))
+                .expect("metric registered twice");
+        }
+        for collector in [&metrics.book_depth, &metrics.active_subscribers] {
+            registry
+                .register(Box::new(collector.clone()))
+                .expect("metric registered twice");
+        }
+        registry
+            .register(Box::new(metrics.seconds_since_update.clone()))
+            .expect("metric registered twice");
+        registry
+            .register(Box::new(metrics.summary_emit_latency.clone()))
+            .expect("metric registered twice");
+        metrics
+    }
+
+    // control frames (pings, pongs) are not counted
+    pub fn count_message(&self, exchange: ExchangeId, message: &Message) {
+        if message.is_text() {
+            self.messages_received
+                .with_label_values(&[exchange.as_str()])
+                .inc();
+        }
+    }
+
+    pub fn count_parse_error(&self, exchange: ExchangeId) {
+        self.parse_errors
+            .with_label_values(&[exchange.as_str()])
+            .inc();
+    }
+
+    pub fn count_dropped_update(&self, exchange: ExchangeId) {
+        self.dropped_updates
+            .with_label_values(&[exchange.as_str()])
+            .inc();
+    }
+
+    pub fn count_reconnect(&self, exchange: ExchangeId) {
+        self.reconnects
+            .with_label_values(&[exchange.as_str()])
+            .inc();
+    }
+
+    // registers a book of `symbol`, held for as long as the book is updated
+    pub fn book_guard(&self, symbol: &str) -> BookMetricsGuard {
+        let id = self.next_book_id.fetch_add(1, Ordering::Relaxed);
+        let mut symbols = self.symbols.lock().expect("metrics lock poisoned");
+        symbols.entry(symbol.to_string()).or_default().books += 1;
+        BookMetricsGuard {
+            symbol: symbol.to_string(),
+            id,
+        }
+    }
+
+    // to be called with each update applied to the book of a symbol
+    pub fn mark_update(
+        &self,
+        book: &BookMetricsGuard,
+        exchange: ExchangeId,
+        order_book: &OrderBook,
+    ) {
+        let mut symbols = self.symbols.lock().expect("metrics lock poisoned");
+        let Some(symbol_books) = symbols.get_mut(&book.symbol) else {
+            return;
+        };
+        symbol_books.last_updates.insert(exchange, Instant::now());
+        if *symbol_books.depth_reporter.get_or_insert(book.id) != book.id {
+            return;
+        }
+        let (bids, asks) = order_book.exchange_depth(exchange);
+        self.book_depth
+            .with_label_values(&[&book.symbol, exchange.as_str(), "bid"])
+            .set(bids as i64);
+        self.book_depth
+            .with_label_values(&[&book.symbol, exchange.as_str(), "ask"])
+            .set(asks as i64);
+    }
+
+    fn release_book(&self, book: &BookMetricsGuard) {
+        let mut symbols = self.symbols.lock().expect("metrics lock poisoned");
+        let Some(symbol_books) = symbols.get_mut(&book.symbol) else {
+            return;
+        };
+        symbol_books.books -= 1;
+        if symbol_books.depth_reporter == Some(book.id) {
+            // the next book updated takes over
+            symbol_books.depth_reporter = None;
+        }
+        if symbol_books.books > 0 {
+            return;
+        }
+        symbols.remove(&book.symbol);
+        // errors only mean the series was never set
+        for exchange in ExchangeId::ALL {
+            let exchange = exchange.as_str();
+            _ = self
+                .seconds_since_update
+                .remove_label_values(&[&book.symbol, exchange]);
+            for side in ["bid", "ask"] {
+                _ = self
+                    .book_depth
+                    .remove_label_values(&[&book.symbol, exchange, side]);
+            }
+        }
+    }
+
+    // prometheus text format
+    pub fn render(&self) -> Result<String> {
+        for (symbol, symbol_books) in self.symbols.lock().expect("metrics lock poisoned").iter() {
+            for (exchange, last_update) in &symbol_books.last_updates {
+                self.seconds_since_update
+                    .with_label_values(&[symbol, exchange.as_str()])
+                    .set(last_update.elapsed().as_secs_f64());
+            }
+        }
+        let mut buffer = Vec::new();
+        TextEncoder::new()
+            .encode(&self.registry.gather(), &mut buffer)
+            .context("failed to encode metrics")?;
+        String::from_utf8(buffer).context("metrics are not utf8")
+    }
+}
+
+// counts one open stream of an rpc for as long as it is alive
+pub struct SubscriberGuard {
+    rpc: &'static str,
+}
+
+impl SubscriberGuard {
+    pub fn new(rpc: &'static str) -> Self {
+        METRICS.active_subscribers.with_label_values(&[rpc]).inc();
+        SubscriberGuard { rpc }
+    }
+}
+
+impl Drop for SubscriberGuard {
+    fn drop(&mut self) {
+        METRICS
+            .active_subscribers
+            .with_label_values(&[self.rpc])
+            .dec();
+    }
+}
+
+// one book of a symbol in the book metrics, its series are removed when the
+// last book of the symbol is dropped
+pub struct BookMetricsGuard {
+    symbol: String,
+    id: u64,
+}
+
+impl Drop for BookMetricsGuard {
+    fn drop(&mut self) {
+        METRICS.release_book(self);
+    }
+}
+
+async fn handle(request: Request<Body>) -> Result<Response<Body>, Infallible> {
+    let response = match (request.method(), request.uri().path()) {
+        (&Method::GET, "/metrics") => match METRICS.render() {
+            Ok(body) => Response::builder()
+                .header(CONTENT_TYPE, TextEncoder::new().format_type())
+                .body(Body::from(body)),
+            Err(err) => Response::builder()
+                .status(StatusCode::INTERNAL_SERVER_ERROR)
+                .body(Body::from(err.to_string())),
+        },
+        _ => Response::builder()
+            .status(StatusCode::NOT_FOUND)
+            .body(Body::empty()),
+    };
+    Ok(response.expect("failed to build metrics response"))
+}
+
+// plain HTTP endpoint serving GET /metrics
+pub async fn serve(address: SocketAddr) -> Result<()> {
+    let make_service = make_service_fn(|_| async { Ok::<_, Infallible>(service_fn(handle)) });
+    tracing::info!("metrics available on http://{}/metrics", address);
+    hyper::Server::try_bind(&address)
+        .context("failed to bind metrics address")?
+        .serve(make_service)
+        .await
+        .context("metrics server failed")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::exchanges::{ParsedLevel, ParsedUpdate, UpdateKind};
+
+    #[test]
+    fn renders_book_and_subscriber_metrics() {
+        let order_book = OrderBook::new(
+            5,
+            ParsedUpdate {
+                exchange: ExchangeId::Binance,
+                kind: UpdateKind::Snapshot,
+                bids: vec![ParsedLevel {
+                    price: 9.0,
+                    amount: 1.0,
+                }],
+                asks: vec![],
+                last_update_id: 1,
+                first_update_id: None,
+            },
+        )
+        .unwrap();
+        let book = METRICS.book_guard("metricstest");
+        let other_book = METRICS.book_guard("metricstest");
+        METRICS.mark_update(&book, ExchangeId::Binance, &order_book);
+        // only the first book updated reports its depth
+        METRICS.mark_update(&other_book, ExchangeId::Binance, &OrderBook::default());
+        let guard = SubscriberGuard::new("metrics_test");
+
+        let rendered = METRICS.render().unwrap();
+        assert!(rendered.contains(
+            r#"orderbook_book_depth{exchange="BINANCE",side="bid",symbol="metricstest"} 1"#
+        ));
+        assert!(rendered.contains(
+            r#"orderbook_seconds_since_last_update{exchange="BINANCE",symbol="metricstest"}"#
+        ));
+        assert!(rendered.contains(r#"orderbook_active_subscribers{rpc="metrics_test"} 1"#));
+
+        drop(guard);
+        assert!(METRICS
+            .render()
+            .unwrap()
+            .contains(r#"orderbook_active_subscribers{rpc="metrics_test"} 0"#));
+
+        drop(book);
+        assert!(METRICS
+            .render()
+            .unwrap()
+            .contains(r#"symbol="metricstest""#));
+        drop(other_book);
+        assert!(!METRICS
+            .render()
+            .unwrap()
+            .contains(r#"symbol="metricstest""#));
+    }
+}
@@ -1,36 +1,276 @@
-use crate::exchanges::ParsedUpdate;
-use crate::orderbookaggregator::{Level, Summary};
+use crate::exchanges::{ExchangeId, ParsedLevel, ParsedUpdate, UpdateKind};
+use crate::orderbookaggregator::{Analytics, Level, Summary, VenueAmount};
 use anyhow::Result;
 use colored::Colorize;
-use rust_decimal::{prelude::FromPrimitive, Decimal};
+use std::collections::btree_map::Entry;
 use std::collections::BTreeMap;
 use std::collections::HashMap;
 use std::fmt;
 
+// depth of the analytics block when a client does not ask for a specific one
+pub const DEFAULT_DEPTH_BPS: f64 = 10.0;
+
 pub fn price_to_price_map_index(price: f64) -> usize {
     // representing with a very big usize was chosen over decimal for semplicity
     // and to remove bugs when prices were significantly below 1, like 0.00000001
-    let price_index = (price * 1_000_000_000.0) as usize;
-    return price_index;
+    (price * 1_000_000_000.0) as usize
+}
+
+pub fn price_map_index_to_price(price_index: usize) -> f64 {
+    price_index as f64 / 1_000_000_000.0
+}
+
+// one venue quote as stored in the book, it only becomes a proto Level
+// (borrowing the exchange name, see summary.rs) when a summary is built
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BookLevel {
+    pub exchange: ExchangeId,
+    pub price: f64,
+    pub amount: f64,
+}
+
+impl From<&BookLevel> for Level {
+    fn from(level: &BookLevel) -> Self {
+        Level {
+            exchange: level.exchange.as_str().into(),
+            price: level.price,
+            amount: level.amount,
+            venues: Vec::new(),
+        }
+    }
+}
+
+// all the venues quoting one price, at most one entry per exchange so it stays tiny
+// and it is kept sorted by amount (largest first) which is the reporting order
+#[derive(Debug, Default)]
+struct PriceLevel {
+    levels: Vec<BookLevel>,
+}
+
+impl PriceLevel {
+    // true when the exchange was not quoting this price yet
+    fn upsert(&mut self, level: BookLevel) -> bool {
+        let added = match self
+            .levels
+            .iter_mut()
+            .find(|current| current.exchange == level.exchange)
+        {
+            Some(current) => {
+                current.amount = level.amount;
+                false
+            }
+            None => {
+                self.levels.push(level);
+                true
+            }
+        };
+        // insertion sort under the hood for a handful of elements, no allocation
+        self.levels.sort_by(|a, b| b.amount.total_cmp(&a.amount));
+        added
+    }
+
+    // true when the exchange was quoting this price
+    fn remove(&mut self, exchange: ExchangeId) -> bool {
+        let levels_before = self.levels.len();
+        self.levels.retain(|level| level.exchange != exchange);
+        self.levels.len() != levels_before
+    }
+
+    fn contains(&self, exchange: ExchangeId) -> bool {
+        self.levels.iter().any(|level| level.exchange == exchange)
+    }
+
+    fn amount(&self) -> f64 {
+        self.levels.iter().map(|level| level.amount).sum()
+    }
+
+    // merges all the venues quoting this price in a single Level
+    fn consolidate(&self) -> Level {
+        Level {
+            exchange: "".into(),
+            price: self.levels.first().map_or(0.0, |level| level.price),
+            amount: self.amount(),
+            venues: self
+                .levels
+                .iter()
+                .map(|level| VenueAmount {
+                    exchange: level.exchange.as_str().into(),
+                    amount: level.amount,
+                })
+                .collect(),
+        }
+    }
+}
+
+fn remove_exchange_level(
+    prices_reference: &mut BTreeMap<usize, PriceLevel>,
+    levels_count: &mut HashMap<ExchangeId, usize>,
+    exchange: ExchangeId,
+    price_position: usize,
+) {
+    if let Entry::Occupied(mut price_level) = prices_reference.entry(price_position) {
+        if price_level.get_mut().remove(exchange) {
+            *levels_count.entry(exchange).or_default() -= 1;
+        }
+        if price_level.get().levels.is_empty() {
+            price_level.remove();
+        }
+    }
+}
+
+// zero amounts delete the exchange from the price, and the price itself once no
+// venue is left, so that the first and last keys are always the best prices.
+// deleting a price that is not in the book (never received or pruned) does nothing
+fn merge_level(
+    prices_reference: &mut BTreeMap<usize, PriceLevel>,
+    levels_count: &mut HashMap<ExchangeId, usize>,
+    exchange: ExchangeId,
+    level: ParsedLevel,
+) {
+    let price_position = price_to_price_map_index(level.price);
+    // exchanges send a literal zero amount for deletes, any other amount is a level
+    if level.amount == 0.0 {
+        remove_exchange_level(prices_reference, levels_count, exchange, price_position);
+    } else {
+        let added = prices_reference
+            .entry(price_position)
+            .or_default()
+            .upsert(BookLevel {
+                exchange,
+                price: level.price,
+                amount: level.amount,
+            });
+        if added {
+            *levels_count.entry(exchange).or_default() += 1;
+        }
+    }
+}
+
+// the book of an exchange is not known past its pruned levels: bids at or below the
+// highest pruned bid, asks at or above the lowest pruned ask
+fn mark_pruned(
+    pruned_at: &mut HashMap<ExchangeId, usize>,
+    exchange: ExchangeId,
+    price_position: usize,
+    is_bid_side: bool,
+) {
+    let boundary = pruned_at.entry(exchange).or_insert(price_position);
+    *boundary = if is_bid_side {
+        (*boundary).max(price_position)
+    } else {
+        (*boundary).min(price_position)
+    };
+}
+
+// drops the `excess` levels of one exchange that are furthest from the top of the book.
+// The side is walked from its far end (lowest bids, highest asks) and the walk stops
+// as soon as `excess` levels of the exchange are found
+fn prune_exchange_depth(
+    prices_reference: &mut BTreeMap<usize, PriceLevel>,
+    levels_count: &mut HashMap<ExchangeId, usize>,
+    pruned_at: &mut HashMap<ExchangeId, usize>,
+    exchange: ExchangeId,
+    excess: usize,
+    is_bid_side: bool,
+) {
+    let exchange_price = |(price_position, price_level): (&usize, &PriceLevel)| {
+        price_level.contains(exchange).then_some(*price_position)
+    };
+    let pruned_positions: Vec<usize> = if is_bid_side {
+        let far_end = prices_reference.iter();
+        far_end.filter_map(exchange_price).take(excess).collect()
+    } else {
+        let far_end = prices_reference.iter().rev();
+        far_end.filter_map(exchange_price).take(excess).collect()
+    };
+    for price_position in pruned_positions {
+        remove_exchange_level(prices_reference, levels_count, exchange, price_position);
+        mark_pruned(pruned_at, exchange, price_position, is_bid_side);
+    }
+}
+
+fn forget_pruned_levels(
+    pruned: BTreeMap<usize, PriceLevel>,
+    levels_count: &mut HashMap<ExchangeId, usize>,
+    pruned_at: &mut HashMap<ExchangeId, usize>,
+    is_bid_side: bool,
+) {
+    for (price_position, price_level) in &pruned {
+        for level in &price_level.levels {
+            *levels_count.entry(level.exchange).or_default() -= 1;
+            mark_pruned(pruned_at, level.exchange, *price_position, is_bid_side);
+        }
+    }
+}
+
+// a diff that does not start right after the last one applied, the diffs in between
+// were lost and the book of the exchange has to be taken again from a snapshot
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SequenceGap {
+    pub exchange: ExchangeId,
+    pub last_update_id: u64,
+    pub first_update_id: u64,
+}
+
+impl fmt::Display for SequenceGap {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} diffs lost, the last one applied was {} and the next starts at {}",
+            self.exchange, self.last_update_id, self.first_update_id
+        )
+    }
 }
 
-pub fn volume_to_volume_mantissa(volume: f64) -> u32 {
-    let price_index = Decimal::from_f64(volume).expect("Decimal failed to parse f64 for volume");
-    return price_index.mantissa() as u32;
+impl std::error::Error for SequenceGap {}
+
+// the settings a book is served with, kept in the header of its recordings so that
+// a replay prunes the book the same way
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct BookConfig {
+    pub reporting_levels: u32,
+    pub max_depth_per_exchange: Option<usize>,
+    pub price_band_bps: Option<f64>,
 }
 
 #[derive(Debug, Default)]
 pub struct OrderBook {
     // The idea is storing price points in a BTreeMap
     // Indexing the price with some integer (usize) representation
-    // This will allow O(1) retrieval for any price point
-    // also the BTreeMap is ideal for sice of the price refence and keep tha data sorted
-    pub best_bid_price: usize,
-    pub best_ask_price: usize,
-    bid_prices_reference: BTreeMap<usize, HashMap<String, Level>>,
-    ask_prices_reference: BTreeMap<usize, HashMap<String, Level>>,
-    pub reporting_levels: u32, // to be set as a parmater
-    pub last_update_ids: HashMap<String, u64>,
+    // This will allow O(log n) retrieval for any price point
+    // also the BTreeMap is ideal for sice of the price refence and keep tha data sorted,
+    // best bid and ask are simply the two ends of the trees as empty prices are removed
+    bid_prices_reference: BTreeMap<usize, PriceLevel>,
+    ask_prices_reference: BTreeMap<usize, PriceLevel>,
+    // number of prices each exchange quotes on each side, for the depth cap
+    bid_levels_count: HashMap<ExchangeId, usize>,
+    ask_levels_count: HashMap<ExchangeId, usize>,
+    pub reporting_levels: u32,            // to be set as a parmater
+    pub consolidated: bool,               // one Level per price with venues breakdown
+    pub analytics_depth_bps: Option<f64>, // analytics block in the summary when set
+    pub last_update_ids: HashMap<ExchangeId, u64>,
+    // memory bounds, levels beyond them are pruned after every update
+    pub max_depth_per_exchange: Option<usize>, // prices kept per exchange and side
+    pub price_band_bps: Option<f64>,           // distance from mid in basis points
+    // per exchange, the pruned bid closest to the top and the same for asks, the updates
+    // past them are ignored (see mark_pruned)
+    bid_pruned_at: HashMap<ExchangeId, usize>,
+    ask_pruned_at: HashMap<ExchangeId, usize>,
+    // exchanges to take again from a snapshot, see take_resync
+    resync: Vec<ExchangeId>,
+}
+
+// in consolidated mode the exchange is empty and the venues are listed instead
+fn level_source(level: &Level) -> String {
+    if level.venues.is_empty() {
+        return level.exchange.to_string();
+    }
+    let venues: Vec<String> = level
+        .venues
+        .iter()
+        .map(|venue| format!("{} {}", venue.exchange, venue.amount))
+        .collect();
+    format!("[{}]", venues.join(", "))
 }
 
 // Summary trait to allow pretty printing from orderbook-client
@@ -45,7 +285,7 @@ impl fmt::Display for Summary {
         for level in self.asks.iter().rev() {
             let ask_level_to_print = format!(
                 "{}: {} - {}\n",
-                &level.exchange,
+                level_source(level),
                 level.price.to_string().red(),
                 level.amount.to_string().red()
             );
@@ -55,7 +295,7 @@ impl fmt::Display for Summary {
         for level in self.bids.iter() {
             let bid_level_to_print = format!(
                 "{}: {} - {}\n",
-                &level.exchange,
+                level_source(level),
                 level.price.to_string().green(),
                 level.amount.to_string().green()
             );
@@ -64,180 +304,505 @@ impl fmt::Display for Summary {
 
         write!(
             f,
-            "{}: {}\n{}\n{}",
-            "current spread",
+            "current spread: {}\n{}\n{}",
             self.spread.to_string().green(),
             asks_to_display,
             bids_to_display
-        )
+        )?;
+        if let Some(analytics) = &self.analytics {
+            write!(
+                f,
+                "\nmid: {} microprice: {} imbalance: {:.4}\ndepth within {} bps: {} / {}",
+                analytics.mid_price,
+                analytics.microprice,
+                analytics.imbalance,
+                analytics.depth_bps,
+                analytics.bid_depth.to_string().green(),
+                analytics.ask_depth.to_string().red()
+            )?;
+        }
+        Ok(())
     }
 }
 
-// levels - number of levels to be monitored as u32 (this is for the summary, the orderbook stores anyway everything it receives)
+// levels - number of levels to be monitored as u32 (this is for the summary, the orderbook stores
+// everything it receives unless max_depth_per_exchange or price_band_bps are set)
 // ParsedUpdate - struct that cotains 2 vetors of levels (for bids and asks) and a timestamp
 impl OrderBook {
     pub fn new(reporting_levels: u32, parsed_update: ParsedUpdate) -> Result<Self> {
-        let best_bid_price: usize = 0;
-        let best_ask_price: usize = usize::MAX;
+        let config = BookConfig {
+            reporting_levels,
+            ..BookConfig::default()
+        };
+        Self::with_config(config, parsed_update)
+    }
 
-        let bid_prices_reference: BTreeMap<usize, HashMap<String, Level>> = BTreeMap::new();
-        let ask_prices_reference: BTreeMap<usize, HashMap<String, Level>> = BTreeMap::new();
+    // the bounds are set before the first update is merged, so it is pruned as well
+    pub fn with_config(config: BookConfig, parsed_update: ParsedUpdate) -> Result<Self> {
+        let bid_prices_reference: BTreeMap<usize, PriceLevel> = BTreeMap::new();
+        let ask_prices_reference: BTreeMap<usize, PriceLevel> = BTreeMap::new();
 
-        let mut last_update_ids = HashMap::new(); // to be kept with latest update from each exchange
-        last_update_ids.insert("BINANCE".to_string(), 1);
-        last_update_ids.insert("BITSTAMP".to_string(), 1);
+        // to be kept with latest update from each exchange
+        let last_update_ids = ExchangeId::ALL
+            .iter()
+            .map(|exchange| (*exchange, 1))
+            .collect();
 
         let mut order_book = Self {
-            best_bid_price,
-            best_ask_price,
             bid_prices_reference,
             ask_prices_reference,
-            reporting_levels,
+            bid_levels_count: HashMap::new(),
+            ask_levels_count: HashMap::new(),
+            reporting_levels: config.reporting_levels,
+            consolidated: false,
+            analytics_depth_bps: None,
             last_update_ids,
+            max_depth_per_exchange: config.max_depth_per_exchange,
+            price_band_bps: config.price_band_bps,
+            bid_pruned_at: HashMap::new(),
+            ask_pruned_at: HashMap::new(),
+            resync: Vec::new(),
         };
         order_book.merge_parse_update(parsed_update)?;
         Ok(order_book)
     }
 
-    pub fn merge_parse_update(&mut self, parsed_update: ParsedUpdate) -> Result<()> {
-        // this first checks if for a given exchange we have a last_update_id timestmp
-        // higher than current, if not simply returns Ok(())
-        let exchange_identifier;
-        if parsed_update.bids.len() >= 1 {
-            exchange_identifier = parsed_update.bids[0].exchange.clone();
-        } else {
-            exchange_identifier = parsed_update.asks[0].exchange.clone();
-        }
-        if parsed_update.last_update_id
-            > *self
-                .last_update_ids
-                .get(&exchange_identifier)
-                .expect("failed to retrieve last_update_timestamp for exchange")
-        {
-            self.last_update_ids
-                .insert(exchange_identifier, parsed_update.last_update_id);
-        } else {
-            return Ok(());
+    pub fn config(&self) -> BookConfig {
+        BookConfig {
+            reporting_levels: self.reporting_levels,
+            max_depth_per_exchange: self.max_depth_per_exchange,
+            price_band_bps: self.price_band_bps,
         }
+    }
 
-        for bid in parsed_update.bids {
-            self.merge_bid(bid)?
+    // removes every level of one exchange from both sides, other venues are untouched
+    pub fn clear_exchange(&mut self, exchange: ExchangeId) {
+        for prices_reference in [
+            &mut self.bid_prices_reference,
+            &mut self.ask_prices_reference,
+        ] {
+            prices_reference.retain(|_, price_level| {
+                price_level.remove(exchange);
+                !price_level.levels.is_empty()
+            });
         }
+        self.bid_levels_count.insert(exchange, 0);
+        self.ask_levels_count.insert(exchange, 0);
+        self.bid_pruned_at.remove(&exchange);
+        self.ask_pruned_at.remove(&exchange);
+    }
 
-        for ask in parsed_update.asks {
-            self.merge_ask(ask)?
+    // the levels one exchange currently has in the book, as a snapshot that
+    // apply_snapshot turns back into the same state (best prices first)
+    pub fn exchange_snapshot(&self, exchange: ExchangeId) -> ParsedUpdate {
+        let to_parsed_levels = |levels: &mut dyn Iterator<Item = &BookLevel>| {
+            levels
+                .filter(|level| level.exchange == exchange)
+                .map(|level| ParsedLevel {
+                    price: level.price,
+                    amount: level.amount,
+                })
+                .collect()
+        };
+        ParsedUpdate {
+            exchange,
+            kind: UpdateKind::Snapshot,
+            bids: to_parsed_levels(&mut self.bids_iter()),
+            asks: to_parsed_levels(&mut self.asks_iter()),
+            last_update_id: self.last_update_ids.get(&exchange).copied().unwrap_or(0),
+            first_update_id: None,
         }
+    }
 
-        return Ok(());
+    // (bids, asks) number of prices quoted by one exchange
+    pub fn exchange_depth(&self, exchange: ExchangeId) -> (usize, usize) {
+        (
+            self.bid_levels_count.get(&exchange).copied().unwrap_or(0),
+            self.ask_levels_count.get(&exchange).copied().unwrap_or(0),
+        )
     }
 
-    pub fn merge_bid(&mut self, level: Level) -> Result<()> {
-        let price_position = price_to_price_map_index(level.price);
-        let ref_map = self
-            .bid_prices_reference
-            .entry(price_position)
-            .or_insert(HashMap::new());
-
-        if volume_to_volume_mantissa(level.amount) == 0 {
-            ref_map.remove(&level.exchange);
-            if (price_position == self.best_bid_price) && (ref_map.len() == 0) {
-                for (next_price_index, next_exchange_map) in self.bid_prices_reference.iter().rev()
-                {
-                    if next_exchange_map.len() > 0 {
-                        self.best_bid_price = *next_price_index;
-                        break;
-                    }
+    // enforces max_depth_per_exchange and price_band_bps. The book of an exchange is not
+    // known past its pruned levels anymore, updates there are ignored. It stays exact
+    // within half the band around mid and over half the depth cap: once mid moves or
+    // deletes make the pruned levels come back in there, the exchange is flagged to be
+    // taken again from a snapshot (see take_resync)
+    pub fn prune(&mut self) {
+        if let Some(price_band_bps) = self.price_band_bps {
+            self.prune_outside_band(price_band_bps);
+        }
+        if let Some(max_depth) = self.max_depth_per_exchange {
+            for exchange in ExchangeId::ALL {
+                let (bids_depth, asks_depth) = self.exchange_depth(exchange);
+                if bids_depth > max_depth {
+                    prune_exchange_depth(
+                        &mut self.bid_prices_reference,
+                        &mut self.bid_levels_count,
+                        &mut self.bid_pruned_at,
+                        exchange,
+                        bids_depth - max_depth,
+                        true,
+                    );
+                }
+                if asks_depth > max_depth {
+                    prune_exchange_depth(
+                        &mut self.ask_prices_reference,
+                        &mut self.ask_levels_count,
+                        &mut self.ask_pruned_at,
+                        exchange,
+                        asks_depth - max_depth,
+                        false,
+                    );
                 }
-            }
-        } else {
-            ref_map.insert(level.exchange.clone(), level);
-            if price_position > self.best_bid_price {
-                self.best_bid_price = price_position
             }
         }
-        return Ok(());
+        self.flag_uncovered();
     }
 
-    pub fn merge_ask(&mut self, level: Level) -> Result<()> {
-        let price_position = price_to_price_map_index(level.price);
-        let ref_map = self
-            .ask_prices_reference
-            .entry(price_position)
-            .or_insert(HashMap::new());
-
-        if volume_to_volume_mantissa(level.amount) == 0 {
-            ref_map.remove(&level.exchange);
-            if (price_position == self.best_ask_price) && (ref_map.len() == 0) {
-                for (next_price_index, next_exchange_map) in self.ask_prices_reference.iter() {
-                    if next_exchange_map.len() > 0 {
-                        self.best_ask_price = *next_price_index;
-                        break;
-                    }
-                }
+    // the exchanges whose pruned levels came back within half the band or half the cap
+    fn flag_uncovered(&mut self) {
+        if self.bid_pruned_at.is_empty() && self.ask_pruned_at.is_empty() {
+            return;
+        }
+        let mid = self.mid_price();
+        for exchange in ExchangeId::ALL {
+            let bid_pruned_at = self.bid_pruned_at.get(&exchange).copied();
+            let ask_pruned_at = self.ask_pruned_at.get(&exchange).copied();
+            let mut uncovered = false;
+            if let (Some(price_band_bps), Some(mid)) = (self.price_band_bps, mid) {
+                let lowest_bid = price_to_price_map_index(mid * (1.0 - price_band_bps / 20_000.0));
+                let highest_ask = price_to_price_map_index(mid * (1.0 + price_band_bps / 20_000.0));
+                uncovered |= bid_pruned_at.is_some_and(|pruned_at| pruned_at >= lowest_bid);
+                uncovered |= ask_pruned_at.is_some_and(|pruned_at| pruned_at <= highest_ask);
             }
-        } else {
-            ref_map.insert(level.exchange.clone(), level);
-            if price_position < self.best_ask_price {
-                self.best_ask_price = price_position
+            if let Some(max_depth) = self.max_depth_per_exchange {
+                let (bids_depth, asks_depth) = self.exchange_depth(exchange);
+                uncovered |= bid_pruned_at.is_some() && bids_depth * 2 < max_depth;
+                uncovered |= ask_pruned_at.is_some() && asks_depth * 2 < max_depth;
             }
-        }
-        return Ok(());
-    }
-
-    pub fn get_asks_reporting_levels(&mut self) -> Result<Vec<Level>> {
-        let mut selected_ask: Vec<Level> = Vec::new();
-        let mut count = 0;
-
-        for (_, exchange_levels_map) in self.ask_prices_reference.iter() {
-            let mut sorted_levels: Vec<(&std::string::String, &Level)> =
-                exchange_levels_map.iter().collect();
-            sorted_levels.sort_by(|a, b| b.1.amount.partial_cmp(&a.1.amount).unwrap());
-            for (_, level) in sorted_levels {
-                selected_ask.push(level.clone());
-                count += 1;
-                if count == self.reporting_levels {
-                    break;
+            if uncovered {
+                // flagged once, the snapshot restores the levels
+                self.bid_pruned_at.remove(&exchange);
+                self.ask_pruned_at.remove(&exchange);
+                if !self.resync.contains(&exchange) {
+                    self.resync.push(exchange);
                 }
             }
-            if count == self.reporting_levels {
-                break;
-            }
         }
-        return Ok(selected_ask);
-    }
-
-    pub fn get_bids_reporting_levels(&mut self) -> Result<Vec<Level>> {
-        let mut selected_bids: Vec<Level> = Vec::new();
-        let mut count = 0;
-
-        // bids should be iterated from larger to smaller so .rev()
-        for (_, exchange_levels_map) in self.bid_prices_reference.iter().rev() {
-            let mut sorted_levels: Vec<(&std::string::String, &Level)> =
-                exchange_levels_map.iter().collect();
-            sorted_levels.sort_by(|a, b| b.1.amount.partial_cmp(&a.1.amount).unwrap());
-            for (_, level) in sorted_levels {
-                selected_bids.push(level.clone());
-                count += 1;
-                if count == self.reporting_levels {
-                    break;
+    }
+
+    // an exchange whose book lost levels to pruning that it should show again, to be
+    // resynced from a new snapshot
+    pub fn take_resync(&mut self) -> Option<ExchangeId> {
+        self.resync.pop()
+    }
+
+    fn prune_outside_band(&mut self, price_band_bps: f64) {
+        let Some(mid) = self.mid_price() else {
+            return;
+        };
+        let lowest_bid = price_to_price_map_index(mid * (1.0 - price_band_bps / 10_000.0));
+        let highest_ask = price_to_price_map_index(mid * (1.0 + price_band_bps / 10_000.0));
+
+        // split_off keeps the tree operations logarithmic, checking the ends first
+        // avoids it altogether in the common case where nothing is out of the band
+        if self
+            .bid_prices_reference
+            .keys()
+            .next()
+            .is_some_and(|lowest| *lowest < lowest_bid)
+        {
+            let kept_bids = self.bid_prices_reference.split_off(&lowest_bid);
+            let pruned_bids = std::mem::replace(&mut self.bid_prices_reference, kept_bids);
+            forget_pruned_levels(
+                pruned_bids,
+                &mut self.bid_levels_count,
+                &mut self.bid_pruned_at,
+                true,
+            );
+        }
+        if self
+            .ask_prices_reference
+            .keys()
+            .next_back()
+            .is_some_and(|highest| *highest > highest_ask)
+        {
+            let pruned_asks = self.ask_prices_reference.split_off(&(highest_ask + 1));
+            forget_pruned_levels(
+                pruned_asks,
+                &mut self.ask_levels_count,
+                &mut self.ask_pruned_at,
+                false,
+            );
+        }
+    }
+
+    // a REST snapshot is the whole book of its exchange, so the levels that exchange
+    // had are replaced rather than merged (anything missing from the snapshot is gone)
+    // and its sequence restarts from the snapshot id so that only newer diffs apply
+    pub fn apply_snapshot(&mut self, parsed_update: ParsedUpdate) -> Result<()> {
+        let exchange = parsed_update.exchange;
+        self.clear_exchange(exchange);
+        self.last_update_ids
+            .insert(exchange, parsed_update.last_update_id);
+
+        for bid in parsed_update.bids {
+            self.merge_bid(exchange, bid)?
+        }
+
+        for ask in parsed_update.asks {
+            self.merge_ask(exchange, ask)?
+        }
+
+        self.prune();
+        Ok(())
+    }
+
+    // 0 when there are no bids, as it was before the first update
+    pub fn best_bid_price(&self) -> usize {
+        self.bid_prices_reference
+            .keys()
+            .next_back()
+            .copied()
+            .unwrap_or(0)
+    }
+
+    // usize::MAX when there are no asks
+    pub fn best_ask_price(&self) -> usize {
+        self.ask_prices_reference
+            .keys()
+            .next()
+            .copied()
+            .unwrap_or(usize::MAX)
+    }
+
+    // Ok(false) when the update was dropped as stale (not newer than the last one applied),
+    // a SequenceGap error when diffs are missing before it, the book is left untouched
+    pub fn merge_parse_update(&mut self, parsed_update: ParsedUpdate) -> Result<bool> {
+        if parsed_update.kind == UpdateKind::Snapshot {
+            self.apply_snapshot(parsed_update)?;
+            return Ok(true);
+        }
+
+        // this first checks if for a given exchange we have a last_update_id timestmp
+        // higher than current, if not simply returns Ok(false)
+        let exchange = parsed_update.exchange;
+        let last_update_id = self
+            .last_update_ids
+            .get_mut(&exchange)
+            .expect("failed to retrieve last_update_timestamp for exchange");
+        if parsed_update.last_update_id <= *last_update_id {
+            return Ok(false);
+        }
+        if let Some(first_update_id) = parsed_update.first_update_id {
+            if first_update_id > *last_update_id + 1 {
+                return Err(SequenceGap {
+                    exchange,
+                    last_update_id: *last_update_id,
+                    first_update_id,
                 }
+                .into());
             }
-            if count == self.reporting_levels {
-                break;
-            }
         }
-        return Ok(selected_bids);
+        *last_update_id = parsed_update.last_update_id;
+
+        for bid in parsed_update.bids {
+            self.merge_bid(exchange, bid)?
+        }
+
+        for ask in parsed_update.asks {
+            self.merge_ask(exchange, ask)?
+        }
+
+        self.prune();
+        Ok(true)
+    }
+
+    pub fn merge_bid(&mut self, exchange: ExchangeId, level: ParsedLevel) -> Result<()> {
+        if self
+            .bid_pruned_at
+            .get(&exchange)
+            .is_some_and(|pruned_at| price_to_price_map_index(level.price) <= *pruned_at)
+        {
+            return Ok(());
+        }
+        merge_level(
+            &mut self.bid_prices_reference,
+            &mut self.bid_levels_count,
+            exchange,
+            level,
+        );
+        Ok(())
+    }
+
+    pub fn merge_ask(&mut self, exchange: ExchangeId, level: ParsedLevel) -> Result<()> {
+        if self
+            .ask_pruned_at
+            .get(&exchange)
+            .is_some_and(|pruned_at| price_to_price_map_index(level.price) >= *pruned_at)
+        {
+            return Ok(());
+        }
+        merge_level(
+            &mut self.ask_prices_reference,
+            &mut self.ask_levels_count,
+            exchange,
+            level,
+        );
+        Ok(())
+    }
+
+    // asks in reporting order (best price first, then largest amount first)
+    // borrowed from the book, nothing is allocated until converted to proto Levels
+    pub fn asks_iter(&self) -> impl Iterator<Item = &BookLevel> {
+        self.ask_prices_reference
+            .values()
+            .flat_map(|price_level| price_level.levels.iter())
+    }
+
+    // bids should be iterated from larger to smaller so .rev()
+    pub fn bids_iter(&self) -> impl Iterator<Item = &BookLevel> {
+        self.bid_prices_reference
+            .values()
+            .rev()
+            .flat_map(|price_level| price_level.levels.iter())
+    }
+
+    pub fn get_asks_reporting_levels(&self) -> Result<Vec<Level>> {
+        Ok(self
+            .asks_iter()
+            .take(self.reporting_levels as usize)
+            .map(Level::from)
+            .collect())
+    }
+
+    pub fn get_bids_reporting_levels(&self) -> Result<Vec<Level>> {
+        Ok(self
+            .bids_iter()
+            .take(self.reporting_levels as usize)
+            .map(Level::from)
+            .collect())
+    }
+
+    pub fn get_consolidated_asks_reporting_levels(&self) -> Result<Vec<Level>> {
+        let selected_asks = self
+            .ask_prices_reference
+            .values()
+            .take(self.reporting_levels as usize)
+            .map(PriceLevel::consolidate)
+            .collect();
+        Ok(selected_asks)
+    }
+
+    pub fn get_consolidated_bids_reporting_levels(&self) -> Result<Vec<Level>> {
+        // same as above, bids from larger to smaller
+        let selected_bids = self
+            .bid_prices_reference
+            .values()
+            .rev()
+            .take(self.reporting_levels as usize)
+            .map(PriceLevel::consolidate)
+            .collect();
+        Ok(selected_bids)
+    }
+
+    pub fn mid_price(&self) -> Option<f64> {
+        let best_bid = self.bid_prices_reference.keys().next_back()?;
+        let best_ask = self.ask_prices_reference.keys().next()?;
+        Some((price_map_index_to_price(*best_bid) + price_map_index_to_price(*best_ask)) / 2.0)
+    }
+
+    // size weighted mid, leans towards the side with less liquidity on top
+    // as that is the one more likely to be taken out first
+    pub fn microprice(&self) -> Option<f64> {
+        let (best_bid, bid_level) = self.bid_prices_reference.iter().next_back()?;
+        let (best_ask, ask_level) = self.ask_prices_reference.iter().next()?;
+        let best_bid = price_map_index_to_price(*best_bid);
+        let best_ask = price_map_index_to_price(*best_ask);
+        let bid_amount = bid_level.amount();
+        let ask_amount = ask_level.amount();
+        if bid_amount + ask_amount == 0.0 {
+            return self.mid_price();
+        }
+        Some((best_bid * ask_amount + best_ask * bid_amount) / (bid_amount + ask_amount))
     }
 
-    pub fn get_summary(&mut self) -> Result<Summary> {
-        let bids = self.get_bids_reporting_levels()?;
-        let asks = self.get_asks_reporting_levels()?;
-        return Ok(Summary {
-            spread: (self.best_ask_price as f64 / 1_000_000_000.0
-                - self.best_bid_price as f64 / 1_000_000_000.0),
+    // volume imbalance over the top `levels` distinct prices of each side,
+    // 1.0 means only bids, -1.0 only asks
+    pub fn imbalance(&self, levels: usize) -> Option<f64> {
+        let bid_volume: f64 = self
+            .bid_prices_reference
+            .values()
+            .rev()
+            .take(levels)
+            .map(PriceLevel::amount)
+            .sum();
+        let ask_volume: f64 = self
+            .ask_prices_reference
+            .values()
+            .take(levels)
+            .map(PriceLevel::amount)
+            .sum();
+        if bid_volume + ask_volume == 0.0 {
+            return None;
+        }
+        Some((bid_volume - ask_volume) / (bid_volume + ask_volume))
+    }
+
+    // cumulative (bid, ask) amounts with a price within `bps` basis points of mid
+    pub fn depth_within_bps(&self, bps: f64) -> Option<(f64, f64)> {
+        let mid = self.mid_price()?;
+        let lowest_bid = price_to_price_map_index(mid * (1.0 - bps / 10_000.0));
+        let highest_ask = price_to_price_map_index(mid * (1.0 + bps / 10_000.0));
+        let bid_depth = self
+            .bid_prices_reference
+            .range(lowest_bid..)
+            .map(|(_, price_level)| price_level.amount())
+            .sum();
+        let ask_depth = self
+            .ask_prices_reference
+            .range(..=highest_ask)
+            .map(|(_, price_level)| price_level.amount())
+            .sum();
+        Some((bid_depth, ask_depth))
+    }
+
+    pub fn get_analytics(&self, depth_bps: f64) -> Option<Analytics> {
+        let (bid_depth, ask_depth) = self.depth_within_bps(depth_bps)?;
+        Some(Analytics {
+            mid_price: self.mid_price()?,
+            microprice: self.microprice()?,
+            imbalance: self
+                .imbalance(self.reporting_levels as usize)
+                .unwrap_or(0.0),
+            depth_bps,
+            bid_depth,
+            ask_depth,
+        })
+    }
+
+    pub fn get_summary(&self) -> Result<Summary> {
+        let (bids, asks) = if self.consolidated {
+            (
+                self.get_consolidated_bids_reporting_levels()?,
+                self.get_consolidated_asks_reporting_levels()?,
+            )
+        } else {
+            (
+                self.get_bids_reporting_levels()?,
+                self.get_asks_reporting_levels()?,
+            )
+        };
+        let analytics = self
+            .analytics_depth_bps
+            .and_then(|depth_bps| self.get_analytics(depth_bps));
+        Ok(Summary {
+            spread: (price_map_index_to_price(self.best_ask_price())
+                - price_map_index_to_price(self.best_bid_price())),
             bids,
             asks,
-        });
+            analytics,
+        })
     }
 }
 
@@ -249,146 +814,750 @@ mod tests {
     #[test]
     fn creates_an_orderbook() {
         let snapshots = ParsedUpdate {
+            exchange: ExchangeId::Bitstamp,
+            kind: UpdateKind::Snapshot,
             last_update_id: 100000,
-            bids: vec![Level {
+            first_update_id: None,
+            bids: vec![ParsedLevel {
                 price: 8.0,
                 amount: 1.0,
-                exchange: "BITSTAMP".to_string(),
             }],
-            asks: vec![Level {
+            asks: vec![ParsedLevel {
                 price: 10.0,
                 amount: 1.0,
-                exchange: "BITSTAMP".to_string(),
             }],
         };
         let ob = OrderBook::new(5, snapshots).unwrap();
-        assert_eq!(ob.best_ask_price, price_to_price_map_index(10.0));
-        assert_eq!(ob.best_bid_price, price_to_price_map_index(8.0));
+        assert_eq!(ob.best_ask_price(), price_to_price_map_index(10.0));
+        assert_eq!(ob.best_bid_price(), price_to_price_map_index(8.0));
     }
 
     #[test]
     fn creates_an_orderbook_and_deletes_best_bid_ask() {
         let snapshots = ParsedUpdate {
+            exchange: ExchangeId::Bitstamp,
+            kind: UpdateKind::Snapshot,
             last_update_id: 100000, // make it newer update
+            first_update_id: None,
             bids: vec![
-                Level {
+                ParsedLevel {
                     price: 7.0,
                     amount: 1.0,
-                    exchange: "BITSTAMP".to_string(),
                 },
-                Level {
+                ParsedLevel {
                     price: 8.0,
                     amount: 1.0,
-                    exchange: "BITSTAMP".to_string(),
                 },
             ],
             asks: vec![
-                Level {
+                ParsedLevel {
                     price: 10.0,
                     amount: 1.0,
-                    exchange: "BITSTAMP".to_string(),
                 },
-                Level {
+                ParsedLevel {
                     price: 11.00,
                     amount: 1.0,
-                    exchange: "BITSTAMP".to_string(),
                 },
             ],
         };
         let mut ob = OrderBook::new(5, snapshots).unwrap();
         let new_update = ParsedUpdate {
+            exchange: ExchangeId::Bitstamp,
+            kind: UpdateKind::Diff,
             last_update_id: 110000,
-            bids: vec![Level {
+            first_update_id: None,
+            bids: vec![ParsedLevel {
                 price: 8.0,
                 amount: 0.0,
-                exchange: "BITSTAMP".to_string(),
             }],
-            asks: vec![Level {
+            asks: vec![ParsedLevel {
                 price: 10.0,
                 amount: 0.0,
-                exchange: "BITSTAMP".to_string(),
             }],
         };
         ob.merge_parse_update(new_update)
             .expect("broken merge update");
-        assert_eq!(ob.best_bid_price, price_to_price_map_index(7.0));
-        assert_eq!(ob.best_ask_price, price_to_price_map_index(11.0));
+        assert_eq!(ob.best_bid_price(), price_to_price_map_index(7.0));
+        assert_eq!(ob.best_ask_price(), price_to_price_map_index(11.0));
+    }
+
+    #[test]
+    fn keeps_tiny_non_zero_amounts() {
+        let snapshot = ParsedUpdate {
+            exchange: ExchangeId::Binance,
+            kind: UpdateKind::Snapshot,
+            last_update_id: 100,
+            first_update_id: None,
+            bids: vec![
+                ParsedLevel {
+                    price: 7.0,
+                    amount: 1.0,
+                },
+                ParsedLevel {
+                    price: 8.0,
+                    amount: 1.0,
+                },
+            ],
+            asks: vec![ParsedLevel {
+                price: 10.0,
+                amount: 1.0,
+            }],
+        };
+        let mut ob = OrderBook::new(5, snapshot).unwrap();
+        // a dust amount is a level, only a literal zero deletes it
+        ob.merge_parse_update(ParsedUpdate {
+            exchange: ExchangeId::Binance,
+            kind: UpdateKind::Diff,
+            last_update_id: 101,
+            first_update_id: Some(101),
+            bids: vec![ParsedLevel {
+                price: 8.0,
+                amount: 1e-9,
+            }],
+            asks: vec![ParsedLevel {
+                price: 9.0,
+                amount: 1e-12,
+            }],
+        })
+        .expect("broken merge update");
+        assert_eq!(ob.best_bid_price(), price_to_price_map_index(8.0));
+        assert_eq!(ob.best_ask_price(), price_to_price_map_index(9.0));
+        let bids = ob.get_bids_reporting_levels().unwrap();
+        let asks = ob.get_asks_reporting_levels().unwrap();
+        assert_eq!((bids[0].price, bids[0].amount), (8.0, 1e-9));
+        assert_eq!((asks[0].price, asks[0].amount), (9.0, 1e-12));
     }
 
     #[test]
     fn creates_an_orderbook_and_adds_best_bid() {
         let snapshots = ParsedUpdate {
+            exchange: ExchangeId::Bitstamp,
+            kind: UpdateKind::Snapshot,
             last_update_id: 100000,
+            first_update_id: None,
             bids: vec![
-                Level {
+                ParsedLevel {
                     price: 7.0,
                     amount: 1.0,
-                    exchange: "BITSTAMP".to_string(),
                 },
-                Level {
+                ParsedLevel {
                     price: 8.0,
                     amount: 1.0,
-                    exchange: "BITSTAMP".to_string(),
                 },
             ],
-            asks: vec![Level {
+            asks: vec![ParsedLevel {
                 price: 10.0,
                 amount: 1.0,
-                exchange: "BITSTAMP".to_string(),
             }],
         };
         let mut ob = OrderBook::new(5, snapshots).unwrap();
         let new_update = ParsedUpdate {
+            exchange: ExchangeId::Bitstamp,
+            kind: UpdateKind::Diff,
             last_update_id: 110000,
-            bids: vec![Level {
+            first_update_id: None,
+            bids: vec![ParsedLevel {
                 price: 9.0,
                 amount: 1.0,
-                exchange: "BITSTAMP".to_string(),
             }],
-            asks: vec![Level {
+            asks: vec![ParsedLevel {
                 price: 11.0,
                 amount: 1.0,
-                exchange: "BITSTAMP".to_string(),
             }],
         };
         ob.merge_parse_update(new_update)
             .expect("broken merge update");
-        assert_eq!(ob.best_ask_price, price_to_price_map_index(10.0));
-        assert_eq!(ob.best_bid_price, price_to_price_map_index(9.0));
+        assert_eq!(ob.best_ask_price(), price_to_price_map_index(10.0));
+        assert_eq!(ob.best_bid_price(), price_to_price_map_index(9.0));
     }
 
     #[test]
     fn already_received_update() {
         let snapshots = ParsedUpdate {
+            exchange: ExchangeId::Bitstamp,
+            kind: UpdateKind::Snapshot,
             last_update_id: 100000,
-            bids: vec![Level {
+            first_update_id: None,
+            bids: vec![ParsedLevel {
                 price: 8.0,
                 amount: 1.0,
-                exchange: "BITSTAMP".to_string(),
             }],
-            asks: vec![Level {
+            asks: vec![ParsedLevel {
                 price: 10.0,
                 amount: 1.0,
-                exchange: "BITSTAMP".to_string(),
             }],
         };
         let mut ob = OrderBook::new(5, snapshots).unwrap();
         let new_update = ParsedUpdate {
+            exchange: ExchangeId::Bitstamp,
+            kind: UpdateKind::Diff,
             last_update_id: 9000,
-            bids: vec![Level {
+            first_update_id: None,
+            bids: vec![ParsedLevel {
                 price: 9.0,
                 amount: 1.0,
-                exchange: "BITSTAMP".to_string(),
             }],
-            asks: vec![Level {
+            asks: vec![ParsedLevel {
                 price: 11.0,
                 amount: 1.0,
-                exchange: "BITSTAMP".to_string(),
             }],
         };
         ob.merge_parse_update(new_update)
             .expect("broken merge update");
-        assert_eq!(ob.best_ask_price, price_to_price_map_index(10.0));
-        assert_eq!(ob.best_bid_price, price_to_price_map_index(8.0));
+        assert_eq!(ob.best_ask_price(), price_to_price_map_index(10.0));
+        assert_eq!(ob.best_bid_price(), price_to_price_map_index(8.0));
+    }
+
+    #[test]
+    fn consolidated_summary_merges_venues_at_same_price() {
+        let snapshots = ParsedUpdate {
+            exchange: ExchangeId::Binance,
+            kind: UpdateKind::Snapshot,
+            last_update_id: 100000,
+            first_update_id: None,
+            bids: vec![
+                ParsedLevel {
+                    price: 8.0,
+                    amount: 1.0,
+                },
+                ParsedLevel {
+                    price: 7.0,
+                    amount: 1.0,
+                },
+            ],
+            asks: vec![ParsedLevel {
+                price: 10.0,
+                amount: 1.0,
+            }],
+        };
+        let mut ob = OrderBook::new(1, snapshots).unwrap();
+        let bitstamp_update = ParsedUpdate {
+            exchange: ExchangeId::Bitstamp,
+            kind: UpdateKind::Snapshot,
+            last_update_id: 100000,
+            first_update_id: None,
+            bids: vec![ParsedLevel {
+                price: 8.0,
+                amount: 2.5,
+            }],
+            asks: vec![],
+        };
+        ob.merge_parse_update(bitstamp_update)
+            .expect("broken merge update");
+
+        // per exchange mode spends the single level on one of the duplicates
+        let summary = ob.get_summary().unwrap();
+        assert_eq!(summary.bids.len(), 1);
+        assert_eq!(summary.bids[0].exchange, "BITSTAMP");
+
+        ob.consolidated = true;
+        ob.reporting_levels = 2;
+        let summary = ob.get_summary().unwrap();
+        assert_eq!(summary.bids.len(), 2);
+        assert_eq!(summary.bids[0].price, 8.0);
+        assert_eq!(summary.bids[0].amount, 3.5);
+        assert_eq!(summary.bids[0].exchange, "");
+        assert_eq!(
+            summary.bids[0].venues,
+            vec![
+                VenueAmount {
+                    exchange: "BITSTAMP".into(),
+                    amount: 2.5,
+                },
+                VenueAmount {
+                    exchange: "BINANCE".into(),
+                    amount: 1.0,
+                },
+            ]
+        );
+        assert_eq!(summary.bids[1].price, 7.0);
+        assert_eq!(summary.asks.len(), 1);
+        assert_eq!(summary.asks[0].amount, 1.0);
+    }
+
+    #[test]
+    fn computes_analytics_on_merged_book() {
+        let snapshots = ParsedUpdate {
+            exchange: ExchangeId::Binance,
+            kind: UpdateKind::Snapshot,
+            last_update_id: 100000,
+            first_update_id: None,
+            bids: vec![
+                ParsedLevel {
+                    price: 99.0,
+                    amount: 3.0,
+                },
+                ParsedLevel {
+                    price: 98.0,
+                    amount: 5.0,
+                },
+            ],
+            asks: vec![
+                ParsedLevel {
+                    price: 101.0,
+                    amount: 1.0,
+                },
+                ParsedLevel {
+                    price: 103.0,
+                    amount: 2.0,
+                },
+            ],
+        };
+        let mut ob = OrderBook::new(1, snapshots).unwrap();
+
+        assert_eq!(ob.mid_price(), Some(100.0));
+        // 3 on the bid against 1 on the ask pushes the microprice up to the ask
+        assert_eq!(ob.microprice(), Some((99.0 * 1.0 + 101.0 * 3.0) / 4.0));
+        assert_eq!(ob.imbalance(1), Some(0.5));
+        assert_eq!(ob.imbalance(2), Some((8.0 - 3.0) / 11.0));
+        // 150 bps around 100 is [98.5, 101.5]
+        assert_eq!(ob.depth_within_bps(150.0), Some((3.0, 1.0)));
+        assert_eq!(ob.depth_within_bps(300.0), Some((8.0, 3.0)));
+
+        assert!(ob.get_summary().unwrap().analytics.is_none());
+        ob.analytics_depth_bps = Some(150.0);
+        let analytics = ob.get_summary().unwrap().analytics.unwrap();
+        assert_eq!(analytics.mid_price, 100.0);
+        assert_eq!(analytics.imbalance, 0.5);
+        assert_eq!(analytics.bid_depth, 3.0);
+        assert_eq!(analytics.ask_depth, 1.0);
+    }
+
+    #[test]
+    fn removes_empty_price_levels() {
+        let snapshots = ParsedUpdate {
+            exchange: ExchangeId::Binance,
+            kind: UpdateKind::Snapshot,
+            last_update_id: 100000,
+            first_update_id: None,
+            bids: vec![
+                ParsedLevel {
+                    price: 8.0,
+                    amount: 1.0,
+                },
+                ParsedLevel {
+                    price: 7.0,
+                    amount: 1.0,
+                },
+            ],
+            asks: vec![ParsedLevel {
+                price: 10.0,
+                amount: 1.0,
+            }],
+        };
+        let mut ob = OrderBook::new(5, snapshots).unwrap();
+        let new_update = ParsedUpdate {
+            exchange: ExchangeId::Binance,
+            kind: UpdateKind::Diff,
+            last_update_id: 110000,
+            first_update_id: None,
+            bids: vec![
+                ParsedLevel {
+                    price: 8.0,
+                    amount: 0.0,
+                },
+                // deleting a price that was never there must not create it
+                ParsedLevel {
+                    price: 6.0,
+                    amount: 0.0,
+                },
+            ],
+            asks: vec![ParsedLevel {
+                price: 10.0,
+                amount: 0.0,
+            }],
+        };
+        ob.merge_parse_update(new_update)
+            .expect("broken merge update");
+        assert_eq!(ob.bid_prices_reference.len(), 1);
+        assert!(ob.ask_prices_reference.is_empty());
+        assert_eq!(ob.best_bid_price(), price_to_price_map_index(7.0));
+        assert_eq!(ob.best_ask_price(), usize::MAX);
+        assert_eq!(ob.mid_price(), None);
+    }
+
+    #[test]
+    fn empty_diffs_advance_sequence() {
+        let snapshots = ParsedUpdate {
+            exchange: ExchangeId::Binance,
+            kind: UpdateKind::Snapshot,
+            last_update_id: 100000,
+            first_update_id: None,
+            bids: vec![ParsedLevel {
+                price: 8.0,
+                amount: 1.0,
+            }],
+            asks: vec![ParsedLevel {
+                price: 10.0,
+                amount: 1.0,
+            }],
+        };
+        let mut ob = OrderBook::new(5, snapshots).unwrap();
+        for exchange in ExchangeId::ALL {
+            let empty_update = ParsedUpdate {
+                exchange,
+                kind: UpdateKind::Diff,
+                last_update_id: 110000,
+                first_update_id: None,
+                bids: vec![],
+                asks: vec![],
+            };
+            ob.merge_parse_update(empty_update)
+                .expect("broken merge update");
+            assert_eq!(ob.last_update_ids[&exchange], 110000);
+        }
+        // an older diff after the empty one is still dropped
+        let old_update = ParsedUpdate {
+            exchange: ExchangeId::Binance,
+            kind: UpdateKind::Diff,
+            last_update_id: 105000,
+            first_update_id: None,
+            bids: vec![ParsedLevel {
+                price: 9.0,
+                amount: 1.0,
+            }],
+            asks: vec![],
+        };
+        let applied = ob
+            .merge_parse_update(old_update)
+            .expect("broken merge update");
+        assert!(!applied);
+        assert_eq!(ob.best_bid_price(), price_to_price_map_index(8.0));
+        assert_eq!(ob.best_ask_price(), price_to_price_map_index(10.0));
+    }
+
+    #[test]
+    fn rejects_binance_diffs_after_a_gap() {
+        let snapshot = ParsedUpdate {
+            exchange: ExchangeId::Binance,
+            kind: UpdateKind::Snapshot,
+            last_update_id: 100,
+            first_update_id: None,
+            bids: vec![ParsedLevel {
+                price: 8.0,
+                amount: 1.0,
+            }],
+            asks: vec![],
+        };
+        let mut ob = OrderBook::new(5, snapshot).unwrap();
+        let diff = |first_update_id, last_update_id, price| ParsedUpdate {
+            exchange: ExchangeId::Binance,
+            kind: UpdateKind::Diff,
+            last_update_id,
+            first_update_id: Some(first_update_id),
+            bids: vec![ParsedLevel { price, amount: 1.0 }],
+            asks: vec![],
+        };
+        // straddling the snapshot is fine, the diff right after it too
+        assert!(ob.merge_parse_update(diff(95, 105, 8.5)).unwrap());
+        assert!(ob.merge_parse_update(diff(106, 110, 8.6)).unwrap());
+        let err = ob.merge_parse_update(diff(112, 115, 9.0)).unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<SequenceGap>(),
+            Some(&SequenceGap {
+                exchange: ExchangeId::Binance,
+                last_update_id: 110,
+                first_update_id: 112,
+            })
+        );
+        assert_eq!(ob.last_update_ids[&ExchangeId::Binance], 110);
+        assert_eq!(ob.best_bid_price(), price_to_price_map_index(8.6));
+    }
+
+    #[test]
+    fn snapshot_replaces_only_its_exchange() {
+        let snapshots = ParsedUpdate {
+            exchange: ExchangeId::Binance,
+            kind: UpdateKind::Snapshot,
+            last_update_id: 100,
+            first_update_id: None,
+            bids: vec![
+                ParsedLevel {
+                    price: 8.0,
+                    amount: 1.0,
+                },
+                ParsedLevel {
+                    price: 7.0,
+                    amount: 1.0,
+                },
+            ],
+            asks: vec![ParsedLevel {
+                price: 10.0,
+                amount: 1.0,
+            }],
+        };
+        let mut ob = OrderBook::new(5, snapshots).unwrap();
+        let bitstamp_snapshot = ParsedUpdate {
+            exchange: ExchangeId::Bitstamp,
+            kind: UpdateKind::Snapshot,
+            last_update_id: 1687000000123456,
+            first_update_id: None,
+            bids: vec![ParsedLevel {
+                price: 8.0,
+                amount: 2.0,
+            }],
+            asks: vec![ParsedLevel {
+                price: 11.0,
+                amount: 2.0,
+            }],
+        };
+        ob.merge_parse_update(bitstamp_snapshot)
+            .expect("broken snapshot");
+
+        let new_update = ParsedUpdate {
+            exchange: ExchangeId::Binance,
+            kind: UpdateKind::Diff,
+            last_update_id: 150,
+            first_update_id: None,
+            bids: vec![ParsedLevel {
+                price: 8.5,
+                amount: 1.0,
+            }],
+            asks: vec![],
+        };
+        ob.merge_parse_update(new_update)
+            .expect("broken merge update");
+        // a resync snapshot without the 8.0 and 8.5 bids and the 10.0 ask,
+        // with an id lower than the diff already received
+        let binance_snapshot = ParsedUpdate {
+            exchange: ExchangeId::Binance,
+            kind: UpdateKind::Snapshot,
+            last_update_id: 120,
+            first_update_id: None,
+            bids: vec![ParsedLevel {
+                price: 7.0,
+                amount: 3.0,
+            }],
+            asks: vec![ParsedLevel {
+                price: 12.0,
+                amount: 3.0,
+            }],
+        };
+        ob.apply_snapshot(binance_snapshot)
+            .expect("broken snapshot");
+
+        let bids = ob.get_bids_reporting_levels().unwrap();
+        let asks = ob.get_asks_reporting_levels().unwrap();
+        let bid_quotes: Vec<(&str, f64, f64)> = bids
+            .iter()
+            .map(|level| (level.exchange.as_ref(), level.price, level.amount))
+            .collect();
+        let ask_quotes: Vec<(&str, f64, f64)> = asks
+            .iter()
+            .map(|level| (level.exchange.as_ref(), level.price, level.amount))
+            .collect();
+        assert_eq!(
+            bid_quotes,
+            vec![("BITSTAMP", 8.0, 2.0), ("BINANCE", 7.0, 3.0)]
+        );
+        assert_eq!(
+            ask_quotes,
+            vec![("BITSTAMP", 11.0, 2.0), ("BINANCE", 12.0, 3.0)]
+        );
+        assert_eq!(ob.last_update_ids[&ExchangeId::Binance], 120);
+        assert_eq!(ob.last_update_ids[&ExchangeId::Bitstamp], 1687000000123456);
+
+        // diffs are checked against the snapshot id from now on
+        let already_in_snapshot = ParsedUpdate {
+            exchange: ExchangeId::Binance,
+            kind: UpdateKind::Diff,
+            last_update_id: 119,
+            first_update_id: None,
+            bids: vec![ParsedLevel {
+                price: 9.0,
+                amount: 1.0,
+            }],
+            asks: vec![],
+        };
+        ob.merge_parse_update(already_in_snapshot)
+            .expect("broken merge update");
+        assert_eq!(ob.best_bid_price(), price_to_price_map_index(8.0));
+    }
+
+    fn ladder(prices: &[f64]) -> Vec<ParsedLevel> {
+        prices
+            .iter()
+            .map(|price| ParsedLevel {
+                price: *price,
+                amount: 1.0,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn caps_depth_per_exchange() {
+        let snapshots = ParsedUpdate {
+            exchange: ExchangeId::Binance,
+            kind: UpdateKind::Snapshot,
+            last_update_id: 100,
+            first_update_id: None,
+            bids: ladder(&[9.0, 8.0, 7.0, 6.0, 5.0]),
+            asks: ladder(&[11.0, 12.0, 13.0, 14.0, 15.0]),
+        };
+        let mut ob = OrderBook::new(10, snapshots).unwrap();
+        ob.max_depth_per_exchange = Some(3);
+        ob.prune();
+        assert_eq!(ob.exchange_depth(ExchangeId::Binance), (3, 3));
+        assert_eq!(ob.bid_prices_reference.len(), 3);
+        assert_eq!(
+            ob.ask_prices_reference.keys().next_back(),
+            Some(&price_to_price_map_index(13.0))
+        );
+
+        // the cap is per exchange, bitstamp keeps its own 3 levels
+        let bitstamp_snapshot = ParsedUpdate {
+            exchange: ExchangeId::Bitstamp,
+            kind: UpdateKind::Snapshot,
+            last_update_id: 100,
+            first_update_id: None,
+            bids: ladder(&[8.0, 4.0, 3.0, 2.0]),
+            asks: ladder(&[16.0]),
+        };
+        ob.apply_snapshot(bitstamp_snapshot).unwrap();
+        assert_eq!(ob.exchange_depth(ExchangeId::Bitstamp), (3, 1));
+        assert_eq!(ob.exchange_depth(ExchangeId::Binance), (3, 3));
+
+        // the pruned levels are not known anymore, an update of one is ignored
+        let diff = |last_update_id, bids| ParsedUpdate {
+            exchange: ExchangeId::Binance,
+            kind: UpdateKind::Diff,
+            last_update_id,
+            first_update_id: None,
+            bids,
+            asks: vec![],
+        };
+        let deleted = |price| ParsedLevel { price, amount: 0.0 };
+        let updated = ParsedLevel {
+            price: 6.0,
+            amount: 2.0,
+        };
+        ob.merge_parse_update(diff(101, vec![deleted(9.0), updated]))
+            .unwrap();
+        let binance_bids: Vec<f64> = ob
+            .bids_iter()
+            .filter(|level| level.exchange == ExchangeId::Binance)
+            .map(|level| level.price)
+            .collect();
+        assert_eq!(binance_bids, vec![8.0, 7.0]);
+        assert_eq!(ob.take_resync(), None);
+
+        // under half the cap the missing levels have to come back from a snapshot
+        ob.merge_parse_update(diff(102, vec![deleted(8.0)]))
+            .unwrap();
+        assert_eq!(ob.exchange_depth(ExchangeId::Binance), (1, 3));
+        assert_eq!(ob.take_resync(), Some(ExchangeId::Binance));
+        assert_eq!(ob.take_resync(), None);
+        let resync = ParsedUpdate {
+            exchange: ExchangeId::Binance,
+            kind: UpdateKind::Snapshot,
+            last_update_id: 103,
+            first_update_id: None,
+            bids: ladder(&[7.0, 6.0, 5.0, 4.0]),
+            asks: ladder(&[11.0, 12.0, 13.0]),
+        };
+        ob.merge_parse_update(resync).unwrap();
+        assert_eq!(ob.exchange_depth(ExchangeId::Binance), (3, 3));
+        assert_eq!(ob.take_resync(), None);
+    }
+
+    #[test]
+    fn prunes_outside_price_band_and_takes_levels_back() {
+        let snapshots = ParsedUpdate {
+            exchange: ExchangeId::Binance,
+            kind: UpdateKind::Snapshot,
+            last_update_id: 100,
+            first_update_id: None,
+            bids: ladder(&[99.0, 95.0, 80.0]),
+            asks: ladder(&[101.0, 105.0, 120.0]),
+        };
+        let mut ob = OrderBook::new(10, snapshots).unwrap();
+        // 10% around a mid of 100
+        ob.price_band_bps = Some(1000.0);
+        ob.prune();
+        assert_eq!(ob.exchange_depth(ExchangeId::Binance), (2, 2));
+        assert_eq!(ob.bid_prices_reference.len(), 2);
+        assert_eq!(ob.ask_prices_reference.len(), 2);
+
+        let diff = |last_update_id, bids, asks| ParsedUpdate {
+            exchange: ExchangeId::Binance,
+            kind: UpdateKind::Diff,
+            last_update_id,
+            first_update_id: None,
+            bids,
+            asks,
+        };
+        let level = |price, amount| ParsedLevel { price, amount };
+        // the market moves down, mid is now 85. The pruned 80.0 bid is back in the band
+        // but still more than 5% away, an update of it is ignored as it is not known
+        ob.merge_parse_update(diff(
+            101,
+            vec![
+                level(99.0, 0.0),
+                level(95.0, 0.0),
+                level(84.0, 1.0),
+                level(80.0, 3.0),
+            ],
+            vec![level(101.0, 0.0), level(86.0, 1.0)],
+        ))
+        .unwrap();
+        assert_eq!(ob.mid_price(), Some(85.0));
+        let bids: Vec<f64> = ob.bids_iter().map(|level| level.price).collect();
+        assert_eq!(bids, vec![84.0]);
+        // 105.0 is now more than 10% away from 85
+        let asks: Vec<f64> = ob.asks_iter().map(|level| level.price).collect();
+        assert_eq!(asks, vec![86.0]);
+        assert_eq!(ob.take_resync(), None);
+
+        // mid 84, 80.0 is within 5% and the exchange is taken again from a snapshot
+        ob.merge_parse_update(diff(102, vec![level(84.0, 0.0), level(82.0, 1.0)], vec![]))
+            .unwrap();
+        assert_eq!(ob.take_resync(), Some(ExchangeId::Binance));
+        ob.merge_parse_update(ParsedUpdate {
+            exchange: ExchangeId::Binance,
+            kind: UpdateKind::Snapshot,
+            last_update_id: 103,
+            first_update_id: None,
+            bids: ladder(&[82.0, 80.0]),
+            asks: ladder(&[84.0, 105.0]),
+        })
+        .unwrap();
+        let bids: Vec<f64> = ob.bids_iter().map(|level| level.price).collect();
+        assert_eq!(bids, vec![82.0, 80.0]);
+        assert_eq!(ob.take_resync(), None);
+    }
+
+    #[test]
+    fn exchange_snapshot_round_trips() {
+        let mut order_book = OrderBook::new(
+            10,
+            ParsedUpdate {
+                exchange: ExchangeId::Binance,
+                kind: UpdateKind::Snapshot,
+                bids: ladder(&[9.0, 8.0]),
+                asks: ladder(&[10.0, 11.0]),
+                last_update_id: 5,
+                first_update_id: None,
+            },
+        )
+        .unwrap();
+        order_book
+            .apply_snapshot(ParsedUpdate {
+                exchange: ExchangeId::Bitstamp,
+                kind: UpdateKind::Snapshot,
+                bids: ladder(&[9.0, 7.0]),
+                asks: ladder(&[10.5]),
+                last_update_id: 7,
+                first_update_id: None,
+            })
+            .unwrap();
+
+        let snapshot = order_book.exchange_snapshot(ExchangeId::Bitstamp);
+        assert_eq!(snapshot.last_update_id, 7);
+        assert_eq!(snapshot.bids, ladder(&[9.0, 7.0]));
+        assert_eq!(snapshot.asks, ladder(&[10.5]));
+
+        let summary = order_book.get_summary().unwrap();
+        order_book.apply_snapshot(snapshot).unwrap();
+        assert_eq!(order_book.get_summary().unwrap(), summary);
     }
 }
@@ -0,0 +1,103 @@
+use std::collections::VecDeque;
+use std::time::Duration;
+use tokio::sync::Mutex;
+use tokio::time::{self, Instant};
+
+// request weights spent over a sliding window, requests wait for their weight to fit
+// in the budget so that many symbols bootstrapping at once are spread out instead of
+// getting the IP banned
+pub struct WeightLimiter {
+    budget: u32,
+    window: Duration,
+    // weights spent in the window, oldest first. Waiters queue on the lock in order
+    spent: Mutex<VecDeque<(Instant, u32)>>,
+}
+
+impl WeightLimiter {
+    pub fn new(budget: u32, window: Duration) -> Self {
+        WeightLimiter {
+            budget,
+            window,
+            spent: Mutex::new(VecDeque::new()),
+        }
+    }
+
+    // waits until `weight` fits in the window and counts it as spent. A request heavier
+    // than the whole budget only waits for the window to be empty
+    pub async fn acquire(&self, weight: u32) {
+        let mut spent = self.spent.lock().await;
+        loop {
+            let now = Instant::now();
+            while spent
+                .front()
+                .is_some_and(|(at, _)| now.duration_since(*at) >= self.window)
+            {
+                spent.pop_front();
+            }
+            let used: u32 = spent.iter().map(|(_, weight)| weight).sum();
+            if spent.is_empty() || used + weight <= self.budget {
+                spent.push_back((now, weight));
+                return;
+            }
+            let (oldest, _) = spent[0];
+            tracing::info!(
+                "request weight {} over the budget ({} of {} used), waiting",
+                weight,
+                used,
+                self.budget
+            );
+            time::sleep_until(oldest + self.window).await;
+        }
+    }
+
+    // the exchange counted `used` in its own window (other clients on the same IP,
+    // requests before a restart), the difference is spent now
+    pub async fn record_used(&self, used: u32) {
+        let now = Instant::now();
+        let mut spent = self.spent.lock().await;
+        let tracked: u32 = spent
+            .iter()
+            .filter(|(at, _)| now.duration_since(*at) < self.window)
+            .map(|(_, weight)| weight)
+            .sum();
+        if used > tracked {
+            spent.push_back((now, used - tracked));
+        }
+    }
+
+    // weight spent in the current window
+    pub async fn used(&self) -> u32 {
+        let now = Instant::now();
+        self.spent
+            .lock()
+            .await
+            .iter()
+            .filter(|(at, _)| now.duration_since(*at) < self.window)
+            .map(|(_, weight)| weight)
+            .sum()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn waits_for_the_weight_to_fit_in_the_window() {
+        let limiter = WeightLimiter::new(100, Duration::from_millis(200));
+        let start = Instant::now();
+        limiter.acquire(50).await;
+        limiter.acquire(50).await;
+        assert_eq!(limiter.used().await, 100);
+        assert!(start.elapsed() < Duration::from_millis(100));
+
+        // has to wait for the first two to leave the window
+        limiter.acquire(60).await;
+        assert!(start.elapsed() >= Duration::from_millis(200));
+        assert_eq!(limiter.used().await, 60);
+
+        // heavier than the budget, goes through once the window is empty
+        limiter.acquire(250).await;
+        assert!(start.elapsed() >= Duration::from_millis(400));
+    }
+}
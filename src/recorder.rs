@@ -0,0 +1,681 @@
+use crate::exchanges::{now_micros, ExchangeId, ParsedLevel, ParsedUpdate, UpdateKind};
+use crate::orderbook::{BookConfig, OrderBook};
+use anyhow::{anyhow, Context, Result};
+use std::fs::File;
+use std::io::{BufReader, BufWriter, ErrorKind, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+
+// L2 log format, everything little endian:
+//   header: magic "OBL2", version u16, symbol length u16, symbol bytes, start time u64,
+//           reporting levels u32, max depth per exchange u64, price band bps f64
+//           (the bounds of the recorded book, 0 when not set)
+//   record: timestamp u64, kind u8, exchange u8, last_update_id u64,
+//           bids count u32, asks count u32, then (price f64, amount f64) for bids and asks
+// timestamps are microseconds since epoch (local receive time)
+// records are rows, one per update, rather than columns: replay applies them one by one
+// in the order they were merged and seeks to a checkpoint by its offset, which a
+// columnar layout would only make more expensive to write and to read back
+//
+// next to it, {file}.idx indexes the checkpoints: (timestamp u64, offset u64) for each
+// group of checkpoints, offset being where the first of them starts in the L2 file
+const MAGIC: &[u8; 4] = b"OBL2";
+const VERSION: u16 = 2;
+pub const FILE_EXTENSION: &str = "l2";
+const INDEX_EXTENSION: &str = "l2.idx";
+
+// updates waiting for the file writer before the recording is given up
+const RECORDER_CHANNEL_CAPACITY: usize = 4096;
+
+// diffs and snapshots are the updates handed to the book as received, checkpoints
+// are written by the recorder itself and hold the whole book of one exchange
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RecordKind {
+    Diff,
+    Snapshot,
+    Checkpoint,
+}
+
+impl RecordKind {
+    fn code(&self) -> u8 {
+        match self {
+            RecordKind::Diff => 0,
+            RecordKind::Snapshot => 1,
+            RecordKind::Checkpoint => 2,
+        }
+    }
+
+    fn from_code(code: u8) -> Result<Self> {
+        match code {
+            0 => Ok(RecordKind::Diff),
+            1 => Ok(RecordKind::Snapshot),
+            2 => Ok(RecordKind::Checkpoint),
+            _ => Err(anyhow!("unknown record kind {}", code)),
+        }
+    }
+}
+
+fn exchange_code(exchange: ExchangeId) -> u8 {
+    match exchange {
+        ExchangeId::Binance => 0,
+        ExchangeId::Bitstamp => 1,
+    }
+}
+
+fn exchange_from_code(code: u8) -> Result<ExchangeId> {
+    match code {
+        0 => Ok(ExchangeId::Binance),
+        1 => Ok(ExchangeId::Bitstamp),
+        _ => Err(anyhow!("unknown exchange code {}", code)),
+    }
+}
+
+#[derive(Debug)]
+pub struct Record {
+    pub timestamp: u64,
+    pub kind: RecordKind,
+    pub update: ParsedUpdate,
+}
+
+// the checkpoint index of a recording, {dir}/{symbol}-{start micros}.l2.idx
+pub fn index_path(path: &Path) -> PathBuf {
+    path.with_extension(INDEX_EXTENSION)
+}
+
+// writes every update given to the book of one symbol, plus a checkpoint of each
+// exchange every `checkpoint_interval` microseconds. With an index, a reader can start
+// from the last checkpoint before the time it wants instead of the beginning of the file
+pub struct Recorder<W: Write> {
+    writer: W,
+    index: Option<W>,
+    // bytes written so far, the offset of the next record
+    position: u64,
+    checkpoint_interval: u64,
+    last_checkpoint: Option<u64>,
+}
+
+impl<W: Write> Recorder<W> {
+    pub fn new(
+        writer: W,
+        symbol: &str,
+        start: u64,
+        config: BookConfig,
+        checkpoint_interval_secs: u64,
+    ) -> Result<Self> {
+        let mut recorder = Recorder {
+            writer,
+            index: None,
+            position: 0,
+            checkpoint_interval: checkpoint_interval_secs * 1_000_000,
+            last_checkpoint: None,
+        };
+        recorder.write(MAGIC)?;
+        recorder.write(&VERSION.to_le_bytes())?;
+        let symbol_len = u16::try_from(symbol.len()).context("symbol too long to record")?;
+        recorder.write(&symbol_len.to_le_bytes())?;
+        recorder.write(symbol.as_bytes())?;
+        recorder.write(&start.to_le_bytes())?;
+        recorder.write(&config.reporting_levels.to_le_bytes())?;
+        let max_depth = config.max_depth_per_exchange.unwrap_or(0) as u64;
+        recorder.write(&max_depth.to_le_bytes())?;
+        recorder.write(&config.price_band_bps.unwrap_or(0.0).to_le_bytes())?;
+        Ok(recorder)
+    }
+
+    pub fn with_index(mut self, index: W) -> Self {
+        self.index = Some(index);
+        self
+    }
+
+    fn write(&mut self, bytes: &[u8]) -> Result<()> {
+        self.writer.write_all(bytes)?;
+        self.position += bytes.len() as u64;
+        Ok(())
+    }
+
+    fn write_record(
+        &mut self,
+        timestamp: u64,
+        kind: RecordKind,
+        parsed_update: &ParsedUpdate,
+    ) -> Result<()> {
+        self.write(&timestamp.to_le_bytes())?;
+        self.write(&[kind.code(), exchange_code(parsed_update.exchange)])?;
+        self.write(&parsed_update.last_update_id.to_le_bytes())?;
+        self.write(&(parsed_update.bids.len() as u32).to_le_bytes())?;
+        self.write(&(parsed_update.asks.len() as u32).to_le_bytes())?;
+        for level in parsed_update.bids.iter().chain(parsed_update.asks.iter()) {
+            self.write(&level.price.to_le_bytes())?;
+            self.write(&level.amount.to_le_bytes())?;
+        }
+        Ok(())
+    }
+
+    // to be called with each update the book applied, in the order it applied them.
+    // Stale diffs and the one that raised a SequenceGap are left out: the book traders
+    // saw never had them, and the ids the gap was detected with are not recorded
+    pub fn record_update(&mut self, timestamp: u64, parsed_update: &ParsedUpdate) -> Result<()> {
+        let kind = match parsed_update.kind {
+            UpdateKind::Diff => RecordKind::Diff,
+            UpdateKind::Snapshot => RecordKind::Snapshot,
+        };
+        self.write_record(timestamp, kind, parsed_update)
+    }
+
+    // true when the last checkpoint is old enough, or there is none yet
+    fn checkpoint_due(&self, timestamp: u64) -> bool {
+        match self.last_checkpoint {
+            Some(last_checkpoint) => timestamp >= last_checkpoint + self.checkpoint_interval,
+            None => true,
+        }
+    }
+
+    // writes the whole book of every exchange when a checkpoint is due
+    pub fn maybe_checkpoint(&mut self, timestamp: u64, order_book: &OrderBook) -> Result<()> {
+        if !self.checkpoint_due(timestamp) {
+            return Ok(());
+        }
+        let snapshots = checkpoint_snapshots(order_book);
+        self.write_checkpoint(timestamp, &snapshots)
+    }
+
+    // one checkpoint record per exchange, indexed as a group. The file is flushed
+    // before the index so that an indexed offset is always in the file
+    pub fn write_checkpoint(&mut self, timestamp: u64, snapshots: &[ParsedUpdate]) -> Result<()> {
+        let offset = self.position;
+        for snapshot in snapshots {
+            self.write_record(timestamp, RecordKind::Checkpoint, snapshot)?;
+        }
+        self.last_checkpoint = Some(timestamp);
+        self.flush()?;
+        if let Some(index) = self.index.as_mut() {
+            index.write_all(&timestamp.to_le_bytes())?;
+            index.write_all(&offset.to_le_bytes())?;
+            index.flush().context("failed to flush recorder index")?;
+        }
+        Ok(())
+    }
+
+    pub fn flush(&mut self) -> Result<()> {
+        self.writer.flush().context("failed to flush recorder")
+    }
+
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+
+    pub fn into_parts(self) -> (W, Option<W>) {
+        (self.writer, self.index)
+    }
+}
+
+fn checkpoint_snapshots(order_book: &OrderBook) -> Vec<ParsedUpdate> {
+    ExchangeId::ALL
+        .iter()
+        .map(|exchange| order_book.exchange_snapshot(*exchange))
+        .collect()
+}
+
+enum RecordCommand {
+    Update(u64, ParsedUpdate),
+    Checkpoint(u64, Vec<ParsedUpdate>),
+}
+
+// records a stream from async code: the file is written by a blocking task fed through
+// a channel so that the disk never holds a tokio worker. The stream only clones what
+// it records, and gives the recording up if the writer falls too far behind
+pub struct FileRecorder {
+    sender: mpsc::Sender<RecordCommand>,
+    writer: JoinHandle<Result<()>>,
+    checkpoint_interval: u64,
+    last_checkpoint: Option<u64>,
+}
+
+impl FileRecorder {
+    // one file per recorded stream, {dir}/{symbol}-{start micros}.l2, and its index
+    pub async fn create(
+        dir: &Path,
+        symbol: &str,
+        config: BookConfig,
+        checkpoint_interval_secs: u64,
+    ) -> Result<Self> {
+        let dir = dir.to_path_buf();
+        let symbol = symbol.to_string();
+        let recorder = tokio::task::spawn_blocking(move || {
+            create_file_recorder(&dir, &symbol, config, checkpoint_interval_secs)
+        })
+        .await??;
+
+        let (sender, receiver) = mpsc::channel(RECORDER_CHANNEL_CAPACITY);
+        let writer = tokio::task::spawn_blocking(move || write_records(recorder, receiver));
+        Ok(FileRecorder {
+            sender,
+            writer,
+            checkpoint_interval: checkpoint_interval_secs * 1_000_000,
+            last_checkpoint: None,
+        })
+    }
+
+    fn send(&mut self, command: RecordCommand) -> Result<()> {
+        self.sender.try_send(command).map_err(|err| match err {
+            mpsc::error::TrySendError::Full(_) => anyhow!("recorder falling behind"),
+            mpsc::error::TrySendError::Closed(_) => anyhow!("recorder writer stopped"),
+        })
+    }
+
+    // with each update the book applied (see Recorder::record_update), the stream
+    // clones it before merging only while it records
+    pub fn record_update(&mut self, timestamp: u64, parsed_update: ParsedUpdate) -> Result<()> {
+        self.send(RecordCommand::Update(timestamp, parsed_update))
+    }
+
+    // the book of every exchange is only copied when a checkpoint is due
+    pub fn maybe_checkpoint(&mut self, timestamp: u64, order_book: &OrderBook) -> Result<()> {
+        if let Some(last_checkpoint) = self.last_checkpoint {
+            if timestamp < last_checkpoint + self.checkpoint_interval {
+                return Ok(());
+            }
+        }
+        self.last_checkpoint = Some(timestamp);
+        self.send(RecordCommand::Checkpoint(
+            timestamp,
+            checkpoint_snapshots(order_book),
+        ))
+    }
+
+    // waits for everything sent so far to be written and flushed
+    pub async fn close(self) -> Result<()> {
+        drop(self.sender);
+        self.writer.await?
+    }
+}
+
+fn create_file_recorder(
+    dir: &Path,
+    symbol: &str,
+    config: BookConfig,
+    checkpoint_interval_secs: u64,
+) -> Result<Recorder<BufWriter<File>>> {
+    std::fs::create_dir_all(dir)
+        .with_context(|| format!("failed to create record dir {}", dir.display()))?;
+    let start = now_micros();
+    let path: PathBuf = dir.join(format!("{}-{}.{}", symbol, start, FILE_EXTENSION));
+    let create = |path: &Path| {
+        File::create(path)
+            .map(BufWriter::new)
+            .with_context(|| format!("failed to create record file {}", path.display()))
+    };
+    let file = create(&path)?;
+    let index = create(&index_path(&path))?;
+    tracing::info!("recording {} to {}", symbol, path.display());
+    Ok(Recorder::new(file, symbol, start, config, checkpoint_interval_secs)?.with_index(index))
+}
+
+// the blocking side of a FileRecorder, until the stream drops or closes it
+fn write_records(
+    mut recorder: Recorder<BufWriter<File>>,
+    mut receiver: mpsc::Receiver<RecordCommand>,
+) -> Result<()> {
+    while let Some(command) = receiver.blocking_recv() {
+        let written = match command {
+            RecordCommand::Update(timestamp, parsed_update) => {
+                recorder.record_update(timestamp, &parsed_update)
+            }
+            RecordCommand::Checkpoint(timestamp, snapshots) => {
+                recorder.write_checkpoint(timestamp, &snapshots)
+            }
+        };
+        if let Err(err) = written {
+            tracing::error!("recording stopped: {:#}", err);
+            return Err(err);
+        }
+    }
+    recorder.flush()
+}
+
+// reads back the records written by a Recorder, oldest first. A record cut short
+// at the end of the file (the process died while writing it) ends the iteration
+pub struct RecordReader<R: Read> {
+    reader: R,
+    symbol: String,
+    start: u64,
+    config: BookConfig,
+}
+
+impl RecordReader<BufReader<File>> {
+    pub fn open(path: &Path) -> Result<Self> {
+        let file = File::open(path)
+            .with_context(|| format!("failed to open record file {}", path.display()))?;
+        RecordReader::new(BufReader::new(file))
+    }
+
+    // positioned on the last group of checkpoints at or before `timestamp` when the
+    // recording has an index, on the first record otherwise
+    pub fn open_at_checkpoint(path: &Path, timestamp: u64) -> Result<Self> {
+        let mut reader = RecordReader::open(path)?;
+        let offset = match std::fs::read(index_path(path)) {
+            Ok(index) => checkpoint_offset(&index, timestamp),
+            Err(err) if err.kind() == ErrorKind::NotFound => None,
+            Err(err) => return Err(err).context("failed to read record index"),
+        };
+        if let Some(offset) = offset {
+            reader.seek(offset)?;
+        }
+        Ok(reader)
+    }
+}
+
+// the offset of the last checkpoint at or before `timestamp` in an index,
+// an entry cut short at the end is left out
+fn checkpoint_offset(index: &[u8], timestamp: u64) -> Option<u64> {
+    index
+        .chunks_exact(16)
+        .map(|entry| {
+            let (checkpoint_time, offset) = entry.split_at(8);
+            (
+                u64::from_le_bytes(checkpoint_time.try_into().unwrap()),
+                u64::from_le_bytes(offset.try_into().unwrap()),
+            )
+        })
+        .take_while(|(checkpoint_time, _)| *checkpoint_time <= timestamp)
+        .last()
+        .map(|(_, offset)| offset)
+}
+
+fn read_array<const N: usize>(reader: &mut impl Read) -> std::io::Result<[u8; N]> {
+    let mut buffer = [0u8; N];
+    reader.read_exact(&mut buffer)?;
+    Ok(buffer)
+}
+
+impl<R: Read> RecordReader<R> {
+    pub fn new(mut reader: R) -> Result<Self> {
+        let magic: [u8; 4] = read_array(&mut reader).context("missing record file header")?;
+        if &magic != MAGIC {
+            return Err(anyhow!("not an L2 record file"));
+        }
+        let version = u16::from_le_bytes(read_array(&mut reader)?);
+        if version != VERSION {
+            return Err(anyhow!("unsupported L2 record version {}", version));
+        }
+        let symbol_len = u16::from_le_bytes(read_array(&mut reader)?) as usize;
+        let mut symbol = vec![0u8; symbol_len];
+        reader.read_exact(&mut symbol)?;
+        let symbol = String::from_utf8(symbol).context("record symbol is not utf8")?;
+        let start = u64::from_le_bytes(read_array(&mut reader)?);
+        let reporting_levels = u32::from_le_bytes(read_array(&mut reader)?);
+        let max_depth = u64::from_le_bytes(read_array(&mut reader)?);
+        let price_band_bps = f64::from_le_bytes(read_array(&mut reader)?);
+        let config = BookConfig {
+            reporting_levels,
+            max_depth_per_exchange: (max_depth > 0).then_some(max_depth as usize),
+            price_band_bps: (price_band_bps > 0.0).then_some(price_band_bps),
+        };
+        Ok(RecordReader {
+            reader,
+            symbol,
+            start,
+            config,
+        })
+    }
+
+    // the settings of the recorded book
+    pub fn config(&self) -> BookConfig {
+        self.config
+    }
+
+    pub fn symbol(&self) -> &str {
+        &self.symbol
+    }
+
+    // microseconds since epoch when the recording started
+    pub fn start(&self) -> u64 {
+        self.start
+    }
+
+    fn read_levels(&mut self, count: u32) -> std::io::Result<Vec<ParsedLevel>> {
+        (0..count)
+            .map(|_| {
+                Ok(ParsedLevel {
+                    price: f64::from_le_bytes(read_array(&mut self.reader)?),
+                    amount: f64::from_le_bytes(read_array(&mut self.reader)?),
+                })
+            })
+            .collect()
+    }
+
+    fn read_record(&mut self) -> Result<Record> {
+        let timestamp = u64::from_le_bytes(read_array(&mut self.reader)?);
+        let [kind, exchange] = read_array(&mut self.reader)?;
+        let kind = RecordKind::from_code(kind)?;
+        let exchange = exchange_from_code(exchange)?;
+        let last_update_id = u64::from_le_bytes(read_array(&mut self.reader)?);
+        let bids_count = u32::from_le_bytes(read_array(&mut self.reader)?);
+        let asks_count = u32::from_le_bytes(read_array(&mut self.reader)?);
+        let bids = self.read_levels(bids_count)?;
+        let asks = self.read_levels(asks_count)?;
+        Ok(Record {
+            timestamp,
+            kind,
+            update: ParsedUpdate {
+                exchange,
+                kind: match kind {
+                    RecordKind::Diff => UpdateKind::Diff,
+                    RecordKind::Snapshot | RecordKind::Checkpoint => UpdateKind::Snapshot,
+                },
+                bids,
+                asks,
+                last_update_id,
+                // not recorded, the diffs are replayed as they were applied
+                first_update_id: None,
+            },
+        })
+    }
+}
+
+impl<R: Read + Seek> RecordReader<R> {
+    // `offset` from the start of the file, as written in the index
+    pub fn seek(&mut self, offset: u64) -> Result<()> {
+        self.reader.seek(SeekFrom::Start(offset))?;
+        Ok(())
+    }
+}
+
+impl<R: Read> Iterator for RecordReader<R> {
+    type Item = Result<Record>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.read_record() {
+            Ok(record) => Some(Ok(record)),
+            Err(err) => match err.downcast_ref::<std::io::Error>() {
+                Some(io_err) if io_err.kind() == ErrorKind::UnexpectedEof => None,
+                _ => Some(Err(err)),
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn update(exchange: ExchangeId, kind: UpdateKind, last_update_id: u64) -> ParsedUpdate {
+        ParsedUpdate {
+            exchange,
+            kind,
+            bids: vec![ParsedLevel {
+                price: 9.0,
+                amount: 1.5,
+            }],
+            asks: vec![
+                ParsedLevel {
+                    price: 10.0,
+                    amount: 2.0,
+                },
+                ParsedLevel {
+                    price: 10.5,
+                    amount: 0.0,
+                },
+            ],
+            last_update_id,
+            first_update_id: None,
+        }
+    }
+
+    #[test]
+    fn records_round_trip() {
+        let config = BookConfig {
+            reporting_levels: 10,
+            max_depth_per_exchange: Some(500),
+            price_band_bps: None,
+        };
+        let mut recorder = Recorder::new(Vec::new(), "btcusdt", 1_000, config, 60).unwrap();
+        recorder
+            .record_update(1_000, &update(ExchangeId::Binance, UpdateKind::Snapshot, 5))
+            .unwrap();
+        recorder
+            .record_update(2_000, &update(ExchangeId::Bitstamp, UpdateKind::Diff, 9))
+            .unwrap();
+        let buffer = recorder.into_inner();
+
+        let mut reader = RecordReader::new(buffer.as_slice()).unwrap();
+        assert_eq!(reader.symbol(), "btcusdt");
+        assert_eq!(reader.start(), 1_000);
+        assert_eq!(reader.config(), config);
+
+        let first = reader.next().unwrap().unwrap();
+        assert_eq!((first.timestamp, first.kind), (1_000, RecordKind::Snapshot));
+        assert_eq!(first.update.exchange, ExchangeId::Binance);
+        assert_eq!(first.update.last_update_id, 5);
+
+        let second = reader.next().unwrap().unwrap();
+        assert_eq!(second.kind, RecordKind::Diff);
+        assert_eq!(second.update.exchange, ExchangeId::Bitstamp);
+        assert_eq!(
+            second.update.bids,
+            update(ExchangeId::Bitstamp, UpdateKind::Diff, 9).bids
+        );
+        assert_eq!(second.update.asks.len(), 2);
+        assert!(reader.next().is_none());
+    }
+
+    #[test]
+    fn checkpoints_every_interval() {
+        let order_book =
+            OrderBook::new(10, update(ExchangeId::Binance, UpdateKind::Snapshot, 5)).unwrap();
+        let mut recorder = Recorder::new(Vec::new(), "btcusdt", 0, BookConfig::default(), 60)
+            .unwrap()
+            .with_index(Vec::new());
+        for seconds in [0, 30, 59, 60, 100, 120] {
+            recorder
+                .record_update(
+                    seconds * 1_000_000,
+                    &update(ExchangeId::Binance, UpdateKind::Diff, 6 + seconds),
+                )
+                .unwrap();
+            recorder
+                .maybe_checkpoint(seconds * 1_000_000, &order_book)
+                .unwrap();
+        }
+        let (buffer, index) = recorder.into_parts();
+        let index = index.unwrap();
+
+        // the index points at the first checkpoint of the group
+        let offset = checkpoint_offset(&index, 90 * 1_000_000).unwrap();
+        let mut reader = RecordReader::new(std::io::Cursor::new(&buffer)).unwrap();
+        reader.seek(offset).unwrap();
+        let record = reader.next().unwrap().unwrap();
+        assert_eq!(
+            (record.timestamp, record.kind, record.update.exchange),
+            (60 * 1_000_000, RecordKind::Checkpoint, ExchangeId::Binance)
+        );
+        assert_eq!(
+            checkpoint_offset(&index[..index.len() - 3], 200 * 1_000_000),
+            checkpoint_offset(&index, 60 * 1_000_000)
+        );
+
+        let checkpoints: Vec<(u64, ExchangeId)> = RecordReader::new(buffer.as_slice())
+            .unwrap()
+            .map(|record| record.unwrap())
+            .filter(|record| record.kind == RecordKind::Checkpoint)
+            .map(|record| (record.timestamp / 1_000_000, record.update.exchange))
+            .collect();
+        assert_eq!(
+            checkpoints,
+            vec![
+                (0, ExchangeId::Binance),
+                (0, ExchangeId::Bitstamp),
+                (60, ExchangeId::Binance),
+                (60, ExchangeId::Bitstamp),
+                (120, ExchangeId::Binance),
+                (120, ExchangeId::Bitstamp),
+            ]
+        );
+    }
+
+    #[test]
+    fn truncated_last_record_ends_the_log() {
+        let mut recorder =
+            Recorder::new(Vec::new(), "ethbtc", 0, BookConfig::default(), 60).unwrap();
+        for last_update_id in [1, 2] {
+            recorder
+                .record_update(
+                    0,
+                    &update(ExchangeId::Binance, UpdateKind::Diff, last_update_id),
+                )
+                .unwrap();
+        }
+        let mut buffer = recorder.into_inner();
+        buffer.truncate(buffer.len() - 7);
+
+        let records: Vec<Record> = RecordReader::new(buffer.as_slice())
+            .unwrap()
+            .collect::<Result<_>>()
+            .unwrap();
+        assert_eq!(records.len(), 1);
+        assert!(RecordReader::new(&b"JSON"[..]).is_err());
+    }
+
+    #[tokio::test]
+    async fn file_recorder_writes_from_its_own_task() {
+        let dir = std::env::temp_dir().join(format!("recorder-test-{}", std::process::id()));
+        let config = BookConfig {
+            reporting_levels: 5,
+            max_depth_per_exchange: None,
+            price_band_bps: Some(100.0),
+        };
+        let mut recorder = FileRecorder::create(&dir, "btcusdt", config, 60)
+            .await
+            .unwrap();
+        let order_book =
+            OrderBook::new(10, update(ExchangeId::Binance, UpdateKind::Snapshot, 5)).unwrap();
+        for seconds in [0, 70] {
+            let timestamp = seconds * 1_000_000;
+            recorder
+                .record_update(timestamp, update(ExchangeId::Binance, UpdateKind::Diff, 6))
+                .unwrap();
+            recorder.maybe_checkpoint(timestamp, &order_book).unwrap();
+        }
+        recorder.close().await.unwrap();
+
+        let path = std::fs::read_dir(&dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .find(|path| path.extension().and_then(|e| e.to_str()) == Some(FILE_EXTENSION))
+            .unwrap();
+        assert!(index_path(&path).exists());
+        let reader = RecordReader::open_at_checkpoint(&path, 80 * 1_000_000).unwrap();
+        assert_eq!(reader.config(), config);
+        let kinds: Vec<(u64, RecordKind)> = reader
+            .map(|record| record.unwrap())
+            .map(|record| (record.timestamp / 1_000_000, record.kind))
+            .collect();
+        assert_eq!(
+            kinds,
+            vec![(70, RecordKind::Checkpoint), (70, RecordKind::Checkpoint)]
+        );
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
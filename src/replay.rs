@@ -0,0 +1,448 @@
+use crate::orderbook::{BookConfig, OrderBook};
+use crate::recorder::{Record, RecordReader, FILE_EXTENSION};
+use anyhow::{anyhow, Context, Result};
+use std::cmp::Reverse;
+use std::path::{Path, PathBuf};
+
+// the merged book rebuilt from a recording, as it was right after the last
+// record at or before the requested timestamp
+pub struct ReplayedBook {
+    pub order_book: OrderBook,
+    // receive time of the last record applied
+    pub last_record_timestamp: u64,
+    // false when the recording stopped before the requested timestamp,
+    // the book is then the last one recorded rather than the one at that time
+    pub covers_timestamp: bool,
+}
+
+// applies the records up to `timestamp` (microseconds since epoch) in the order they
+// were recorded, which is the order the server merged them, to a book pruned as given
+// by `config` (the one of the recording header). Checkpoints replace the book of their
+// exchange so a damaged stretch of diffs does not carry past them, and the records can
+// start at one (see RecordReader::open_at_checkpoint)
+pub fn book_at(
+    records: impl Iterator<Item = Result<Record>>,
+    timestamp: u64,
+    config: BookConfig,
+) -> Result<ReplayedBook> {
+    let mut order_book: Option<OrderBook> = None;
+    let mut last_record_timestamp = 0;
+    let mut covers_timestamp = false;
+
+    for record in records {
+        let record = record?;
+        if record.timestamp > timestamp {
+            covers_timestamp = true;
+            break;
+        }
+        last_record_timestamp = record.timestamp;
+        match order_book.as_mut() {
+            Some(order_book) => {
+                order_book.merge_parse_update(record.update)?;
+            }
+            None => order_book = Some(OrderBook::with_config(config, record.update)?),
+        }
+    }
+
+    let order_book =
+        order_book.ok_or_else(|| anyhow!("nothing recorded at or before {}", timestamp))?;
+    Ok(ReplayedBook {
+        order_book,
+        last_record_timestamp,
+        covers_timestamp,
+    })
+}
+
+// replays one recording from its last checkpoint before `timestamp`, with the bounds
+// of the recorded book and `reporting_levels`
+pub fn replay_file(path: &Path, timestamp: u64, reporting_levels: u32) -> Result<ReplayedBook> {
+    let reader = RecordReader::open_at_checkpoint(path, timestamp)?;
+    let config = BookConfig {
+        reporting_levels,
+        ..reader.config()
+    };
+    book_at(reader, timestamp, config)
+}
+
+// recordings of a symbol in `dir` as (start, path), most recent first
+pub fn recordings(dir: &Path, symbol: &str) -> Result<Vec<(u64, PathBuf)>> {
+    let prefix = format!("{}-", symbol);
+    let mut recordings = Vec::new();
+    for entry in std::fs::read_dir(dir)
+        .with_context(|| format!("failed to read record dir {}", dir.display()))?
+    {
+        let path = entry?.path();
+        if path.extension().and_then(|extension| extension.to_str()) != Some(FILE_EXTENSION) {
+            continue;
+        }
+        let start = path
+            .file_stem()
+            .and_then(|stem| stem.to_str())
+            .and_then(|stem| stem.strip_prefix(&prefix))
+            .and_then(|start| start.parse::<u64>().ok());
+        if let Some(start) = start {
+            recordings.push((start, path));
+        }
+    }
+    recordings.sort_by_key(|(start, _)| Reverse(*start));
+    Ok(recordings)
+}
+
+// picks, among the recordings of the symbol started before `timestamp`, the most
+// recent one still running at that time (several BookSummary streams of the same
+// symbol record the same market). Falls back to the most recent one otherwise
+pub fn book_at_from_dir(
+    dir: &Path,
+    symbol: &str,
+    timestamp: u64,
+    reporting_levels: u32,
+) -> Result<ReplayedBook> {
+    let mut fallback = None;
+    for (_, path) in recordings(dir, symbol)?
+        .into_iter()
+        .filter(|(start, _)| *start <= timestamp)
+    {
+        let replayed_book = replay_file(&path, timestamp, reporting_levels)
+            .with_context(|| format!("failed to replay {}", path.display()))?;
+        if replayed_book.covers_timestamp {
+            return Ok(replayed_book);
+        }
+        if fallback.is_none() {
+            fallback = Some(replayed_book);
+        }
+    }
+    fallback.ok_or_else(|| anyhow!("no {} recording started before {}", symbol, timestamp))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::exchanges::{ExchangeId, ParsedLevel, ParsedUpdate, UpdateKind};
+    use crate::orderbook::BookConfig;
+    use crate::recorder::Recorder;
+
+    fn update(
+        exchange: ExchangeId,
+        kind: UpdateKind,
+        last_update_id: u64,
+        bid: (f64, f64),
+        ask: (f64, f64),
+    ) -> ParsedUpdate {
+        ParsedUpdate {
+            exchange,
+            kind,
+            bids: vec![ParsedLevel {
+                price: bid.0,
+                amount: bid.1,
+            }],
+            asks: vec![ParsedLevel {
+                price: ask.0,
+                amount: ask.1,
+            }],
+            last_update_id,
+            first_update_id: None,
+        }
+    }
+
+    // binance and bitstamp snapshots at 1s, a binance diff at 2s moving the best bid up
+    // and a bitstamp diff at 3s removing its ask
+    fn recording() -> Vec<u8> {
+        let second = 1_000_000;
+        let mut recorder =
+            Recorder::new(Vec::new(), "btcusdt", second, BookConfig::default(), 60).unwrap();
+        let updates = [
+            (
+                second,
+                update(
+                    ExchangeId::Binance,
+                    UpdateKind::Snapshot,
+                    10,
+                    (9.0, 1.0),
+                    (11.0, 1.0),
+                ),
+            ),
+            (
+                second,
+                update(
+                    ExchangeId::Bitstamp,
+                    UpdateKind::Snapshot,
+                    20,
+                    (8.0, 1.0),
+                    (10.5, 2.0),
+                ),
+            ),
+            (
+                2 * second,
+                update(
+                    ExchangeId::Binance,
+                    UpdateKind::Diff,
+                    11,
+                    (9.5, 3.0),
+                    (11.0, 1.0),
+                ),
+            ),
+            (
+                3 * second,
+                update(
+                    ExchangeId::Bitstamp,
+                    UpdateKind::Diff,
+                    21,
+                    (8.0, 1.0),
+                    (10.5, 0.0),
+                ),
+            ),
+        ];
+        for (timestamp, parsed_update) in updates {
+            recorder.record_update(timestamp, &parsed_update).unwrap();
+        }
+        recorder.into_inner()
+    }
+
+    fn levels(reporting_levels: u32) -> BookConfig {
+        BookConfig {
+            reporting_levels,
+            ..BookConfig::default()
+        }
+    }
+
+    fn best_prices(replayed_book: &ReplayedBook) -> (f64, f64) {
+        let summary = replayed_book.order_book.get_summary().unwrap();
+        (summary.bids[0].price, summary.asks[0].price)
+    }
+
+    #[test]
+    fn rebuilds_the_book_at_each_time() {
+        let buffer = recording();
+        let at = |timestamp: u64| {
+            book_at(
+                RecordReader::new(buffer.as_slice()).unwrap(),
+                timestamp,
+                levels(5),
+            )
+            .unwrap()
+        };
+
+        let replayed_book = at(1_500_000);
+        assert_eq!(best_prices(&replayed_book), (9.0, 10.5));
+        assert_eq!(replayed_book.last_record_timestamp, 1_000_000);
+        assert!(replayed_book.covers_timestamp);
+
+        assert_eq!(best_prices(&at(2_000_000)), (9.5, 10.5));
+
+        let replayed_book = at(10_000_000);
+        assert_eq!(best_prices(&replayed_book), (9.5, 11.0));
+        assert!(!replayed_book.covers_timestamp);
+
+        assert!(book_at(RecordReader::new(buffer.as_slice()).unwrap(), 10, levels(5)).is_err());
+    }
+
+    #[test]
+    fn checkpoint_replaces_the_exchange_book() {
+        let mut recorder =
+            Recorder::new(Vec::new(), "btcusdt", 0, BookConfig::default(), 60).unwrap();
+        let snapshot = update(
+            ExchangeId::Binance,
+            UpdateKind::Snapshot,
+            10,
+            (9.0, 1.0),
+            (11.0, 1.0),
+        );
+        recorder.record_update(0, &snapshot).unwrap();
+        // a checkpoint of a book where the 9.0 bid is gone and 8.0 is quoted
+        let checkpoint_book = OrderBook::new(
+            5,
+            update(
+                ExchangeId::Binance,
+                UpdateKind::Snapshot,
+                12,
+                (8.0, 1.0),
+                (11.0, 1.0),
+            ),
+        )
+        .unwrap();
+        recorder.maybe_checkpoint(5, &checkpoint_book).unwrap();
+
+        let buffer = recorder.into_inner();
+        let replayed_book =
+            book_at(RecordReader::new(buffer.as_slice()).unwrap(), 5, levels(5)).unwrap();
+        let summary = replayed_book.order_book.get_summary().unwrap();
+        assert_eq!(summary.bids.len(), 1);
+        assert_eq!(summary.bids[0].price, 8.0);
+        assert_eq!(
+            replayed_book.order_book.last_update_ids[&ExchangeId::Binance],
+            12
+        );
+    }
+
+    #[test]
+    fn replays_the_book_served_through_a_sequence_gap() {
+        let mut recorder =
+            Recorder::new(Vec::new(), "btcusdt", 0, BookConfig::default(), 60).unwrap();
+        let snapshot = update(
+            ExchangeId::Binance,
+            UpdateKind::Snapshot,
+            10,
+            (9.0, 1.0),
+            (11.0, 1.0),
+        );
+        recorder.record_update(0, &snapshot).unwrap();
+        let mut live_book = OrderBook::new(5, snapshot).unwrap();
+        let diff = |first_update_id, last_update_id, bid| ParsedUpdate {
+            first_update_id: Some(first_update_id),
+            ..update(
+                ExchangeId::Binance,
+                UpdateKind::Diff,
+                last_update_id,
+                bid,
+                (11.0, 1.0),
+            )
+        };
+        // as the stream does: only what the book applied is recorded, the diff after the
+        // gap (13 and 14 missed) is rejected and the book waits for the resync snapshot
+        let feed = [
+            (1_000_000, diff(11, 12, (9.5, 1.0))),
+            (2_000_000, diff(15, 16, (10.0, 1.0))),
+            (
+                3_000_000,
+                update(
+                    ExchangeId::Binance,
+                    UpdateKind::Snapshot,
+                    20,
+                    (9.2, 1.0),
+                    (11.0, 1.0),
+                ),
+            ),
+        ];
+        let mut served = Vec::new();
+        for (timestamp, parsed_update) in feed {
+            match live_book.merge_parse_update(parsed_update.clone()) {
+                Ok(true) => recorder.record_update(timestamp, &parsed_update).unwrap(),
+                Ok(false) => {}
+                Err(err) => assert!(err.is::<crate::orderbook::SequenceGap>()),
+            }
+            let summary = live_book.get_summary().unwrap();
+            served.push((timestamp, summary.bids[0].price, summary.asks[0].price));
+        }
+
+        let buffer = recorder.into_inner();
+        for (timestamp, best_bid, best_ask) in served {
+            let replayed_book = book_at(
+                RecordReader::new(buffer.as_slice()).unwrap(),
+                timestamp,
+                levels(5),
+            )
+            .unwrap();
+            assert_eq!(best_prices(&replayed_book), (best_bid, best_ask));
+        }
+        let at_gap = book_at(
+            RecordReader::new(buffer.as_slice()).unwrap(),
+            2_500_000,
+            levels(5),
+        )
+        .unwrap();
+        assert_eq!(best_prices(&at_gap), (9.5, 11.0));
+    }
+
+    #[test]
+    fn picks_the_recording_running_at_the_time() {
+        let dir = std::env::temp_dir().join(format!("replay-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        // an older recording still running at 2.5s and a newer one that has nothing after 2.5s
+        std::fs::write(dir.join("btcusdt-1000000.l2"), recording()).unwrap();
+        let mut recorder =
+            Recorder::new(Vec::new(), "btcusdt", 2_000_000, BookConfig::default(), 60).unwrap();
+        recorder
+            .record_update(
+                2_000_000,
+                &update(
+                    ExchangeId::Binance,
+                    UpdateKind::Snapshot,
+                    50,
+                    (7.0, 1.0),
+                    (12.0, 1.0),
+                ),
+            )
+            .unwrap();
+        std::fs::write(dir.join("btcusdt-2000000.l2"), recorder.into_inner()).unwrap();
+        std::fs::write(dir.join("ethbtc-1000000.l2"), b"not a recording").unwrap();
+
+        let starts: Vec<u64> = recordings(&dir, "btcusdt")
+            .unwrap()
+            .into_iter()
+            .map(|(start, _)| start)
+            .collect();
+        assert_eq!(starts, vec![2_000_000, 1_000_000]);
+
+        let replayed_book = book_at_from_dir(&dir, "btcusdt", 2_500_000, 5).unwrap();
+        assert!(replayed_book.covers_timestamp);
+        assert_eq!(best_prices(&replayed_book), (9.5, 10.5));
+        assert!(book_at_from_dir(&dir, "btcusdt", 10, 5).is_err());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn replays_from_the_last_checkpoint_with_the_recorded_bounds() {
+        let dir =
+            std::env::temp_dir().join(format!("replay-checkpoint-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let config = BookConfig {
+            reporting_levels: 10,
+            max_depth_per_exchange: Some(1),
+            price_band_bps: None,
+        };
+        let header_len = Recorder::new(Vec::new(), "btcusdt", 0, config, 60)
+            .unwrap()
+            .into_inner()
+            .len();
+        let mut recorder = Recorder::new(Vec::new(), "btcusdt", 0, config, 60)
+            .unwrap()
+            .with_index(Vec::new());
+        let mut snapshot = update(
+            ExchangeId::Binance,
+            UpdateKind::Snapshot,
+            10,
+            (9.0, 1.0),
+            (11.0, 1.0),
+        );
+        snapshot.bids.push(ParsedLevel {
+            price: 8.0,
+            amount: 1.0,
+        });
+        recorder.record_update(0, &snapshot).unwrap();
+        let mut order_book = OrderBook::new(10, snapshot).unwrap();
+        recorder.maybe_checkpoint(0, &order_book).unwrap();
+        let diff = update(
+            ExchangeId::Binance,
+            UpdateKind::Diff,
+            11,
+            (9.5, 1.0),
+            (11.0, 1.0),
+        );
+        recorder.record_update(61_000_000, &diff).unwrap();
+        order_book.merge_parse_update(diff).unwrap();
+        recorder.maybe_checkpoint(61_000_000, &order_book).unwrap();
+        let (mut buffer, index) = recorder.into_parts();
+        // a damaged first record, only read when not starting from a checkpoint
+        buffer[header_len + 8] = 9;
+        let path = dir.join("btcusdt-0.l2");
+        std::fs::write(&path, buffer).unwrap();
+        std::fs::write(crate::recorder::index_path(&path), index.unwrap()).unwrap();
+
+        let replayed_book = replay_file(&path, 70_000_000, 5).unwrap();
+        assert_eq!(
+            replayed_book.order_book.config(),
+            BookConfig {
+                reporting_levels: 5,
+                ..config
+            }
+        );
+        // the checkpoint kept 9.5, 9.0 and 8.0, the recorded depth cap keeps the best one
+        let summary = replayed_book.order_book.get_summary().unwrap();
+        let bids: Vec<f64> = summary.bids.iter().map(|level| level.price).collect();
+        assert_eq!(bids, vec![9.5]);
+        assert!(RecordReader::open(&path).unwrap().next().unwrap().is_err());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
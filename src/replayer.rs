@@ -0,0 +1,57 @@
+use anyhow::Result;
+use clap::Parser;
+use loshan_keyrock::orderbook::DEFAULT_DEPTH_BPS;
+use loshan_keyrock::replay::{book_at_from_dir, replay_file, ReplayedBook};
+use std::path::PathBuf;
+
+/// prints the Summary the server would have sent for a symbol at a past time,
+/// rebuilt from the files written with --record-dir
+#[derive(Parser)]
+struct Cli {
+    symbol: String,
+    /// microseconds since epoch
+    timestamp: u64,
+    levels: u32,
+    /// directory holding the recordings of the symbol
+    #[arg(long, default_value = ".")]
+    dir: PathBuf,
+    /// replay this recording instead of looking for one in --dir
+    #[arg(long)]
+    file: Option<PathBuf>,
+    #[arg(long)]
+    consolidated: bool,
+    #[arg(long)]
+    analytics: bool,
+    #[arg(long, default_value_t = 0.0)]
+    depth_bps: f64,
+}
+
+fn main() -> Result<()> {
+    let args = Cli::parse();
+
+    let ReplayedBook {
+        mut order_book,
+        last_record_timestamp,
+        covers_timestamp,
+    } = match &args.file {
+        Some(file) => replay_file(file, args.timestamp, args.levels)?,
+        None => book_at_from_dir(&args.dir, &args.symbol, args.timestamp, args.levels)?,
+    };
+    if !covers_timestamp {
+        eprintln!(
+            "recording stops at {}, showing the last book recorded",
+            last_record_timestamp
+        );
+    }
+
+    order_book.consolidated = args.consolidated;
+    if args.analytics {
+        order_book.analytics_depth_bps = Some(if args.depth_bps > 0.0 {
+            args.depth_bps
+        } else {
+            DEFAULT_DEPTH_BPS
+        });
+    }
+    println!("{}", order_book.get_summary()?);
+    Ok(())
+}
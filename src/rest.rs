@@ -0,0 +1,344 @@
+use crate::exchanges::ExchangeId;
+use crate::rate_limit::WeightLimiter;
+use reqwest::header::{HeaderMap, RETRY_AFTER};
+use reqwest::StatusCode;
+use std::fmt;
+use std::sync::{LazyLock, Mutex};
+use std::time::Duration;
+use tokio::time::{self, Instant};
+use tonic::Code;
+
+// binance allows 6000 of REST request weight per minute and IP, part of it is left
+// to whatever else runs on the same IP
+const BINANCE_WEIGHT_PER_MINUTE: u32 = 4800;
+// bitstamp allows 10000 requests per 10 minutes, every request weighs 1
+const BITSTAMP_REQUESTS_PER_10_MINUTES: u32 = 8000;
+
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+// a 429 is retried this many times once its Retry-After is over
+const RATE_LIMITED_RETRIES: u32 = 3;
+// when the exchange doesn't say how long to wait
+const DEFAULT_RATE_LIMITED_WAIT: Duration = Duration::from_secs(10);
+const DEFAULT_BAN_WAIT: Duration = Duration::from_secs(120);
+
+// one pooled client per venue, shared by every snapshot of the server
+pub static BINANCE_REST: LazyLock<RestClient> = LazyLock::new(|| {
+    RestClient::new(
+        ExchangeId::Binance,
+        WeightLimiter::new(BINANCE_WEIGHT_PER_MINUTE, Duration::from_secs(60)),
+    )
+});
+pub static BITSTAMP_REST: LazyLock<RestClient> = LazyLock::new(|| {
+    RestClient::new(
+        ExchangeId::Bitstamp,
+        WeightLimiter::new(BITSTAMP_REQUESTS_PER_10_MINUTES, Duration::from_secs(600)),
+    )
+});
+
+#[derive(Debug)]
+pub enum RestError {
+    // no complete answer within REQUEST_TIMEOUT
+    Timeout(ExchangeId),
+    // 429, still rate limited after the retries
+    RateLimited {
+        exchange: ExchangeId,
+        retry_after: Duration,
+    },
+    // 418, the IP is banned for a while after ignoring 429s, nothing is sent until then
+    Banned {
+        exchange: ExchangeId,
+        retry_after: Duration,
+    },
+    // any other error status, with the start of the body the exchange sent
+    Status {
+        exchange: ExchangeId,
+        status: StatusCode,
+        body: String,
+    },
+    // connection failures and bodies cut short
+    Request {
+        exchange: ExchangeId,
+        error: reqwest::Error,
+    },
+}
+
+impl fmt::Display for RestError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RestError::Timeout(exchange) => {
+                write!(
+                    f,
+                    "{} request timed out after {:?}",
+                    exchange, REQUEST_TIMEOUT
+                )
+            }
+            RestError::RateLimited {
+                exchange,
+                retry_after,
+            } => write!(f, "{} rate limited, retry in {:?}", exchange, retry_after),
+            RestError::Banned {
+                exchange,
+                retry_after,
+            } => write!(f, "{} banned this IP, retry in {:?}", exchange, retry_after),
+            RestError::Status {
+                exchange,
+                status,
+                body,
+            } => write!(f, "{} answered {}: {}", exchange, status, body),
+            RestError::Request { exchange, error } => {
+                write!(f, "{} request failed: {}", exchange, error)
+            }
+        }
+    }
+}
+
+impl RestError {
+    // the gRPC code of an rpc that could not get its snapshot: a symbol the exchange
+    // doesn't know is the caller's mistake, the rest is worth retrying later
+    pub fn code(&self) -> Code {
+        match self {
+            RestError::Timeout(_) => Code::DeadlineExceeded,
+            RestError::Status { status, .. }
+                if *status == StatusCode::BAD_REQUEST || *status == StatusCode::NOT_FOUND =>
+            {
+                Code::InvalidArgument
+            }
+            RestError::Status { status, .. } if status.is_client_error() => Code::Internal,
+            _ => Code::Unavailable,
+        }
+    }
+
+    // how long the exchange asked to wait before the next request
+    pub fn retry_after(&self) -> Option<Duration> {
+        match self {
+            RestError::RateLimited { retry_after, .. } | RestError::Banned { retry_after, .. } => {
+                Some(*retry_after)
+            }
+            _ => None,
+        }
+    }
+}
+
+impl std::error::Error for RestError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            RestError::Request { error, .. } => Some(error),
+            _ => None,
+        }
+    }
+}
+
+pub struct RestClient {
+    exchange: ExchangeId,
+    client: reqwest::Client,
+    weights: WeightLimiter,
+    // set by a 429 (requests wait for it) or a 418 (requests fail until then)
+    blocked_until: Mutex<Option<(Instant, StatusCode)>>,
+}
+
+impl RestClient {
+    pub fn new(exchange: ExchangeId, weights: WeightLimiter) -> Self {
+        let client = reqwest::Client::builder()
+            .timeout(REQUEST_TIMEOUT)
+            .connect_timeout(CONNECT_TIMEOUT)
+            .build()
+            .expect("failed to build the http client");
+        RestClient {
+            exchange,
+            client,
+            weights,
+            blocked_until: Mutex::new(None),
+        }
+    }
+
+    // GETs the body once `weight` fits in the venue budget and any backoff is over, it is
+    // parsed by the caller
+    pub async fn get_text(&self, url: &str, weight: u32) -> Result<String, RestError> {
+        let mut retries = 0;
+        loop {
+            self.check_ban()?;
+            if let Some(until) = self.backoff_deadline() {
+                time::sleep_until(until).await;
+            }
+            self.weights.acquire(weight).await;
+
+            let response = self
+                .client
+                .get(url)
+                .send()
+                .await
+                .map_err(|error| self.request_error(error))?;
+            let status = response.status();
+            if let Some(used) = used_weight(response.headers()) {
+                self.weights.record_used(used).await;
+            }
+            match status {
+                StatusCode::TOO_MANY_REQUESTS | StatusCode::IM_A_TEAPOT => {
+                    let banned = status == StatusCode::IM_A_TEAPOT;
+                    let default_wait = if banned {
+                        DEFAULT_BAN_WAIT
+                    } else {
+                        DEFAULT_RATE_LIMITED_WAIT
+                    };
+                    let retry_after = retry_after(response.headers()).unwrap_or(default_wait);
+                    tracing::warn!(
+                        "{} answered {}, backing off {:?}",
+                        self.exchange,
+                        status,
+                        retry_after
+                    );
+                    *self
+                        .blocked_until
+                        .lock()
+                        .expect("rest backoff lock poisoned") =
+                        Some((Instant::now() + retry_after, status));
+                    if banned || retries == RATE_LIMITED_RETRIES {
+                        return Err(self.limited_error(status, retry_after));
+                    }
+                    retries += 1;
+                }
+                status if !status.is_success() => {
+                    let body = response.text().await.unwrap_or_default();
+                    let body = body.chars().take(200).collect();
+                    return Err(RestError::Status {
+                        exchange: self.exchange,
+                        status,
+                        body,
+                    });
+                }
+                _ => {
+                    return response
+                        .text()
+                        .await
+                        .map_err(|error| self.request_error(error))
+                }
+            }
+        }
+    }
+
+    // fails right away while banned, the ban can last days
+    fn check_ban(&self) -> Result<(), RestError> {
+        match *self
+            .blocked_until
+            .lock()
+            .expect("rest backoff lock poisoned")
+        {
+            Some((until, StatusCode::IM_A_TEAPOT)) if until > Instant::now() => {
+                Err(self.limited_error(StatusCode::IM_A_TEAPOT, until - Instant::now()))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn backoff_deadline(&self) -> Option<Instant> {
+        self.blocked_until
+            .lock()
+            .expect("rest backoff lock poisoned")
+            .map(|(until, _)| until)
+            .filter(|until| *until > Instant::now())
+    }
+
+    fn limited_error(&self, status: StatusCode, retry_after: Duration) -> RestError {
+        let exchange = self.exchange;
+        match status {
+            StatusCode::IM_A_TEAPOT => RestError::Banned {
+                exchange,
+                retry_after,
+            },
+            _ => RestError::RateLimited {
+                exchange,
+                retry_after,
+            },
+        }
+    }
+
+    fn request_error(&self, error: reqwest::Error) -> RestError {
+        match error.is_timeout() {
+            true => RestError::Timeout(self.exchange),
+            false => RestError::Request {
+                exchange: self.exchange,
+                error,
+            },
+        }
+    }
+}
+
+// weight used by this IP in the current minute, as counted by binance
+fn used_weight(headers: &HeaderMap) -> Option<u32> {
+    ["x-mbx-used-weight-1m", "x-mbx-used-weight"]
+        .iter()
+        .find_map(|name| headers.get(*name)?.to_str().ok()?.parse().ok())
+}
+
+// only the delay in seconds form, binance and bitstamp don't send dates
+fn retry_after(headers: &HeaderMap) -> Option<Duration> {
+    let seconds = headers.get(RETRY_AFTER)?.to_str().ok()?.parse().ok()?;
+    Some(Duration::from_secs(seconds))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper::service::{make_service_fn, service_fn};
+    use hyper::{Body, Response};
+    use std::convert::Infallible;
+    use std::sync::atomic::{AtomicU32, Ordering};
+    use std::sync::Arc;
+
+    #[tokio::test]
+    async fn retries_after_429_and_tracks_the_used_weight() {
+        let calls = Arc::new(AtomicU32::new(0));
+        let server_calls = calls.clone();
+        let make_service = make_service_fn(move |_| {
+            let calls = server_calls.clone();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |request: hyper::Request<Body>| {
+                    let call = calls.fetch_add(1, Ordering::SeqCst);
+                    async move {
+                        let response = match (request.uri().path(), call) {
+                            ("/depth", 0) => Response::builder()
+                                .status(429)
+                                .header("retry-after", "0")
+                                .body(Body::empty()),
+                            ("/depth", _) => Response::builder()
+                                .header("x-mbx-used-weight-1m", "300")
+                                .body(Body::from(r#"{"lastUpdateId":1}"#)),
+                            _ => Response::builder()
+                                .status(400)
+                                .body(Body::from(r#"{"code":-1121,"msg":"Invalid symbol."}"#)),
+                        };
+                        Ok::<_, Infallible>(response.unwrap())
+                    }
+                }))
+            }
+        });
+        let server = hyper::Server::bind(&([127, 0, 0, 1], 0).into()).serve(make_service);
+        let address = server.local_addr();
+        tokio::spawn(server);
+
+        let client = RestClient::new(
+            ExchangeId::Binance,
+            WeightLimiter::new(1000, Duration::from_secs(60)),
+        );
+        let body = client
+            .get_text(&format!("http://{}/depth", address), 50)
+            .await
+            .unwrap();
+        assert_eq!(body, r#"{"lastUpdateId":1}"#);
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+        // binance counted more than the 100 sent from here
+        assert_eq!(client.weights.used().await, 300);
+
+        let error = client
+            .get_text(&format!("http://{}/unknown", address), 1)
+            .await
+            .unwrap_err();
+        assert!(
+            matches!(error, RestError::Status { status, ref body, .. } if status == 400 && body.contains("Invalid symbol")),
+            "{}",
+            error
+        );
+        assert_eq!(error.code(), Code::InvalidArgument);
+    }
+}
@@ -1,104 +1,783 @@
-use anyhow::Result;
-use async_stream;
+use anyhow::{anyhow, Context, Result};
+use clap::Parser;
 use futures::Stream;
-use futures::StreamExt;
+use loshan_keyrock::auth::{self, ApiKeys, QuotaGuard};
+use loshan_keyrock::candles::{CandleBuilder, DEFAULT_INTERVALS};
+use loshan_keyrock::combined::{BinanceCombined, BINANCE_COMBINED_URL};
 use loshan_keyrock::exchanges::{
-    binance_diff_json_to_levels, bitstamp_json_to_levels, get_all_streams, get_binance_snapshot,
-    get_bitstamp_snapshot,
+    depth_message_to_update, now_micros, trade_message_to_trade, BinanceDepth, ExchangeId,
 };
-use loshan_keyrock::orderbook::OrderBook;
+use loshan_keyrock::feeds::{ExchangeFeeds, FeedConfig, FeedEvent, FeedKind};
+use loshan_keyrock::health::{FeedHealth, FeedHealthGuard};
+use loshan_keyrock::metrics::{self, SubscriberGuard, METRICS};
+use loshan_keyrock::orderbook::{BookConfig, OrderBook, SequenceGap, DEFAULT_DEPTH_BPS};
 use loshan_keyrock::orderbookaggregator::{
     orderbook_aggregator_server::{OrderbookAggregator, OrderbookAggregatorServer},
-    Summary, SummaryRequest,
+    Candle, CandleSource, CandlesRequest, Summary, SummaryRequest, Trade, TradesRequest,
+    FILE_DESCRIPTOR_SET,
 };
-use serde_json;
+use loshan_keyrock::recorder::FileRecorder;
+use loshan_keyrock::rest::RestError;
+use loshan_keyrock::validation::{sanitize_symbol, validate_summary_request};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::path::PathBuf;
 use std::pin::Pin;
-use tonic::{transport::Server, Request, Status};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::{broadcast, mpsc, watch};
+use tonic::transport::{Certificate, Identity, Server, ServerTlsConfig};
+use tonic::{Request, Status};
 
-#[derive(Debug, Default)]
-struct OrderbookAggregatorService;
+// used when a Candles request leaves interval_secs to 0
+const DEFAULT_CANDLE_INTERVAL_SECS: u32 = 60;
+// closed candles waiting for slow Candles subscribers before they start lagging
+const CANDLE_CHANNEL_CAPACITY: usize = 1024;
+// a candle feed started by a Candles request stops once it had no subscriber for this long
+const CANDLE_FEED_IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+// how long clients are asked to wait before retrying a stream ended by a shutdown
+const SHUTDOWN_RETRY_PUSHBACK_MS: u64 = 1000;
+
+#[derive(Parser)]
+struct Cli {
+    /// address the gRPC server listens on
+    #[arg(long, default_value = "127.0.0.1:5001")]
+    address: SocketAddr,
+    /// PEM certificate chain of the server, enables TLS together with --tls-key
+    #[arg(long, requires = "tls_key")]
+    tls_cert: Option<PathBuf>,
+    /// PEM private key of the server
+    #[arg(long, requires = "tls_cert")]
+    tls_key: Option<PathBuf>,
+    /// PEM CA certificate(s) client certificates must be signed by (mutual TLS)
+    #[arg(long, requires = "tls_cert")]
+    tls_client_ca: Option<PathBuf>,
+    /// largest `levels` a BookSummary request can ask for
+    #[arg(long, default_value_t = 1000)]
+    max_levels: u32,
+    /// json file listing the accepted api keys and their limits, no authentication without it
+    #[arg(long)]
+    api_keys: Option<PathBuf>,
+    /// maximum number of prices stored per exchange and side (the Binance snapshot returns
+    /// --binance-snapshot-limit)
+    #[arg(long)]
+    max_depth: Option<usize>,
+    /// prices further than this many basis points from mid are dropped from the stored book
+    #[arg(long)]
+    price_band_bps: Option<f64>,
+    /// candle intervals in seconds built for every symbol
+    #[arg(long, value_delimiter = ',', default_values_t = DEFAULT_INTERVALS,
+        value_parser = clap::value_parser!(u32).range(1..))]
+    candle_intervals: Vec<u32>,
+    /// closed candles kept per symbol, source and interval for the history backfill
+    #[arg(long, default_value_t = 1000)]
+    candle_history: usize,
+    /// symbols whose candles are built from startup, others start on their first Candles request
+    /// and stop once nobody subscribed to them for a minute
+    #[arg(long, value_delimiter = ',')]
+    candle_symbols: Vec<String>,
+    /// directory where every BookSummary stream records its book (updates and checkpoints)
+    #[arg(long)]
+    record_dir: Option<PathBuf>,
+    /// seconds between two full book checkpoints in the recorded files
+    #[arg(long, default_value_t = 60)]
+    checkpoint_secs: u64,
+    /// serve prometheus metrics on http://{address}/metrics
+    #[arg(long)]
+    metrics_address: Option<SocketAddr>,
+    /// seconds without anything received from an exchange (heartbeat replies included)
+    /// before its websocket is considered dead and opened again
+    #[arg(long, default_value_t = 30)]
+    feed_timeout_secs: u64,
+    /// binance streams of every symbol and rpc on a single websocket (combined endpoint)
+    /// instead of one websocket per stream, to stay under the binance connection limits
+    #[arg(long)]
+    binance_combined: bool,
+    /// binance diff stream update speed, 100 or 1000 ms
+    #[arg(long, default_value_t = 100)]
+    binance_update_speed_ms: u32,
+    /// prices per side of the binance REST snapshot, up to 5000 (deeper costs more request weight)
+    #[arg(long, default_value_t = 1000)]
+    binance_snapshot_limit: u32,
+    /// per symbol binance settings as symbol=update_speed_ms:snapshot_limit, e.g. btcusdt=1000:5000
+    #[arg(long, value_delimiter = ',', value_parser = parse_binance_depth)]
+    binance_depth: Vec<(String, BinanceDepth)>,
+    /// bitstamp books from the order_book channel (top 100 prices, a whole book per message)
+    /// instead of the diff_order_book channel aligned with REST snapshots
+    #[arg(long)]
+    bitstamp_order_book: bool,
+    /// seconds given to open streams and exchange connections to close on SIGTERM or ctrl-c
+    #[arg(long, default_value_t = 10)]
+    shutdown_timeout_secs: u64,
+}
+
+fn parse_binance_depth(arg: &str) -> Result<(String, BinanceDepth)> {
+    let (symbol, depth) = arg.split_once('=').with_context(|| {
+        format!(
+            "expected symbol=update_speed_ms:snapshot_limit, got {}",
+            arg
+        )
+    })?;
+    let symbol = sanitize_symbol(symbol).map_err(|status| anyhow!("{}", status.message()))?;
+    Ok((symbol, depth.parse()?))
+}
+
+// candles of one symbol, built by a background feed shared by all its Candles streams
+struct SymbolCandles {
+    builder: CandleBuilder,
+    sender: broadcast::Sender<Candle>,
+    // --candle-symbols feeds run until shutdown, the others stop when idle
+    keep_running: bool,
+}
+
+type CandleStore = Arc<Mutex<HashMap<String, SymbolCandles>>>;
+
+struct OrderbookAggregatorService {
+    max_depth_per_exchange: Option<usize>,
+    price_band_bps: Option<f64>,
+    candle_intervals: Vec<u32>,
+    candle_history: usize,
+    candles: CandleStore,
+    record_dir: Option<PathBuf>,
+    checkpoint_secs: u64,
+    feed_config: FeedConfig,
+    feed_health: FeedHealth,
+    // None when the server runs without --api-keys
+    api_keys: Option<ApiKeys>,
+    max_levels: u32,
+    // turns true once the server is shutting down, every stream and candle feed watches it
+    shutdown: watch::Receiver<bool>,
+    // held by every candle feed task, main sees the channel close once all of them stopped
+    feeds_running: mpsc::Sender<()>,
+}
+
+// resolves once the server starts shutting down
+async fn shutting_down(shutdown: &mut watch::Receiver<bool>) {
+    // the sender lives as long as main, an error would only mean it is gone
+    _ = shutdown.wait_for(|stopping| *stopping).await;
+}
+
+// the final status of every stream still open when the server shuts down
+fn shutdown_status() -> Status {
+    let mut status = Status::unavailable("server shutting down, retry shortly");
+    status
+        .metadata_mut()
+        .insert("grpc-retry-pushback-ms", SHUTDOWN_RETRY_PUSHBACK_MS.into());
+    status
+}
+
+// a failed snapshot ends the rpc with the code of its REST error (UNAVAILABLE for anything
+// else), a rate limit or ban tells the client when to retry
+fn snapshot_status(exchange: ExchangeId, err: anyhow::Error) -> Status {
+    let message = format!("{} snapshot failed: {:#}", exchange, err);
+    let Some(rest_error) = err.downcast_ref::<RestError>() else {
+        return Status::unavailable(message);
+    };
+    let mut status = Status::new(rest_error.code(), message);
+    if let Some(retry_after) = rest_error.retry_after() {
+        status.metadata_mut().insert(
+            "grpc-retry-pushback-ms",
+            (retry_after.as_millis() as u64).into(),
+        );
+    }
+    status
+}
+
+// a recorder failing is logged and dropped, the stream it records carries on
+fn record_or_stop(
+    recorder: &mut Option<FileRecorder>,
+    write: impl FnOnce(&mut FileRecorder) -> Result<()>,
+) {
+    if let Some(active_recorder) = recorder.as_mut() {
+        if let Err(err) = write(active_recorder) {
+            tracing::error!("recording stopped: {}", err);
+            *recorder = None;
+        }
+    }
+}
+
+impl OrderbookAggregatorService {
+    // the bounds of --max-depth and --price-band-bps, for every book the server keeps
+    fn book_config(&self, reporting_levels: u32) -> BookConfig {
+        BookConfig {
+            reporting_levels,
+            max_depth_per_exchange: self.max_depth_per_exchange,
+            price_band_bps: self.price_band_bps,
+        }
+    }
+
+    // new rpcs are refused while the open streams are drained
+    fn is_shutting_down(&self) -> bool {
+        *self.shutdown.borrow()
+    }
+
+    // counts the stream against the key that sent the request (see auth.rs),
+    // before any exchange connection is made for it
+    fn acquire_quota<T>(
+        &self,
+        request: &Request<T>,
+        symbol: &str,
+        levels: Option<u32>,
+    ) -> Option<Result<QuotaGuard, Status>> {
+        let api_keys = self.api_keys.as_ref()?;
+        Some(api_keys.acquire_request(request, symbol, levels))
+    }
+
+    // starts the candle feed of a symbol unless it is already running. Called with the
+    // store locked so that an idle feed can't stop between this and the subscription
+    fn ensure_candle_feed<'a>(
+        &self,
+        candles: &'a mut HashMap<String, SymbolCandles>,
+        symbol: &str,
+        keep_running: bool,
+    ) -> &'a mut SymbolCandles {
+        if candles.contains_key(symbol) {
+            let symbol_candles = candles.get_mut(symbol).expect("checked above");
+            symbol_candles.keep_running |= keep_running;
+            return symbol_candles;
+        }
+        let (sender, _) = broadcast::channel(CANDLE_CHANNEL_CAPACITY);
+
+        let feed_symbol = symbol.to_string();
+        let store = self.candles.clone();
+        let feed_health = self.feed_health.clone();
+        let feed_config = self.feed_config.clone();
+        // only the mid is read from this book
+        let book_config = self.book_config(1);
+        let shutdown = self.shutdown.clone();
+        let feeds_running = self.feeds_running.clone();
+        tokio::spawn(async move {
+            let _feeds_running = feeds_running;
+            if let Err(err) = run_candle_feed(
+                &feed_symbol,
+                &store,
+                &feed_health,
+                &feed_config,
+                book_config,
+                shutdown,
+            )
+            .await
+            {
+                tracing::error!("{} candle feed stopped: {}", feed_symbol, err);
+                // dropping the sender ends the Candles streams, the next request restarts the feed
+                store
+                    .lock()
+                    .expect("candle store poisoned")
+                    .remove(&feed_symbol);
+            }
+        });
+        candles.entry(symbol.to_string()).or_insert(SymbolCandles {
+            builder: CandleBuilder::new(&self.candle_intervals, self.candle_history),
+            sender,
+            keep_running,
+        })
+    }
+}
+
+// hands a mid or a trade to the builder of the symbol and publishes the closed candles
+fn publish_candles(
+    store: &CandleStore,
+    symbol: &str,
+    update: impl FnOnce(&mut CandleBuilder) -> Vec<Candle>,
+) {
+    let mut candles = store.lock().expect("candle store poisoned");
+    let Some(symbol_candles) = candles.get_mut(symbol) else {
+        return;
+    };
+    for candle in update(&mut symbol_candles.builder) {
+        // an error only means nobody is subscribed right now
+        _ = symbol_candles.sender.send(candle);
+    }
+}
+
+// a new snapshot of the exchange after its websocket was opened again, a sequence gap
+// or the pruning of levels it has to show again (see OrderBook::prune), the diffs
+// missed in between are lost. The feed is reported out of sync until it is applied
+async fn resync_book(
+    order_book: &mut OrderBook,
+    feeds: &mut ExchangeFeeds,
+    symbol: &str,
+    exchange: ExchangeId,
+    feed_health: &mut FeedHealthGuard,
+    recorder: &mut Option<FileRecorder>,
+) -> Result<()> {
+    feed_health.set_in_sync(exchange, false).await;
+    if let Some(snapshot) = feeds.snapshot(exchange).await? {
+        record_or_stop(recorder, |recorder| {
+            recorder.record_update(now_micros(), snapshot.clone())
+        });
+        order_book.apply_snapshot(snapshot)?;
+    }
+    feed_health.set_in_sync(exchange, true).await;
+    tracing::info!("{} {} book resynced", exchange, symbol);
+    Ok(())
+}
+
+// removes the candles of a symbol nobody subscribed to since the previous check,
+// under the store lock so that no Candles request subscribes in the meantime
+fn stop_if_idle(store: &CandleStore, symbol: &str, was_idle: &mut bool) -> bool {
+    let mut candles = store.lock().expect("candle store poisoned");
+    let idle = candles.get(symbol).is_some_and(|symbol_candles| {
+        !symbol_candles.keep_running && symbol_candles.sender.receiver_count() == 0
+    });
+    if idle && *was_idle {
+        candles.remove(symbol);
+        return true;
+    }
+    *was_idle = idle;
+    false
+}
+
+// keeps a merged book of the symbol for the mid candles and follows the trades of
+// both exchanges for the volume candles. Mid candles are timed with the local receive
+// time, trade candles with the time of the trade on the exchange
+async fn run_candle_feed(
+    symbol: &str,
+    store: &CandleStore,
+    feed_health: &FeedHealth,
+    feed_config: &FeedConfig,
+    book_config: BookConfig,
+    mut shutdown: watch::Receiver<bool>,
+) -> Result<()> {
+    let mut depth_feeds = ExchangeFeeds::connect(symbol, FeedKind::Depth, feed_config).await?;
+    let binance_snapshot = depth_feeds
+        .snapshot(ExchangeId::Binance)
+        .await?
+        .ok_or_else(|| anyhow!("no binance snapshot for {}", symbol))?;
+    let mut order_book = OrderBook::with_config(book_config, binance_snapshot)?;
+    if let Some(snapshot) = depth_feeds.snapshot(ExchangeId::Bitstamp).await? {
+        order_book.apply_snapshot(snapshot)?;
+    }
+    let mut feed_health = feed_health.guard(symbol);
+    for exchange in ExchangeId::ALL {
+        feed_health.set_in_sync(exchange, true).await;
+    }
+    let trades = FeedKind::Trades {
+        binance_aggregated: false,
+    };
+    let mut trade_feeds = ExchangeFeeds::connect(symbol, trades, feed_config).await?;
+    let mut idle_check = tokio::time::interval_at(
+        tokio::time::Instant::now() + CANDLE_FEED_IDLE_TIMEOUT,
+        CANDLE_FEED_IDLE_TIMEOUT,
+    );
+    let mut was_idle = false;
+    let book_metrics = METRICS.book_guard(symbol);
+
+    loop {
+        tokio::select! {
+            Some(event) = depth_feeds.next() => {
+                let (exchange, message) = match event? {
+                    FeedEvent::Message(exchange, message) => (exchange, message),
+                    FeedEvent::Reconnected(exchange) => {
+                        resync_book(&mut order_book, &mut depth_feeds, symbol, exchange, &mut feed_health, &mut None).await?;
+                        continue;
+                    }
+                };
+                METRICS.count_message(exchange, &message);
+                let parsed_update = depth_message_to_update(exchange, &message)
+                    .inspect_err(|_| METRICS.count_parse_error(exchange))?;
+                let Some(parsed_update) = parsed_update else {
+                    continue;
+                };
+                let merged = match order_book.merge_parse_update(parsed_update) {
+                    Ok(merged) => merged,
+                    Err(err) if err.is::<SequenceGap>() => {
+                        tracing::warn!("{} {}, resyncing", symbol, err);
+                        resync_book(&mut order_book, &mut depth_feeds, symbol, exchange, &mut feed_health, &mut None).await?;
+                        continue;
+                    }
+                    Err(err) => return Err(err),
+                };
+                if !merged {
+                    METRICS.count_dropped_update(exchange);
+                    continue;
+                }
+                if let Some(pruned) = order_book.take_resync() {
+                    resync_book(&mut order_book, &mut depth_feeds, symbol, pruned, &mut feed_health, &mut None).await?;
+                }
+                METRICS.mark_update(&book_metrics, exchange, &order_book);
+                if let Some(mid_price) = order_book.mid_price() {
+                    publish_candles(store, symbol, |builder| builder.on_mid(now_micros(), mid_price));
+                }
+            }
+            Some(event) = trade_feeds.next() => {
+                // trades missed while reconnecting are not fetched again
+                let FeedEvent::Message(exchange, message) = event? else {
+                    continue;
+                };
+                METRICS.count_message(exchange, &message);
+                let parsed_trade = trade_message_to_trade(exchange, &message)
+                    .inspect_err(|_| METRICS.count_parse_error(exchange))?;
+                let Some(parsed_trade) = parsed_trade else {
+                    continue;
+                };
+                publish_candles(store, symbol, |builder| builder.on_trade(parsed_trade.timestamp, &parsed_trade));
+            }
+            _ = idle_check.tick() => {
+                if stop_if_idle(store, symbol, &mut was_idle) {
+                    tracing::info!("{} candle feed idle, stopping", symbol);
+                    depth_feeds.close().await;
+                    trade_feeds.close().await;
+                    return Ok(());
+                }
+            }
+            _ = shutting_down(&mut shutdown) => {
+                depth_feeds.close().await;
+                trade_feeds.close().await;
+                return Ok(());
+            }
+            else => return Err(anyhow!("exchange streams ended")),
+        }
+    }
+}
 
 #[tonic::async_trait]
 impl OrderbookAggregator for OrderbookAggregatorService {
     type BookSummaryStream = Pin<Box<dyn Stream<Item = Result<Summary, Status>> + Send>>;
+    type TradesStream = Pin<Box<dyn Stream<Item = Result<Trade, Status>> + Send>>;
+    type CandlesStream = Pin<Box<dyn Stream<Item = Result<Candle, Status>> + Send>>;
 
     async fn book_summary(
         &self,
         request: Request<SummaryRequest>,
     ) -> Result<tonic::Response<Self::BookSummaryStream>, Status> {
-        let SummaryRequest { symbol, levels } = request.into_inner();
+        if self.is_shutting_down() {
+            return Err(shutdown_status());
+        }
+        let summary_request = validate_summary_request(request.get_ref(), self.max_levels)?;
+        let quota = self
+            .acquire_quota(
+                &request,
+                &summary_request.symbol,
+                Some(summary_request.levels),
+            )
+            .transpose()?;
+        let SummaryRequest {
+            symbol,
+            levels,
+            consolidated,
+            analytics,
+            depth_bps,
+        } = summary_request;
 
         // create streams before taking the 2 snapshots below
-        let mut stream_map = get_all_streams(&symbol)
+        let mut feeds = ExchangeFeeds::connect(&symbol, FeedKind::Depth, &self.feed_config)
             .await
-            .expect("Error in getting exchenges Streams Map");
+            .map_err(|err| Status::unavailable(format!("exchange connection failed: {:#}", err)))?;
 
         // get initial 2 snapshots here
-        let initial_binance_snaphots = get_binance_snapshot(&symbol)
+        let initial_binance_snaphots = feeds
+            .snapshot(ExchangeId::Binance)
             .await
-            .expect("Error getting ParsedUpdate for BINANCE snapshot");
-        let initial_bitstamp_snapshots = get_bitstamp_snapshot(&symbol)
+            .map_err(|err| snapshot_status(ExchangeId::Binance, err))?
+            .ok_or_else(|| Status::internal("no binance snapshot"))?;
+        // aligned with the diffs already streamed, none for the bitstamp order_book channel
+        let initial_bitstamp_snapshots = feeds
+            .snapshot(ExchangeId::Bitstamp)
             .await
-            .expect("Error getting ParsedUpdate for BITSTAMP snaphost");
+            .map_err(|err| snapshot_status(ExchangeId::Bitstamp, err))?;
 
-        let mut order_book = OrderBook::new(levels, initial_binance_snaphots)
-            .expect("failed to create new orderbook");
+        let book_config = self.book_config(levels);
+        let mut recorder = match &self.record_dir {
+            Some(dir) => Some(
+                FileRecorder::create(dir, &symbol, book_config, self.checkpoint_secs)
+                    .await
+                    .map_err(|err| {
+                        Status::internal(format!("failed to start recording: {}", err))
+                    })?,
+            ),
+            None => None,
+        };
+        record_or_stop(&mut recorder, |recorder| {
+            recorder.record_update(now_micros(), initial_binance_snaphots.clone())?;
+            match &initial_bitstamp_snapshots {
+                Some(snapshot) => recorder.record_update(now_micros(), snapshot.clone()),
+                None => Ok(()),
+            }
+        });
 
-        _ = order_book.merge_parse_update(initial_bitstamp_snapshots);
+        let mut order_book = OrderBook::with_config(book_config, initial_binance_snaphots)
+            .map_err(|err| Status::internal(format!("failed to create the book: {}", err)))?;
+        order_book.consolidated = consolidated;
+        if analytics {
+            order_book.analytics_depth_bps = Some(if depth_bps > 0.0 {
+                depth_bps
+            } else {
+                DEFAULT_DEPTH_BPS
+            });
+        }
 
-        let output = async_stream::try_stream! {
-            while let Some((key, message)) = stream_map.next().await {
-                let message = message.expect("failed to unwrap message from streams main loop");
+        if let Some(snapshot) = initial_bitstamp_snapshots {
+            order_book.apply_snapshot(snapshot).map_err(|err| {
+                Status::internal(format!("failed to apply BITSTAMP snapshot: {}", err))
+            })?;
+        }
 
-                tracing::info!("from exchange {} message received: {}", key, message);
+        // both exchanges are reported out of sync when the stream ends, for any reason
+        let mut feed_health = self.feed_health.guard(&symbol);
+        for exchange in ExchangeId::ALL {
+            feed_health.set_in_sync(exchange, true).await;
+        }
 
-                let message = match message {
-                    tungstenite::Message::Text(_) => message,
-                    // trying to just skip Pings and Pongs messages otherwise they will break parsing
-                    tungstenite::Message::Ping(_) => {
-                        continue;
-                    }
-                    tungstenite::Message::Pong(_) => {
+        let mut shutdown = self.shutdown.clone();
+        let output = async_stream::try_stream! {
+            let _subscriber = SubscriberGuard::new("book_summary");
+            let book_metrics = METRICS.book_guard(&symbol);
+            let _quota = quota;
+            let mut feed_health = feed_health;
+            while let Some(event) = tokio::select! {
+                next = feeds.next() => next,
+                _ = shutting_down(&mut shutdown) => None,
+            } {
+                let event = event.map_err(|err| Status::unavailable(format!("{:#}", err)))?;
+                let (key, message) = match event {
+                    FeedEvent::Message(key, message) => (key, message),
+                    FeedEvent::Reconnected(exchange) => {
+                        resync_book(&mut order_book, &mut feeds, &symbol, exchange, &mut feed_health, &mut recorder)
+                            .await
+                            .map_err(|err| snapshot_status(exchange, err))?;
                         continue;
                     }
-                    _ => {
-                        panic!("unknown message received from stream")
-                    }
                 };
+                let received = Instant::now();
+                METRICS.count_message(key, &message);
 
-                let message_value: serde_json::Value =
-                    serde_json::from_slice(&message.into_data()).expect("empty message?");
-
-                let parsed_update = match key {
-                    "BINANCE" => binance_diff_json_to_levels(message_value)
-                        .expect("error in binance json value to updates"),
-                    "BITSTAMP" => {
-                        let bitstamp_event = &message_value["event"];
-
-                        if bitstamp_event.as_str().expect("error in parsing bitstamp event to string") == "bts:subscription_succeeded" {
-                            tracing::info!(
-                                "received subscription confirmation message with no data, continue"
-                            );
-                            continue;
-                        } else {
-                            bitstamp_json_to_levels(&message_value)
-                                .expect("error in bitstamp json value to updates")
-                        }
+                tracing::info!("from exchange {} message received: {}", key, message);
+
+                // pings, pongs and the bitstamp events without book data are skipped
+                let parsed_update = depth_message_to_update(key, &message).map_err(|err| {
+                    METRICS.count_parse_error(key);
+                    Status::internal(format!("{} message parsing error: {}", key, err))
+                })?;
+                let Some(parsed_update) = parsed_update else {
+                    continue;
+                };
+                let received_at = now_micros();
+                // recorded once applied, replay has to give the book this stream served
+                let recorded = recorder.is_some().then(|| parsed_update.clone());
+                let merged = match order_book.merge_parse_update(parsed_update) {
+                    Ok(merged) => merged,
+                    // the book of the exchange is taken again, the stream carries on
+                    Err(err) if err.is::<SequenceGap>() => {
+                        tracing::warn!("{} {}, resyncing", symbol, err);
+                        resync_book(&mut order_book, &mut feeds, &symbol, key, &mut feed_health, &mut recorder)
+                            .await
+                            .map_err(|err| snapshot_status(key, err))?;
+                        continue;
                     }
-                    _ => panic!("not implemented exchange"),
+                    Err(err) => Err(Status::internal(format!("{} update failed to merge: {}", key, err)))?,
                 };
-                _ = order_book.merge_parse_update(parsed_update);
+                if merged {
+                    if let Some(recorded) = recorded {
+                        record_or_stop(&mut recorder, |recorder| {
+                            recorder.record_update(received_at, recorded)
+                        });
+                    }
+                    METRICS.mark_update(&book_metrics, key, &order_book);
+                } else {
+                    METRICS.count_dropped_update(key);
+                }
+                // levels pruned earlier are needed again
+                if let Some(pruned) = order_book.take_resync() {
+                    resync_book(&mut order_book, &mut feeds, &symbol, pruned, &mut feed_health, &mut recorder)
+                        .await
+                        .map_err(|err| snapshot_status(pruned, err))?;
+                }
+                record_or_stop(&mut recorder, |recorder| {
+                    recorder.maybe_checkpoint(received_at, &order_book)
+                });
 
-                let summary = order_book.get_summary().expect("Error in creating summary");
+                let summary = order_book
+                    .get_summary()
+                    .map_err(|err| Status::internal(format!("failed to build the summary: {}", err)))?;
+                METRICS
+                    .summary_emit_latency
+                    .observe(received.elapsed().as_secs_f64());
 
                 yield summary
             }
+            if *shutdown.borrow() {
+                feeds.close().await;
+                if let Some(recorder) = recorder.take() {
+                    if let Err(err) = recorder.close().await {
+                        tracing::error!("recording not flushed: {:#}", err);
+                    }
+                }
+                Err(shutdown_status())?;
+            }
         };
 
         Ok(tonic::Response::new(
             Box::pin(output) as Self::BookSummaryStream
         ))
     }
+
+    async fn trades(
+        &self,
+        request: Request<TradesRequest>,
+    ) -> Result<tonic::Response<Self::TradesStream>, Status> {
+        if self.is_shutting_down() {
+            return Err(shutdown_status());
+        }
+        let symbol = sanitize_symbol(&request.get_ref().symbol)?;
+        let quota = self.acquire_quota(&request, &symbol, None).transpose()?;
+        let binance_aggregated = request.get_ref().binance_aggregated;
+
+        let trades = FeedKind::Trades { binance_aggregated };
+        let mut feeds = ExchangeFeeds::connect(&symbol, trades, &self.feed_config)
+            .await
+            .map_err(|err| {
+                Status::unavailable(format!("failed to connect trade streams: {}", err))
+            })?;
+
+        // trades from both venues are forwarded in the order they are received
+        let mut shutdown = self.shutdown.clone();
+        let output = async_stream::try_stream! {
+            let _subscriber = SubscriberGuard::new("trades");
+            let _quota = quota;
+            while let Some(event) = tokio::select! {
+                next = feeds.next() => next,
+                _ = shutting_down(&mut shutdown) => None,
+            } {
+                let event = event
+                    .map_err(|err| Status::unavailable(format!("trade stream error: {:#}", err)))?;
+                // trades missed while reconnecting are not fetched again
+                let FeedEvent::Message(key, message) = event else {
+                    continue;
+                };
+                METRICS.count_message(key, &message);
+
+                let parsed_trade = trade_message_to_trade(key, &message).map_err(|err| {
+                    METRICS.count_parse_error(key);
+                    Status::internal(format!("{} trade parsing error: {}", key, err))
+                })?;
+                let Some(parsed_trade) = parsed_trade else {
+                    continue;
+                };
+
+                yield Trade::from(&parsed_trade)
+            }
+            if *shutdown.borrow() {
+                feeds.close().await;
+                Err(shutdown_status())?;
+            }
+        };
+
+        Ok(tonic::Response::new(Box::pin(output) as Self::TradesStream))
+    }
+
+    // the closed candles kept in memory (up to `history`) then the live ones as they close
+    async fn candles(
+        &self,
+        request: Request<CandlesRequest>,
+    ) -> Result<tonic::Response<Self::CandlesStream>, Status> {
+        if self.is_shutting_down() {
+            return Err(shutdown_status());
+        }
+        let symbol = sanitize_symbol(&request.get_ref().symbol)?;
+        let quota = self.acquire_quota(&request, &symbol, None).transpose()?;
+        let CandlesRequest {
+            interval_secs,
+            source,
+            history,
+            ..
+        } = request.into_inner();
+
+        let interval_secs = if interval_secs == 0 {
+            DEFAULT_CANDLE_INTERVAL_SECS
+        } else {
+            interval_secs
+        };
+        if !self.candle_intervals.contains(&interval_secs) {
+            return Err(Status::invalid_argument(format!(
+                "interval of {}s not built, available intervals: {:?}",
+                interval_secs, self.candle_intervals
+            )));
+        }
+        let source = CandleSource::from_i32(source)
+            .ok_or_else(|| Status::invalid_argument(format!("unknown candle source {}", source)))?;
+
+        // history and subscription under the same lock so no candle is missed or repeated
+        let (backfill, mut receiver) = {
+            let mut candles = self.candles.lock().expect("candle store poisoned");
+            let symbol_candles = self.ensure_candle_feed(&mut candles, &symbol, false);
+            let backfill = symbol_candles
+                .builder
+                .series(source, interval_secs)
+                .map(|series| series.history(history as usize))
+                .unwrap_or_default();
+            (backfill, symbol_candles.sender.subscribe())
+        };
+
+        let mut shutdown = self.shutdown.clone();
+        let output = async_stream::try_stream! {
+            let _subscriber = SubscriberGuard::new("candles");
+            let _quota = quota;
+            for candle in backfill {
+                yield candle;
+            }
+            loop {
+                let received = tokio::select! {
+                    received = receiver.recv() => Some(received),
+                    _ = shutting_down(&mut shutdown) => None,
+                };
+                let Some(received) = received else {
+                    Err(shutdown_status())?;
+                    break;
+                };
+                match received {
+                    Ok(candle) => {
+                        if candle.source == source as i32 && candle.interval_secs == interval_secs {
+                            yield candle;
+                        }
+                    }
+                    Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                        tracing::warn!("{} candles subscriber lagging, {} candles skipped", symbol, skipped);
+                    }
+                    Err(broadcast::error::RecvError::Closed) => {
+                        Err(Status::unavailable(format!("{} candle feed stopped", symbol)))?;
+                    }
+                }
+            }
+        };
+
+        Ok(tonic::Response::new(Box::pin(output) as Self::CandlesStream))
+    }
+}
+
+// None when serving plaintext, with --tls-client-ca clients have to present a certificate
+fn server_tls_config(args: &Cli) -> Result<Option<ServerTlsConfig>> {
+    let (Some(cert_path), Some(key_path)) = (&args.tls_cert, &args.tls_key) else {
+        return Ok(None);
+    };
+    let read = |path: &PathBuf| {
+        std::fs::read(path).with_context(|| format!("failed to read {}", path.display()))
+    };
+    let mut tls_config =
+        ServerTlsConfig::new().identity(Identity::from_pem(read(cert_path)?, read(key_path)?));
+    if let Some(client_ca_path) = &args.tls_client_ca {
+        tls_config = tls_config.client_ca_root(Certificate::from_pem(read(client_ca_path)?));
+    }
+    Ok(Some(tls_config))
+}
+
+// SIGTERM (from an orchestrator) or ctrl-c
+#[cfg(unix)]
+async fn shutdown_signal() -> Result<()> {
+    use tokio::signal::unix::{signal, SignalKind};
+    let mut terminate = signal(SignalKind::terminate())?;
+    tokio::select! {
+        _ = terminate.recv() => {}
+        result = tokio::signal::ctrl_c() => result?,
+    }
+    Ok(())
+}
+
+// no SIGTERM outside unix, ctrl-c only
+#[cfg(not(unix))]
+async fn shutdown_signal() -> Result<()> {
+    tokio::signal::ctrl_c().await?;
+    Ok(())
 }
 
 // gRPC server main setup
@@ -107,14 +786,121 @@ async fn main() -> Result<()> {
     let subscriber = tracing_subscriber::FmtSubscriber::new();
     tracing::subscriber::set_global_default(subscriber).expect("setting tracing default failed");
 
-    let address = "127.0.0.1:5001";
-    tracing::info!("Server up and running on {}", address);
+    let args = Cli::parse();
+    let mut server = Server::builder();
+    if let Some(tls_config) = server_tls_config(&args)? {
+        server = server.tls_config(tls_config)?;
+    }
+
+    // "" and the OrderbookAggregator service are always serving, each symbol and
+    // exchange feed has its own status (see health.rs)
+    let (mut health_reporter, health_service) = tonic_health::server::health_reporter();
+    health_reporter
+        .set_serving::<OrderbookAggregatorServer<OrderbookAggregatorService>>()
+        .await;
+    let reflection_service = tonic_reflection::server::Builder::configure()
+        .register_encoded_file_descriptor_set(FILE_DESCRIPTOR_SET)
+        .register_encoded_file_descriptor_set(tonic_health::pb::FILE_DESCRIPTOR_SET)
+        .build()?;
+
+    let api_keys = args
+        .api_keys
+        .as_deref()
+        .map(ApiKeys::from_file)
+        .transpose()?;
+    let interceptor_keys = api_keys.clone();
+    let (shutdown_sender, shutdown) = watch::channel(false);
+    let (feeds_running, mut feeds_stopped) = mpsc::channel(1);
+    let feed_timeout = Duration::from_secs(args.feed_timeout_secs);
+    let feed_config = FeedConfig {
+        inactivity_timeout: feed_timeout,
+        binance_combined: args
+            .binance_combined
+            .then(|| BinanceCombined::start(BINANCE_COMBINED_URL, feed_timeout)),
+        binance_depth: BinanceDepth::new(
+            args.binance_update_speed_ms,
+            args.binance_snapshot_limit,
+        )?,
+        binance_depth_symbols: args.binance_depth.into_iter().collect(),
+        bitstamp_order_book: args.bitstamp_order_book,
+    };
 
-    let socket_addr = address.parse()?;
-    let orderbook_service = OrderbookAggregatorService::default();
-    Server::builder()
-        .add_service(OrderbookAggregatorServer::new(orderbook_service))
-        .serve(socket_addr)
-        .await?;
+    let orderbook_service = OrderbookAggregatorService {
+        max_depth_per_exchange: args.max_depth,
+        price_band_bps: args.price_band_bps,
+        candle_intervals: args.candle_intervals,
+        candle_history: args.candle_history,
+        candles: CandleStore::default(),
+        record_dir: args.record_dir,
+        checkpoint_secs: args.checkpoint_secs,
+        feed_config,
+        feed_health: FeedHealth::new(health_reporter.clone()),
+        api_keys,
+        max_levels: args.max_levels,
+        shutdown: shutdown.clone(),
+        feeds_running,
+    };
+    if let Some(metrics_address) = args.metrics_address {
+        tokio::spawn(async move {
+            if let Err(err) = metrics::serve(metrics_address).await {
+                tracing::error!("{:#}", err);
+            }
+        });
+    }
+    for symbol in &args.candle_symbols {
+        let symbol = sanitize_symbol(symbol).map_err(|status| anyhow!("{}", status.message()))?;
+        let mut candles = orderbook_service
+            .candles
+            .lock()
+            .expect("candle store poisoned");
+        orderbook_service.ensure_candle_feed(&mut candles, &symbol, true);
+    }
+    tracing::info!("Server up and running on {}", args.address);
+    let mut server_shutdown = shutdown.clone();
+    let router = server
+        .add_service(health_service)
+        .add_service(reflection_service)
+        // health and reflection stay open for load balancers and debugging
+        .add_service(OrderbookAggregatorServer::with_interceptor(
+            orderbook_service,
+            auth::interceptor(interceptor_keys),
+        ));
+    let mut server_task = tokio::spawn(router.serve_with_shutdown(args.address, async move {
+        shutting_down(&mut server_shutdown).await
+    }));
+
+    tokio::select! {
+        result = &mut server_task => return Ok(result??),
+        result = shutdown_signal() => result?,
+    }
+
+    // no new connections or rpcs from here, the open streams end with UNAVAILABLE after
+    // closing their exchange sockets and flushing their recorders
+    tracing::info!(
+        "shutting down, draining streams for up to {}s",
+        args.shutdown_timeout_secs
+    );
+    health_reporter
+        .set_not_serving::<OrderbookAggregatorServer<OrderbookAggregatorService>>()
+        .await;
+    health_reporter
+        .set_service_status("", tonic_health::ServingStatus::NotServing)
+        .await;
+    shutdown_sender.send_replace(true);
+
+    let drained = tokio::time::timeout(Duration::from_secs(args.shutdown_timeout_secs), async {
+        let served = server_task.await;
+        // the channel closes once the service and every candle feed are gone
+        while feeds_stopped.recv().await.is_some() {}
+        served
+    })
+    .await;
+    match drained {
+        Ok(served) => served??,
+        Err(_) => tracing::warn!(
+            "streams still open after {}s, exiting anyway",
+            args.shutdown_timeout_secs
+        ),
+    }
     Ok(())
 }
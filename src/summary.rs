@@ -0,0 +1,355 @@
+// orderbookaggregator.Level and VenueAmount written by hand instead of generated (see
+// build.rs): the exchange name is borrowed from ExchangeId::as_str, so a Summary is
+// built without allocating a String per level. Decoded names are owned.
+// Encoded exactly as prost encodes the messages of protos/orderbookaggregator.proto, the
+// tests check the fields against the compiled proto and the bytes against generated code
+use prost::bytes::{Buf, BufMut};
+use prost::encoding::{self, DecodeContext, WireType};
+use prost::DecodeError;
+use std::borrow::Cow;
+
+#[derive(Clone, PartialEq, Default, Debug)]
+pub struct Level {
+    pub exchange: Cow<'static, str>,
+    pub price: f64,
+    pub amount: f64,
+    // only filled in consolidated mode, exchange is left empty in that case
+    pub venues: Vec<VenueAmount>,
+}
+
+#[derive(Clone, PartialEq, Default, Debug)]
+pub struct VenueAmount {
+    pub exchange: Cow<'static, str>,
+    pub amount: f64,
+}
+
+// proto3 leaves the default values out
+fn encode_str(tag: u32, value: &str, buf: &mut impl BufMut) {
+    if value.is_empty() {
+        return;
+    }
+    encoding::encode_key(tag, WireType::LengthDelimited, buf);
+    encoding::encode_varint(value.len() as u64, buf);
+    buf.put_slice(value.as_bytes());
+}
+
+fn str_len(tag: u32, value: &str) -> usize {
+    if value.is_empty() {
+        return 0;
+    }
+    encoding::key_len(tag) + encoding::encoded_len_varint(value.len() as u64) + value.len()
+}
+
+fn encode_double(tag: u32, value: f64, buf: &mut impl BufMut) {
+    if value != 0.0 {
+        encoding::double::encode(tag, &value, buf);
+    }
+}
+
+fn double_len(tag: u32, value: f64) -> usize {
+    if value == 0.0 {
+        return 0;
+    }
+    encoding::double::encoded_len(tag, &value)
+}
+
+fn merge_str(
+    value: &mut Cow<'static, str>,
+    wire_type: WireType,
+    buf: &mut impl Buf,
+    ctx: DecodeContext,
+) -> Result<(), DecodeError> {
+    let mut decoded = String::new();
+    encoding::string::merge(wire_type, &mut decoded, buf, ctx)?;
+    *value = Cow::Owned(decoded);
+    Ok(())
+}
+
+impl prost::Message for Level {
+    fn encode_raw<B: BufMut>(&self, buf: &mut B) {
+        encode_str(1, &self.exchange, buf);
+        encode_double(2, self.price, buf);
+        encode_double(3, self.amount, buf);
+        for venue in &self.venues {
+            encoding::message::encode(4, venue, buf);
+        }
+    }
+
+    fn merge_field<B: Buf>(
+        &mut self,
+        tag: u32,
+        wire_type: WireType,
+        buf: &mut B,
+        ctx: DecodeContext,
+    ) -> Result<(), DecodeError> {
+        match tag {
+            1 => merge_str(&mut self.exchange, wire_type, buf, ctx),
+            2 => encoding::double::merge(wire_type, &mut self.price, buf, ctx),
+            3 => encoding::double::merge(wire_type, &mut self.amount, buf, ctx),
+            4 => encoding::message::merge_repeated(wire_type, &mut self.venues, buf, ctx),
+            _ => encoding::skip_field(wire_type, tag, buf, ctx),
+        }
+    }
+
+    fn encoded_len(&self) -> usize {
+        str_len(1, &self.exchange)
+            + double_len(2, self.price)
+            + double_len(3, self.amount)
+            + encoding::message::encoded_len_repeated(4, &self.venues)
+    }
+
+    fn clear(&mut self) {
+        *self = Level::default();
+    }
+}
+
+impl prost::Message for VenueAmount {
+    fn encode_raw<B: BufMut>(&self, buf: &mut B) {
+        encode_str(1, &self.exchange, buf);
+        encode_double(2, self.amount, buf);
+    }
+
+    fn merge_field<B: Buf>(
+        &mut self,
+        tag: u32,
+        wire_type: WireType,
+        buf: &mut B,
+        ctx: DecodeContext,
+    ) -> Result<(), DecodeError> {
+        match tag {
+            1 => merge_str(&mut self.exchange, wire_type, buf, ctx),
+            2 => encoding::double::merge(wire_type, &mut self.amount, buf, ctx),
+            _ => encoding::skip_field(wire_type, tag, buf, ctx),
+        }
+    }
+
+    fn encoded_len(&self) -> usize {
+        str_len(1, &self.exchange) + double_len(2, self.amount)
+    }
+
+    fn clear(&mut self) {
+        *self = VenueAmount::default();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use prost::Message;
+    use prost_types::field_descriptor_proto::{Label, Type};
+
+    // the messages prost generates from the proto, as any other client decodes the
+    // summaries, and a later version of them with fields this server does not know
+    mod generated {
+        #[derive(Clone, PartialEq, prost::Message)]
+        pub struct Level {
+            #[prost(string, tag = "1")]
+            pub exchange: String,
+            #[prost(double, tag = "2")]
+            pub price: f64,
+            #[prost(double, tag = "3")]
+            pub amount: f64,
+            #[prost(message, repeated, tag = "4")]
+            pub venues: Vec<VenueAmount>,
+        }
+
+        #[derive(Clone, PartialEq, prost::Message)]
+        pub struct VenueAmount {
+            #[prost(string, tag = "1")]
+            pub exchange: String,
+            #[prost(double, tag = "2")]
+            pub amount: f64,
+        }
+
+        #[derive(Clone, PartialEq, prost::Message)]
+        pub struct NewerLevel {
+            #[prost(string, tag = "1")]
+            pub exchange: String,
+            #[prost(double, tag = "2")]
+            pub price: f64,
+            #[prost(double, tag = "3")]
+            pub amount: f64,
+            #[prost(message, repeated, tag = "4")]
+            pub venues: Vec<NewerVenueAmount>,
+            #[prost(uint64, tag = "5")]
+            pub sequence: u64,
+            #[prost(string, tag = "9")]
+            pub note: String,
+        }
+
+        #[derive(Clone, PartialEq, prost::Message)]
+        pub struct NewerVenueAmount {
+            #[prost(string, tag = "1")]
+            pub exchange: String,
+            #[prost(double, tag = "2")]
+            pub amount: f64,
+            #[prost(fixed32, tag = "3")]
+            pub orders: u32,
+        }
+    }
+
+    fn venue(exchange: &'static str, amount: f64) -> VenueAmount {
+        VenueAmount {
+            exchange: Cow::Borrowed(exchange),
+            amount,
+        }
+    }
+
+    fn generated(level: &Level) -> generated::Level {
+        generated::Level {
+            exchange: level.exchange.to_string(),
+            price: level.price,
+            amount: level.amount,
+            venues: level
+                .venues
+                .iter()
+                .map(|venue| generated::VenueAmount {
+                    exchange: venue.exchange.to_string(),
+                    amount: venue.amount,
+                })
+                .collect(),
+        }
+    }
+
+    // what the proto declares, a change there has to be made in this file as well
+    #[test]
+    fn implements_the_fields_of_the_proto() {
+        let descriptors =
+            prost_types::FileDescriptorSet::decode(crate::orderbookaggregator::FILE_DESCRIPTOR_SET)
+                .unwrap();
+        let fields = |message: &str| -> Vec<(String, i32, Type, Label, String)> {
+            descriptors
+                .file
+                .iter()
+                .flat_map(|file| file.message_type.iter())
+                .find(|descriptor| descriptor.name() == message)
+                .unwrap()
+                .field
+                .iter()
+                .map(|field| {
+                    (
+                        field.name().to_string(),
+                        field.number(),
+                        field.r#type(),
+                        field.label(),
+                        field.type_name().to_string(),
+                    )
+                })
+                .collect()
+        };
+        let field = |name: &str, number, field_type, label, type_name: &str| {
+            (
+                name.to_string(),
+                number,
+                field_type,
+                label,
+                type_name.to_string(),
+            )
+        };
+        assert_eq!(
+            fields("Level"),
+            vec![
+                field("exchange", 1, Type::String, Label::Optional, ""),
+                field("price", 2, Type::Double, Label::Optional, ""),
+                field("amount", 3, Type::Double, Label::Optional, ""),
+                field(
+                    "venues",
+                    4,
+                    Type::Message,
+                    Label::Repeated,
+                    ".orderbookaggregator.VenueAmount"
+                ),
+            ]
+        );
+        assert_eq!(
+            fields("VenueAmount"),
+            vec![
+                field("exchange", 1, Type::String, Label::Optional, ""),
+                field("amount", 2, Type::Double, Label::Optional, ""),
+            ]
+        );
+    }
+
+    #[test]
+    fn round_trips_every_field_against_the_generated_messages() {
+        let levels = [
+            Level::default(),
+            Level {
+                exchange: Cow::Borrowed("BINANCE"),
+                price: 0.0639,
+                amount: 1e-9,
+                venues: Vec::new(),
+            },
+            // consolidated, the exchange is left empty
+            Level {
+                exchange: Cow::Borrowed(""),
+                price: 0.0639,
+                amount: 3.5,
+                venues: vec![venue("BINANCE", 2.5), venue("BITSTAMP", 1.0)],
+            },
+            // -0.0 is left out as 0.0 is, an empty venue is still sent
+            Level {
+                exchange: Cow::Borrowed("BITSTAMP"),
+                price: -0.0,
+                amount: -0.0,
+                venues: vec![venue("", -0.0), VenueAmount::default()],
+            },
+            Level {
+                exchange: Cow::Borrowed("BINANCE"),
+                price: f64::MAX,
+                amount: f64::MIN_POSITIVE,
+                venues: vec![venue("BINANCE", -1.5)],
+            },
+        ];
+        for level in levels {
+            let encoded = level.encode_to_vec();
+            let generated = generated(&level);
+            assert_eq!(encoded, generated.encode_to_vec(), "{:?}", level);
+            assert_eq!(level.encoded_len(), encoded.len());
+            assert_eq!(
+                generated::Level::decode(encoded.as_slice()).unwrap(),
+                generated
+            );
+            let decoded = Level::decode(generated.encode_to_vec().as_slice()).unwrap();
+            assert_eq!(decoded, level);
+            assert!(matches!(decoded.exchange, Cow::Owned(_)));
+        }
+        let zero = Level {
+            price: -0.0,
+            ..Level::default()
+        };
+        assert!(zero.encode_to_vec().is_empty());
+        assert!(
+            VenueAmount::decode(venue("", -0.0).encode_to_vec().as_slice())
+                .unwrap()
+                .amount
+                .is_sign_positive()
+        );
+    }
+
+    #[test]
+    fn skips_unknown_fields() {
+        let newer = generated::NewerLevel {
+            exchange: "BINANCE".to_string(),
+            price: 0.0639,
+            amount: 2.0,
+            venues: vec![generated::NewerVenueAmount {
+                exchange: "BITSTAMP".to_string(),
+                amount: 2.0,
+                orders: 7,
+            }],
+            sequence: 42,
+            note: "newer server".to_string(),
+        };
+        let decoded = Level::decode(newer.encode_to_vec().as_slice()).unwrap();
+        assert_eq!(
+            decoded,
+            Level {
+                exchange: Cow::Borrowed("BINANCE"),
+                price: 0.0639,
+                amount: 2.0,
+                venues: vec![venue("BITSTAMP", 2.0)],
+            }
+        );
+    }
+}
@@ -0,0 +1,109 @@
+use crate::orderbookaggregator::SummaryRequest;
+use tonic::Status;
+
+// longer than any symbol listed on Binance or Bitstamp
+const MAX_SYMBOL_LEN: usize = 20;
+
+// symbols go into the exchange urls and channel names, so only ascii letters and
+// digits are accepted. Both exchanges use lowercase symbols, "BTCUSDT " becomes "btcusdt"
+pub fn sanitize_symbol(symbol: &str) -> Result<String, Status> {
+    let symbol = symbol.trim().to_ascii_lowercase();
+    if symbol.is_empty() {
+        return Err(Status::invalid_argument("symbol is empty"));
+    }
+    if symbol.len() > MAX_SYMBOL_LEN {
+        return Err(Status::invalid_argument(format!(
+            "symbol longer than {} characters",
+            MAX_SYMBOL_LEN
+        )));
+    }
+    if !symbol.chars().all(|c| c.is_ascii_alphanumeric()) {
+        return Err(Status::invalid_argument(format!(
+            "symbol {:?} has characters other than letters and digits",
+            symbol
+        )));
+    }
+    Ok(symbol)
+}
+
+// the request with its symbol sanitized, checked before any exchange connection is made
+pub fn validate_summary_request(
+    request: &SummaryRequest,
+    max_levels: u32,
+) -> Result<SummaryRequest, Status> {
+    if request.levels == 0 {
+        return Err(Status::invalid_argument("levels must be at least 1"));
+    }
+    if request.levels > max_levels {
+        return Err(Status::invalid_argument(format!(
+            "levels must be at most {}",
+            max_levels
+        )));
+    }
+    if !request.depth_bps.is_finite() || request.depth_bps < 0.0 {
+        return Err(Status::invalid_argument(
+            "depth_bps must be a positive number (or 0 for the default)",
+        ));
+    }
+    Ok(SummaryRequest {
+        symbol: sanitize_symbol(&request.symbol)?,
+        ..request.clone()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tonic::Code;
+
+    fn summary_request(symbol: &str, levels: u32, depth_bps: f64) -> SummaryRequest {
+        SummaryRequest {
+            levels,
+            symbol: symbol.to_string(),
+            consolidated: false,
+            analytics: true,
+            depth_bps,
+        }
+    }
+
+    #[test]
+    fn sanitizes_symbols() {
+        assert_eq!(sanitize_symbol(" BTCUSDT ").unwrap(), "btcusdt");
+        assert_eq!(sanitize_symbol("ethbtc").unwrap(), "ethbtc");
+        for symbol in [
+            "",
+            "   ",
+            "btc/usdt",
+            "btcusdt@depth",
+            "../etc",
+            "btc usdt",
+            "btcusdt?limit=5000",
+            "bitcoinbitcoinbitcoin1",
+            "btcüsdt",
+        ] {
+            let status = sanitize_symbol(symbol).unwrap_err();
+            assert_eq!(status.code(), Code::InvalidArgument, "{:?}", symbol);
+        }
+    }
+
+    #[test]
+    fn validates_summary_requests() {
+        let request = validate_summary_request(&summary_request("BTCUSDT", 10, 0.0), 100).unwrap();
+        assert_eq!(request.symbol, "btcusdt");
+        assert_eq!(request.levels, 10);
+        assert!(request.analytics);
+
+        for request in [
+            summary_request("btcusdt", 0, 0.0),
+            summary_request("btcusdt", 101, 0.0),
+            summary_request("btcusdt", u32::MAX, 0.0),
+            summary_request("btcusdt", 10, -1.0),
+            summary_request("btcusdt", 10, f64::NAN),
+            summary_request("btcusdt", 10, f64::INFINITY),
+            summary_request("btc&usdt", 10, 0.0),
+        ] {
+            let status = validate_summary_request(&request, 100).unwrap_err();
+            assert_eq!(status.code(), Code::InvalidArgument, "{:?}", request);
+        }
+    }
+}